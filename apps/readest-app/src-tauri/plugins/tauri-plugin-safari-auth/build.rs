@@ -1,4 +1,4 @@
-const COMMANDS: &[&str] = &["auth_with_safari"];
+const COMMANDS: &[&str] = &["auth_with_safari", "cancel"];
 
 fn main() {
     tauri_plugin::Builder::new(COMMANDS)
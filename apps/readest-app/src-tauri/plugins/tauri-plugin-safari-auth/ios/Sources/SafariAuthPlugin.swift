@@ -6,6 +6,8 @@ import WebKit
 
 class RequestArgs: Decodable {
   let authUrl: String
+  let callbackUrl: String?
+  let timeoutSecs: Double?
 }
 
 struct Response: Encodable {
@@ -14,14 +16,20 @@ struct Response: Encodable {
 
 class SafariAuthPlugin: Plugin {
   private var authSession: ASWebAuthenticationSession?
+  private var pendingInvoke: Invoke?
+  private var timeoutWork: DispatchWorkItem?
 
   @objc public func auth_with_safari(_ invoke: Invoke) throws {
     let args = try invoke.parseArgs(RequestArgs.self)
     let authUrl = URL(string: args.authUrl)!
+    let callbackScheme = args.callbackUrl.flatMap { URL(string: $0)?.scheme } ?? "readest"
 
-    authSession = ASWebAuthenticationSession(url: authUrl, callbackURLScheme: "readest") {
+    finish(rejecting: "The authentication was cancelled")
+    pendingInvoke = invoke
+
+    authSession = ASWebAuthenticationSession(url: authUrl, callbackURLScheme: callbackScheme) {
       [weak self] callbackURL, error in
-      guard let strongSelf = self else { return }
+      guard let strongSelf = self, let invoke = strongSelf.takePendingInvoke() else { return }
 
       if let error = error {
         Logger.error("Auth session error: \(error.localizedDescription)")
@@ -31,8 +39,6 @@ class SafariAuthPlugin: Plugin {
 
       if let callbackURL = callbackURL {
         Logger.info("Auth session callback URL: \(callbackURL.absoluteString)")
-        strongSelf.authSession?.cancel()
-        strongSelf.authSession = nil
         invoke.resolve(["redirectUrl": callbackURL.absoluteString])
       }
     }
@@ -43,6 +49,32 @@ class SafariAuthPlugin: Plugin {
 
     let started = authSession?.start() ?? false
     Logger.info("Auth session start result: \(started)")
+
+    let work = DispatchWorkItem { [weak self] in
+      self?.finish(rejecting: "The authentication timed out")
+    }
+    timeoutWork = work
+    DispatchQueue.main.asyncAfter(deadline: .now() + (args.timeoutSecs ?? 300), execute: work)
+  }
+
+  @objc public func cancel(_ invoke: Invoke) throws {
+    finish(rejecting: "The authentication was cancelled")
+    invoke.resolve()
+  }
+
+  // Ends the current session, the pending invoke is returned to be settled by the caller.
+  private func takePendingInvoke() -> Invoke? {
+    let invoke = pendingInvoke
+    pendingInvoke = nil
+    timeoutWork?.cancel()
+    timeoutWork = nil
+    authSession?.cancel()
+    authSession = nil
+    return invoke
+  }
+
+  private func finish(rejecting message: String) {
+    takePendingInvoke()?.reject(message)
   }
 }
 
@@ -9,5 +9,10 @@ pub(crate) async fn auth_with_safari<R: Runtime>(
     app: AppHandle<R>,
     payload: SafariAuthRequest,
 ) -> Result<SafariAuthResponse> {
-    app.safari_auth().auth_with_safari(payload)
+    app.safari_auth().auth_with_safari(payload).await
+}
+
+#[command]
+pub(crate) async fn cancel<R: Runtime>(app: AppHandle<R>) -> Result<()> {
+    app.safari_auth().cancel()
 }
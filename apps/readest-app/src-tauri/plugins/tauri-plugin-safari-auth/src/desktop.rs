@@ -1,23 +1,253 @@
 use serde::de::DeserializeOwned;
-use tauri::{plugin::PluginApi, AppHandle, Runtime};
+use tauri::{plugin::PluginApi, AppHandle, Listener, Runtime, Url};
+use tauri_plugin_opener::OpenerExt;
+use tiny_http::{Header, Request, Response, Server};
+use tokio::sync::oneshot;
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use crate::models::*;
+use crate::{Error, Result};
+
+const DEFAULT_CALLBACK_URL: &str = "readest://";
+const DEFAULT_TIMEOUT_SECS: u64 = 300;
+// Emitted by the deep-link plugin when the app is opened with a URL.
+const DEEP_LINK_EVENT: &str = "deep-link://new-url";
+// Carries the URL fragment, which browsers never send to the server, back to the loopback port.
+const FRAGMENT_PARAM: &str = "__fragment";
+
+const FRAGMENT_PAGE: &str = r#"<!doctype html>
+<html><head><meta charset="utf-8"><title>Readest</title></head><body><script>
+const params = new URLSearchParams(location.search);
+params.append('__fragment', location.hash.slice(1));
+location.replace(location.pathname + '?' + params);
+</script></body></html>"#;
+
+const DONE_PAGE: &str = r#"<!doctype html>
+<html><head><meta charset="utf-8"><title>Readest</title>
+<style>body { font-family: system-ui, sans-serif; text-align: center; margin-top: 20vh; }</style>
+</head><body><p>You can close this window and return to Readest.</p></body></html>"#;
 
 pub fn init<R: Runtime, C: DeserializeOwned>(
     app: &AppHandle<R>,
     _api: PluginApi<R, C>,
 ) -> crate::Result<SafariAuth<R>> {
-    Ok(SafariAuth(app.clone()))
+    let pending = Pending::default();
+    let deep_links = pending.clone();
+    app.listen_any(DEEP_LINK_EVENT, move |event| {
+        if let Ok(urls) = serde_json::from_str::<Vec<String>>(event.payload()) {
+            for url in urls {
+                deep_links.redirect(&url);
+            }
+        }
+    });
+    Ok(SafariAuth {
+        app: app.clone(),
+        pending,
+    })
+}
+
+struct Session {
+    id: u64,
+    callback_url: String,
+    sender: oneshot::Sender<Result<String>>,
+    server: Option<Arc<Server>>,
+}
+
+impl Session {
+    fn finish(self, result: Result<String>) {
+        if let Some(server) = self.server {
+            server.unblock();
+        }
+        let _ = self.sender.send(result);
+    }
+}
+
+#[derive(Default)]
+struct Sessions {
+    last_id: u64,
+    // The authentication waiting for its redirect, at most one at a time.
+    current: Option<Session>,
+}
+
+#[derive(Clone, Default)]
+struct Pending(Arc<Mutex<Sessions>>);
+
+impl Pending {
+    fn start(
+        &self,
+        callback_url: String,
+        server: Option<Arc<Server>>,
+    ) -> (u64, oneshot::Receiver<Result<String>>) {
+        let (sender, receiver) = oneshot::channel();
+        let mut sessions = self.0.lock().unwrap();
+        sessions.last_id += 1;
+        let session = Session {
+            id: sessions.last_id,
+            callback_url,
+            sender,
+            server,
+        };
+        if let Some(previous) = sessions.current.replace(session) {
+            previous.finish(Err(Error::Cancelled));
+        }
+        (sessions.last_id, receiver)
+    }
+
+    // Finishes the session `id`, or the current one when `id` is None.
+    fn finish(&self, id: Option<u64>, result: Result<String>) -> bool {
+        let mut sessions = self.0.lock().unwrap();
+        let session = match sessions.current.take() {
+            Some(session) if id.map_or(true, |id| id == session.id) => session,
+            other => {
+                sessions.current = other;
+                return false;
+            }
+        };
+        session.finish(result);
+        true
+    }
+
+    fn redirect(&self, url: &str) -> bool {
+        let mut sessions = self.0.lock().unwrap();
+        match sessions.current.take() {
+            Some(session) if url.starts_with(&session.callback_url) => {
+                session.finish(Ok(url.to_string()));
+                true
+            }
+            other => {
+                sessions.current = other;
+                false
+            }
+        }
+    }
 }
 
 /// Access to the safari-auth APIs.
-pub struct SafariAuth<R: Runtime>(AppHandle<R>);
+pub struct SafariAuth<R: Runtime> {
+    app: AppHandle<R>,
+    pending: Pending,
+}
 
 impl<R: Runtime> SafariAuth<R> {
-    pub fn auth_with_safari(
+    /// Opens `auth_url` in the system browser and resolves with the URL it redirects to.
+    pub async fn auth_with_safari(
         &self,
-        _payload: SafariAuthRequest,
+        payload: SafariAuthRequest,
     ) -> crate::Result<SafariAuthResponse> {
-        Err(crate::Error::UnsupportedPlatformError)
+        let callback_url = payload
+            .callback_url
+            .unwrap_or_else(|| DEFAULT_CALLBACK_URL.into());
+        let server = loopback_server(&callback_url)?;
+        let (id, receiver) = self.pending.start(callback_url.clone(), server.clone());
+        if let Some(server) = server {
+            let pending = self.pending.clone();
+            std::thread::spawn(move || listen(&server, &callback_url, id, &pending));
+        }
+
+        if let Err(e) = self.app.opener().open_url(&payload.auth_url, None::<&str>) {
+            self.pending.finish(Some(id), Err(Error::Cancelled));
+            return Err(Error::Opener(e.to_string()));
+        }
+
+        let timeout = Duration::from_secs(payload.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS));
+        let redirect_url = match tokio::time::timeout(timeout, receiver).await {
+            Ok(result) => result.unwrap_or(Err(Error::Cancelled))?,
+            Err(_) => {
+                self.pending.finish(Some(id), Err(Error::Timeout));
+                return Err(Error::Timeout);
+            }
+        };
+        Ok(SafariAuthResponse { redirect_url })
+    }
+
+    /// Fails the pending authentication with [`Error::Cancelled`].
+    pub fn cancel(&self) -> crate::Result<()> {
+        self.pending.finish(None, Err(Error::Cancelled));
+        Ok(())
+    }
+
+    /// Completes the pending authentication with a URL the app was opened with, for deep
+    /// links that do not go through the deep-link plugin. Returns whether the URL was used.
+    pub fn handle_redirect_url(&self, url: &str) -> bool {
+        self.pending.redirect(url)
+    }
+}
+
+fn loopback_server(callback_url: &str) -> Result<Option<Arc<Server>>> {
+    let url = Url::parse(callback_url)
+        .map_err(|e| Error::InvalidCallbackUrl(format!("{callback_url}: {e}")))?;
+    if url.scheme() != "http" {
+        return Ok(None);
     }
+    let host = match url.host_str() {
+        Some("localhost") | Some("127.0.0.1") => "127.0.0.1",
+        Some("[::1]") => "[::1]",
+        _ => {
+            return Err(Error::InvalidCallbackUrl(format!(
+                "{callback_url}: not a loopback address"
+            )))
+        }
+    };
+    let port = url
+        .port()
+        .ok_or_else(|| Error::InvalidCallbackUrl(format!("{callback_url}: missing port")))?;
+    let server = Server::http(format!("{host}:{port}"))
+        .map_err(|e| Error::InvalidCallbackUrl(format!("{callback_url}: {e}")))?;
+    Ok(Some(Arc::new(server)))
+}
+
+// Serves the loopback port until the session `id` finishes.
+fn listen(server: &Server, callback_url: &str, id: u64, pending: &Pending) {
+    let Ok(callback) = Url::parse(callback_url) else {
+        return;
+    };
+    for request in server.incoming_requests() {
+        if let Some(redirect_url) = handle_request(request, &callback) {
+            pending.finish(Some(id), Ok(redirect_url));
+            break;
+        }
+    }
+}
+
+fn handle_request(request: Request, callback: &Url) -> Option<String> {
+    let Ok(url) = callback.join(request.url()) else {
+        respond(request, 400, "Bad request");
+        return None;
+    };
+    if url.path() != callback.path() {
+        respond(request, 404, "Not found");
+        return None;
+    }
+    let fragment = url
+        .query_pairs()
+        .find(|(key, _)| key == FRAGMENT_PARAM)
+        .map(|(_, value)| value.into_owned());
+    let Some(fragment) = fragment else {
+        respond(request, 200, FRAGMENT_PAGE);
+        return None;
+    };
+
+    // Restore the URL the browser was redirected to.
+    let mut redirect_url = url.clone();
+    let query: Vec<_> = url
+        .query_pairs()
+        .filter(|(key, _)| key != FRAGMENT_PARAM)
+        .collect();
+    redirect_url.set_query(None);
+    if !query.is_empty() {
+        redirect_url.query_pairs_mut().extend_pairs(query);
+    }
+    redirect_url.set_fragment((!fragment.is_empty()).then_some(fragment.as_str()));
+    respond(request, 200, DONE_PAGE);
+    Some(redirect_url.to_string())
+}
+
+fn respond(request: Request, status: u16, page: &str) {
+    let header = Header::from_bytes("Content-Type", "text/html; charset=utf-8").unwrap();
+    let response = Response::from_string(page)
+        .with_status_code(status)
+        .with_header(header);
+    let _ = request.respond(response);
 }
@@ -6,6 +6,14 @@ pub type Result<T> = std::result::Result<T, Error>;
 pub enum Error {
     #[error("Unsupported platform for this plugin")]
     UnsupportedPlatformError,
+    #[error("The authentication was cancelled")]
+    Cancelled,
+    #[error("The authentication timed out")]
+    Timeout,
+    #[error("Invalid callback URL: {0}")]
+    InvalidCallbackUrl(String),
+    #[error("Failed to open the browser: {0}")]
+    Opener(String),
     #[error(transparent)]
     Io(#[from] std::io::Error),
     #[cfg(mobile)]
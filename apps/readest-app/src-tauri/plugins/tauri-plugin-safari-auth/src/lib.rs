@@ -35,7 +35,10 @@ impl<R: Runtime, T: Manager<R>> crate::SafariAuthExt<R> for T {
 /// Initializes the plugin.
 pub fn init<R: Runtime>() -> TauriPlugin<R> {
     Builder::new("safari-auth")
-        .invoke_handler(tauri::generate_handler![commands::auth_with_safari])
+        .invoke_handler(tauri::generate_handler![
+            commands::auth_with_safari,
+            commands::cancel
+        ])
         .setup(|app, api| {
             #[cfg(mobile)]
             let safari_auth = mobile::init(app, api)?;
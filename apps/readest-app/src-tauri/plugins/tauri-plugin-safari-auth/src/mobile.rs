@@ -26,7 +26,7 @@ pub fn init<R: Runtime, C: DeserializeOwned>(
 pub struct SafariAuth<R: Runtime>(PluginHandle<R>);
 
 impl<R: Runtime> SafariAuth<R> {
-    pub fn auth_with_safari(
+    pub async fn auth_with_safari(
         &self,
         payload: SafariAuthRequest,
     ) -> crate::Result<SafariAuthResponse> {
@@ -34,4 +34,8 @@ impl<R: Runtime> SafariAuth<R> {
             .run_mobile_plugin("auth_with_safari", payload)
             .map_err(Into::into)
     }
+
+    pub fn cancel(&self) -> crate::Result<()> {
+        self.0.run_mobile_plugin("cancel", ()).map_err(Into::into)
+    }
 }
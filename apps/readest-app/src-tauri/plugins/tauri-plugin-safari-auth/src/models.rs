@@ -4,6 +4,11 @@ use serde::{Deserialize, Serialize};
 #[serde(rename_all = "camelCase")]
 pub struct SafariAuthRequest {
     pub auth_url: String,
+    /// The redirect URL to wait for, `readest://` by default. On desktop a
+    /// `http://localhost:<port>/...` URL is received on that loopback port.
+    pub callback_url: Option<String>,
+    /// Fails with [`crate::Error::Timeout`] when no redirect arrives in time, 300 by default.
+    pub timeout_secs: Option<u64>,
 }
 
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
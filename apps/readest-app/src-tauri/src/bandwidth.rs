@@ -0,0 +1,83 @@
+//! Limit the throughput of transfers.
+//!
+//! All running transfers draw from the same token bucket for their direction, so the
+//! caps apply to the total bandwidth and not to each transfer on its own.
+
+use tauri::{command, State};
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+struct Bucket {
+    // Bytes per second, 0 when unlimited.
+    rate: u64,
+    // Available bytes, negative when transfers have reserved more than is available.
+    tokens: f64,
+    last_refill: Instant,
+}
+
+pub struct TokenBucket(Mutex<Bucket>);
+
+impl Default for TokenBucket {
+    fn default() -> Self {
+        Self(Mutex::new(Bucket {
+            rate: 0,
+            tokens: 0.0,
+            last_refill: Instant::now(),
+        }))
+    }
+}
+
+impl TokenBucket {
+    fn set_rate(&self, rate: u64) {
+        let mut bucket = self.0.lock().unwrap();
+        bucket.rate = rate;
+        bucket.tokens = 0.0;
+        bucket.last_refill = Instant::now();
+    }
+
+    // Takes `len` bytes from the bucket and returns how long to wait before sending them.
+    fn reserve(&self, len: usize) -> Duration {
+        let mut bucket = self.0.lock().unwrap();
+        if bucket.rate == 0 {
+            return Duration::ZERO;
+        }
+        let now = Instant::now();
+        let rate = bucket.rate as f64;
+        // At most one second worth of bytes can be saved up as a burst.
+        let refill = now.duration_since(bucket.last_refill).as_secs_f64() * rate;
+        bucket.tokens = (bucket.tokens + refill).min(rate) - len as f64;
+        bucket.last_refill = now;
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / rate)
+        }
+    }
+
+    // Waits until `len` bytes may be transferred without exceeding the rate.
+    pub async fn acquire(&self, len: usize) {
+        let delay = self.reserve(len);
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+// The download and upload rate limits shared by all transfers.
+#[derive(Default)]
+pub struct BandwidthLimiter {
+    pub download: Arc<TokenBucket>,
+    pub upload: Arc<TokenBucket>,
+}
+
+// Sets the download and upload caps in bytes per second, `None` or 0 for unlimited.
+#[command]
+pub fn set_bandwidth_limits(
+    download_limit: Option<u64>,
+    upload_limit: Option<u64>,
+    bandwidth: State<'_, BandwidthLimiter>,
+) {
+    bandwidth.download.set_rate(download_limit.unwrap_or(0));
+    bandwidth.upload.set_rate(upload_limit.unwrap_or(0));
+}
@@ -0,0 +1,269 @@
+//! Read the metadata and the cover of a book without loading it in the webview.
+//!
+//! Importing many books only needs their titles, authors and covers, which are much
+//! cheaper to read natively than by parsing the whole document in JavaScript.
+
+use quick_xml::events::{BytesStart, BytesText};
+use serde::{ser::Serializer, Serialize};
+use tauri::command;
+use zip::ZipArchive;
+
+use crate::{comic_archive, epub, fb2, mobi, pdf};
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+pub(crate) type Result<T> = std::result::Result<T, Error>;
+
+// Archive entries are read into memory, the size in their headers is not trusted and a
+// few kilobytes may inflate to gigabytes.
+pub(crate) const MAX_ENTRY_SIZE: u64 = 256 * 1024 * 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("unsupported book format: {0}")]
+    UnsupportedFormat(String),
+    #[error("invalid book: {0}")]
+    InvalidBook(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Zip(#[from] zip::result::ZipError),
+    #[error(transparent)]
+    Xml(#[from] quick_xml::Error),
+    #[error(transparent)]
+    Comic(#[from] comic_archive::Error),
+    #[error(transparent)]
+    Pdf(#[from] pdf::Error),
+}
+
+impl Serialize for Error {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.to_string().as_ref())
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Title {
+    pub value: String,
+    // e.g. `main`, `subtitle` or `collection`.
+    pub kind: Option<String>,
+    pub file_as: Option<String>,
+    pub language: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Creator {
+    pub name: String,
+    pub file_as: Option<String>,
+    // MARC relator codes such as `aut`, `edt` or `trl`.
+    pub roles: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Identifier {
+    pub value: String,
+    // e.g. `ISBN`, `UUID` or `DOI` when the book names it.
+    pub scheme: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Series {
+    pub name: String,
+    pub index: Option<f64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BookMetadata {
+    // The `BookFormat` of the frontend, e.g. `EPUB`.
+    pub format: &'static str,
+    pub titles: Vec<Title>,
+    pub creators: Vec<Creator>,
+    pub contributors: Vec<Creator>,
+    pub languages: Vec<String>,
+    pub identifiers: Vec<Identifier>,
+    pub series: Option<Series>,
+    pub publisher: Option<String>,
+    pub published: Option<String>,
+    pub description: Option<String>,
+    pub subjects: Vec<String>,
+    // The type of the image written to the cover path, None if the book has no cover.
+    pub cover_mime_type: Option<String>,
+}
+
+// A cover image found in a book.
+pub(crate) struct Cover {
+    pub data: Vec<u8>,
+    pub mime_type: String,
+}
+
+// Reads the metadata of the book and writes its cover image, if any, to `cover_path`.
+#[command]
+pub async fn read_book_metadata(
+    file_path: PathBuf,
+    cover_path: Option<PathBuf>,
+) -> Result<BookMetadata> {
+    tauri::async_runtime::spawn_blocking(move || read_metadata(&file_path, cover_path.as_deref()))
+        .await
+        .map_err(|e| Error::Io(std::io::Error::other(e.to_string())))?
+}
+
+fn read_metadata(file_path: &Path, cover_path: Option<&Path>) -> Result<BookMetadata> {
+    let extension = file_path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let (mut metadata, cover) = match extension.as_str() {
+        "epub" => epub::read(file_path, cover_path.is_some())?,
+        "mobi" | "azw" | "azw3" => mobi::read(file_path, cover_path.is_some())?,
+        "fb2" => fb2::read(file_path, cover_path.is_some())?,
+        "fbz" | "zip" => match zip_book(file_path)? {
+            ZipBook::Epub => epub::read(file_path, cover_path.is_some())?,
+            // Zipped FB2, e.g. `book.fb2.zip`.
+            ZipBook::Fb2 => fb2::read_zip(file_path, cover_path.is_some())?,
+            ZipBook::Comic => comic_archive::read(file_path, cover_path.is_some())?,
+        },
+        "cbz" | "cbr" | "cb7" => comic_archive::read(file_path, cover_path.is_some())?,
+        "pdf" => pdf::read(file_path, cover_path.is_some())?,
+        _ => return Err(Error::UnsupportedFormat(extension)),
+    };
+    write_cover(&mut metadata, cover, cover_path)?;
+    Ok(metadata)
+}
+
+// The kind of book in a zip archive, which its extension does not tell.
+#[derive(Debug, PartialEq)]
+enum ZipBook {
+    Epub,
+    Fb2,
+    Comic,
+}
+
+fn zip_book(path: &Path) -> Result<ZipBook> {
+    let archive = ZipArchive::new(BufReader::new(File::open(path)?))?;
+    let names: Vec<&str> = archive.file_names().collect();
+    if names.contains(&epub::CONTAINER_PATH) {
+        Ok(ZipBook::Epub)
+    } else if names
+        .iter()
+        .any(|name| name.to_lowercase().ends_with(".fb2"))
+    {
+        Ok(ZipBook::Fb2)
+    } else if names.iter().any(|name| comic_archive::is_page(name)) {
+        Ok(ZipBook::Comic)
+    } else {
+        Err(Error::InvalidBook("no book in the zip archive".into()))
+    }
+}
+
+// Writes the cover to `cover_path` and records its type in the metadata.
+pub(crate) fn write_cover(
+    metadata: &mut BookMetadata,
+    cover: Option<Cover>,
+    cover_path: Option<&Path>,
+) -> std::io::Result<()> {
+    if let (Some(cover_path), Some(cover)) = (cover_path, cover) {
+        fs::write(cover_path, &cover.data)?;
+        metadata.cover_mime_type = Some(cover.mime_type);
+    }
+    Ok(())
+}
+
+// Reads an archive entry, failing once it is larger than `limit` bytes.
+pub(crate) fn read_limited(entry: impl Read, limit: u64) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    entry.take(limit + 1).read_to_end(&mut data)?;
+    if data.len() as u64 > limit {
+        return Err(entry_too_large(limit));
+    }
+    Ok(data)
+}
+
+pub(crate) fn entry_too_large(limit: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("the archive entry is larger than {limit} bytes"),
+    )
+}
+
+pub(crate) fn xml_attribute(element: &BytesStart, name: &[u8]) -> Option<String> {
+    element
+        .attributes()
+        .flatten()
+        .find(|attribute| attribute.key.local_name().as_ref() == name)
+        .and_then(|attribute| attribute.unescape_value().ok())
+        .map(|value| value.trim().to_string())
+}
+
+// The text of an element, HTML entities other than `&nbsp;` are kept as they are.
+pub(crate) fn xml_text(text: &BytesText) -> String {
+    text.unescape_with(|entity| match entity {
+        "nbsp" => Some("\u{a0}"),
+        entity => quick_xml::escape::resolve_predefined_entity(entity),
+    })
+    .map(|text| text.into_owned())
+    .unwrap_or_else(|_| String::from_utf8_lossy(text).into_owned())
+}
+
+// Guesses the type of an image from its first bytes.
+pub(crate) fn image_mime_type(data: &[u8]) -> Option<&'static str> {
+    match data {
+        [0xff, 0xd8, 0xff, ..] => Some("image/jpeg"),
+        [0x89, b'P', b'N', b'G', ..] => Some("image/png"),
+        [b'G', b'I', b'F', b'8', ..] => Some("image/gif"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some("image/webp"),
+        [b'B', b'M', ..] => Some("image/bmp"),
+        _ if data.starts_with(b"<svg") || data.starts_with(b"<?xml") => Some("image/svg+xml"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    fn write_zip(names: &[&str]) -> NamedTempFile {
+        let file = NamedTempFile::new().unwrap();
+        let mut zip = ZipWriter::new(file.reopen().unwrap());
+        for name in names {
+            zip.start_file(*name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(b"data").unwrap();
+        }
+        zip.finish().unwrap();
+        file
+    }
+
+    #[test]
+    fn detects_the_book_in_a_zip_archive() {
+        let epub = write_zip(&["mimetype", "META-INF/container.xml", "OEBPS/cover.jpg"]);
+        assert_eq!(zip_book(epub.path()).unwrap(), ZipBook::Epub);
+        let fbz = write_zip(&["Book.FB2"]);
+        assert_eq!(zip_book(fbz.path()).unwrap(), ZipBook::Fb2);
+        let comic = write_zip(&["ComicInfo.xml", "01/page1.jpg", "01/page2.png"]);
+        assert_eq!(zip_book(comic.path()).unwrap(), ZipBook::Comic);
+
+        let other = write_zip(&["readme.txt", "__MACOSX/._page1.jpg"]);
+        assert!(matches!(zip_book(other.path()), Err(Error::InvalidBook(_))));
+    }
+
+    #[test]
+    fn stops_reading_entries_at_the_size_limit() {
+        assert_eq!(read_limited(&b"data"[..], 4).unwrap(), b"data");
+        let error = read_limited(io::repeat(0), 4).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
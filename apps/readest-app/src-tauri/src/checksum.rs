@@ -0,0 +1,85 @@
+//! Digests used to verify the content of transferred files.
+
+use md5::{Digest, Md5};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+// Size of each sampled window of the partial MD5, the same as `partialMD5` in the frontend.
+const PARTIAL_MD5_WINDOW: u64 = 1024;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ChecksumAlgorithm {
+    Md5,
+    Sha256,
+    // MD5 of 1 KiB windows sampled at 0 and 1024 * 4^i bytes, as used to identify books.
+    PartialMd5,
+}
+
+// The digest a transferred file is expected to have, as a hex string.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Checksum {
+    pub algorithm: ChecksumAlgorithm,
+    pub value: String,
+}
+
+impl Checksum {
+    pub fn hasher(&self) -> ChecksumHasher {
+        match self.algorithm {
+            ChecksumAlgorithm::Md5 => ChecksumHasher::Md5(Md5::new()),
+            ChecksumAlgorithm::Sha256 => ChecksumHasher::Sha256(Sha256::new()),
+            ChecksumAlgorithm::PartialMd5 => ChecksumHasher::PartialMd5 {
+                hasher: Md5::new(),
+                position: 0,
+            },
+        }
+    }
+
+    pub fn matches(&self, actual: &str) -> bool {
+        self.value.trim().eq_ignore_ascii_case(actual)
+    }
+}
+
+// Computes a checksum incrementally from the chunks of a file, fed in order.
+#[derive(Clone)]
+pub enum ChecksumHasher {
+    Md5(Md5),
+    Sha256(Sha256),
+    PartialMd5 { hasher: Md5, position: u64 },
+}
+
+impl ChecksumHasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Md5(hasher) => hasher.update(data),
+            Self::Sha256(hasher) => hasher.update(data),
+            Self::PartialMd5 { hasher, position } => {
+                let chunk_start = *position;
+                let chunk_end = chunk_start + data.len() as u64;
+                for window_start in partial_md5_offsets() {
+                    let from = window_start.max(chunk_start);
+                    let to = (window_start + PARTIAL_MD5_WINDOW).min(chunk_end);
+                    if from < to {
+                        hasher.update(
+                            &data[(from - chunk_start) as usize..(to - chunk_start) as usize],
+                        );
+                    }
+                }
+                *position = chunk_end;
+            }
+        }
+    }
+
+    pub fn finalize(self) -> String {
+        match self {
+            Self::Md5(hasher) => format!("{:x}", hasher.finalize()),
+            Self::Sha256(hasher) => format!("{:x}", hasher.finalize()),
+            Self::PartialMd5 { hasher, .. } => format!("{:x}", hasher.finalize()),
+        }
+    }
+}
+
+fn partial_md5_offsets() -> impl Iterator<Item = u64> {
+    std::iter::once(0).chain((0..=10).map(|i| PARTIAL_MD5_WINDOW << (2 * i)))
+}
@@ -0,0 +1,606 @@
+//! Open comic book archives (CBZ, CBR and CB7) natively.
+//!
+//! Pages are the images of the archive in natural order, so `page2.jpg` comes before
+//! `page10.jpg`. The archive type is detected from its signature rather than its extension,
+//! as many `.cbr` files are zip archives and the other way around.
+
+use image::{DynamicImage, ImageFormat};
+use quick_xml::events::Event;
+use serde::{ser::Serializer, Serialize};
+use sevenz_rust::{Password, SevenZReader};
+use tauri::{command, ipc::Response, AppHandle, Manager};
+use zip::ZipArchive;
+
+use crate::book_metadata::{
+    self, entry_too_large, image_mime_type, read_limited, xml_attribute, xml_text, MAX_ENTRY_SIZE,
+};
+use crate::book_metadata::{BookMetadata, Cover, Creator, Identifier, Series, Title};
+
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+// The formats the `image` crate decodes.
+const IMAGE_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "gif", "webp", "bmp"];
+const COMIC_INFO: &str = "comicinfo.xml";
+pub(crate) const DEFAULT_THUMBNAIL_SIZE: u32 = 512;
+const CACHED_PAGE_INDEXES: usize = 4;
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("unsupported comic archive: {0}")]
+    UnsupportedFormat(String),
+    #[error("the comic archive has no pages")]
+    NoPages,
+    #[error("page {0} does not exist")]
+    PageNotFound(usize),
+    #[error("{0} is not in the archive")]
+    MissingEntry(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Zip(#[from] zip::result::ZipError),
+    #[error(transparent)]
+    SevenZ(#[from] sevenz_rust::Error),
+    #[error(transparent)]
+    Rar(#[from] unrar::error::UnrarError),
+    #[error(transparent)]
+    Xml(#[from] quick_xml::Error),
+    #[error(transparent)]
+    Image(#[from] image::ImageError),
+}
+
+impl Serialize for Error {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.to_string().as_ref())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ComicPage {
+    // The path of the image in the archive.
+    pub name: String,
+    pub size: u64,
+}
+
+// The ComicInfo.xml metadata, see https://anansi-project.github.io/docs/comicinfo/intro
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ComicInfo {
+    pub title: Option<String>,
+    pub series: Option<String>,
+    pub number: Option<String>,
+    pub volume: Option<String>,
+    pub summary: Option<String>,
+    // `YYYY`, `YYYY-MM` or `YYYY-MM-DD` depending on the fields that are set.
+    pub published: Option<String>,
+    pub writers: Vec<String>,
+    pub pencillers: Vec<String>,
+    pub inkers: Vec<String>,
+    pub colorists: Vec<String>,
+    pub letterers: Vec<String>,
+    pub cover_artists: Vec<String>,
+    pub editors: Vec<String>,
+    pub translators: Vec<String>,
+    pub publisher: Option<String>,
+    pub genres: Vec<String>,
+    pub tags: Vec<String>,
+    pub language: Option<String>,
+    pub web: Option<String>,
+    pub gtin: Option<String>,
+    // Manga marked as `YesAndRightToLeft`.
+    pub right_to_left: bool,
+    // The index of the page marked as the front cover.
+    pub cover_page: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ComicArchive {
+    pub pages: Vec<ComicPage>,
+    pub info: Option<ComicInfo>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ComicThumbnail {
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Zip,
+    Rar,
+    SevenZ,
+}
+
+struct Archive {
+    path: PathBuf,
+    kind: Kind,
+}
+
+impl Archive {
+    fn open(path: &Path) -> Result<Self> {
+        let mut signature = [0; 6];
+        let len = File::open(path)?.read(&mut signature)?;
+        let kind = match &signature[..len] {
+            [b'P', b'K', 3, 4, ..] => Kind::Zip,
+            [b'R', b'a', b'r', b'!', 0x1a, 7] => Kind::Rar,
+            [b'7', b'z', 0xbc, 0xaf, 0x27, 0x1c] => Kind::SevenZ,
+            _ => {
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                return Err(Error::UnsupportedFormat(name.into_owned()));
+            }
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            kind,
+        })
+    }
+
+    // The files of the archive, with `/` as the separator.
+    fn entries(&self) -> Result<Vec<ComicPage>> {
+        let mut entries = Vec::new();
+        match self.kind {
+            Kind::Zip => {
+                let mut archive = ZipArchive::new(BufReader::new(File::open(&self.path)?))?;
+                for index in 0..archive.len() {
+                    let entry = archive.by_index(index)?;
+                    if entry.is_file() {
+                        entries.push(ComicPage {
+                            name: entry.name().to_string(),
+                            size: entry.size(),
+                        });
+                    }
+                }
+            }
+            Kind::Rar => {
+                for header in unrar::Archive::new(&self.path).open_for_listing()? {
+                    let header = header?;
+                    if header.is_file() {
+                        entries.push(ComicPage {
+                            name: rar_entry_name(&header.filename),
+                            size: header.unpacked_size,
+                        });
+                    }
+                }
+            }
+            Kind::SevenZ => {
+                let reader = SevenZReader::open(&self.path, Password::empty())?;
+                for entry in &reader.archive().files {
+                    if !entry.is_directory() {
+                        entries.push(ComicPage {
+                            name: entry.name().replace('\\', "/"),
+                            size: entry.size(),
+                        });
+                    }
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    fn read(&self, name: &str) -> Result<Vec<u8>> {
+        let mut data = None;
+        match self.kind {
+            Kind::Zip => {
+                let mut archive = ZipArchive::new(BufReader::new(File::open(&self.path)?))?;
+                data = Some(read_limited(archive.by_name(name)?, MAX_ENTRY_SIZE)?);
+            }
+            Kind::Rar => {
+                let mut archive = unrar::Archive::new(&self.path).open_for_processing()?;
+                while let Some(header) = archive.read_header()? {
+                    let entry = header.entry();
+                    if entry.is_file() && rar_entry_name(&entry.filename) == name {
+                        // unrar extracts the whole entry at once, so only its header size
+                        // can be checked.
+                        if entry.unpacked_size > MAX_ENTRY_SIZE {
+                            return Err(entry_too_large(MAX_ENTRY_SIZE).into());
+                        }
+                        data = Some(header.read()?.0);
+                        break;
+                    }
+                    archive = header.skip()?;
+                }
+            }
+            Kind::SevenZ => {
+                // Solid archives are decompressed up to the entry, the entries before it are
+                // read through as their checksums are verified.
+                let mut reader = SevenZReader::open(&self.path, Password::empty())?;
+                reader.for_each_entries(|entry, entry_reader| {
+                    if data.is_some() {
+                        return Ok(false);
+                    }
+                    if entry.name().replace('\\', "/") != name {
+                        std::io::copy(entry_reader, &mut std::io::sink())?;
+                        return Ok(true);
+                    }
+                    data = Some(read_limited(entry_reader, MAX_ENTRY_SIZE)?);
+                    Ok(false)
+                })?;
+            }
+        }
+        data.ok_or_else(|| Error::MissingEntry(name.to_string()))
+    }
+
+    fn info(&self, entries: &[ComicPage]) -> Result<Option<ComicInfo>> {
+        let entry = entries.iter().find(|entry| {
+            let file_name = entry.name.rsplit('/').next().unwrap_or_default();
+            file_name.eq_ignore_ascii_case(COMIC_INFO)
+        });
+        match entry {
+            Some(entry) => {
+                let data = self.read(&entry.name)?;
+                Ok(Some(parse_comic_info(&String::from_utf8_lossy(&data))?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn page(&self, pages: &[ComicPage], index: usize) -> Result<(ComicPage, Vec<u8>)> {
+        let page = pages.get(index).ok_or(Error::PageNotFound(index))?.clone();
+        let data = self.read(&page.name)?;
+        Ok((page, data))
+    }
+
+    // The page marked as the front cover, or the first one.
+    fn cover(&self) -> Result<(ComicArchive, Vec<u8>)> {
+        let comic = self.comic()?;
+        let index = comic
+            .info
+            .as_ref()
+            .and_then(|info| info.cover_page)
+            .filter(|&index| index < comic.pages.len())
+            .unwrap_or(0);
+        let page = comic.pages.get(index).ok_or(Error::NoPages)?;
+        let data = self.read(&page.name)?;
+        Ok((comic, data))
+    }
+
+    fn comic(&self) -> Result<ComicArchive> {
+        let entries = self.entries()?;
+        let info = self.info(&entries)?;
+        Ok(ComicArchive {
+            pages: pages(entries),
+            info,
+        })
+    }
+}
+
+struct PageIndex {
+    path: PathBuf,
+    modified: SystemTime,
+    pages: Arc<Vec<ComicPage>>,
+}
+
+// The pages of the archives read last, so reading a page does not list the whole archive.
+#[derive(Default)]
+pub struct ComicArchiveState {
+    indexes: Mutex<VecDeque<PageIndex>>,
+}
+
+impl ComicArchiveState {
+    // The cached pages of the archive, listed again when the file was modified.
+    fn pages(&self, archive: &Archive) -> Result<Arc<Vec<ComicPage>>> {
+        let modified = fs::metadata(&archive.path)?.modified()?;
+        let is_current =
+            |index: &PageIndex| index.path == archive.path && index.modified == modified;
+        if let Some(index) = self
+            .indexes
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|index| is_current(index))
+        {
+            return Ok(index.pages.clone());
+        }
+
+        let pages = Arc::new(pages(archive.entries()?));
+        let mut indexes = self.indexes.lock().unwrap();
+        indexes.retain(|index| index.path != archive.path);
+        indexes.push_front(PageIndex {
+            path: archive.path.clone(),
+            modified,
+            pages: pages.clone(),
+        });
+        indexes.truncate(CACHED_PAGE_INDEXES);
+        Ok(pages)
+    }
+}
+
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> Result<T> + Send + 'static) -> Result<T> {
+    tauri::async_runtime::spawn_blocking(f)
+        .await
+        .map_err(|e| Error::Io(std::io::Error::other(e.to_string())))?
+}
+
+// Lists the pages and reads the ComicInfo.xml of a comic book archive.
+#[command]
+pub async fn open_comic_archive(file_path: PathBuf) -> Result<ComicArchive> {
+    blocking(move || Archive::open(&file_path)?.comic()).await
+}
+
+// Returns the image of the page `index`.
+#[command]
+pub async fn read_comic_page(app: AppHandle, file_path: PathBuf, index: usize) -> Result<Response> {
+    let (_, data) = blocking(move || {
+        let archive = Archive::open(&file_path)?;
+        let pages = app.state::<ComicArchiveState>().pages(&archive)?;
+        archive.page(&pages, index)
+    })
+    .await?;
+    Ok(Response::new(data))
+}
+
+// Writes the image of the page `index` to `dest_path`.
+#[command]
+pub async fn extract_comic_page(
+    app: AppHandle,
+    file_path: PathBuf,
+    index: usize,
+    dest_path: PathBuf,
+) -> Result<ComicPage> {
+    blocking(move || {
+        let archive = Archive::open(&file_path)?;
+        let pages = app.state::<ComicArchiveState>().pages(&archive)?;
+        let (page, data) = archive.page(&pages, index)?;
+        fs::write(&dest_path, data)?;
+        Ok(page)
+    })
+    .await
+}
+
+// Writes a JPEG thumbnail of the cover, no larger than `max_size` on either side.
+#[command]
+pub async fn create_comic_thumbnail(
+    file_path: PathBuf,
+    dest_path: PathBuf,
+    max_size: Option<u32>,
+) -> Result<ComicThumbnail> {
+    let max_size = max_size.unwrap_or(DEFAULT_THUMBNAIL_SIZE);
+    blocking(move || {
+        let (_, data) = Archive::open(&file_path)?.cover()?;
+        let thumbnail = image::load_from_memory(&data)?.thumbnail(max_size, max_size);
+        // JPEG has no alpha channel.
+        let thumbnail = DynamicImage::ImageRgb8(thumbnail.to_rgb8());
+        let mut file = BufWriter::new(File::create(&dest_path)?);
+        thumbnail.write_to(&mut file, ImageFormat::Jpeg)?;
+        Ok(ComicThumbnail {
+            width: thumbnail.width(),
+            height: thumbnail.height(),
+        })
+    })
+    .await
+}
+
+// Reads the metadata of a comic book for `read_book_metadata`.
+pub(crate) fn read(
+    path: &Path,
+    with_cover: bool,
+) -> book_metadata::Result<(BookMetadata, Option<Cover>)> {
+    let archive = Archive::open(path)?;
+    let (comic, cover) = if with_cover {
+        let (comic, data) = archive.cover()?;
+        let cover = image_mime_type(&data).map(|mime_type| Cover {
+            data,
+            mime_type: mime_type.to_string(),
+        });
+        (comic, cover)
+    } else {
+        (archive.comic()?, None)
+    };
+    let info = comic.info.unwrap_or_default();
+
+    // The frontend reads every comic archive as `CBZ`.
+    let mut metadata = BookMetadata {
+        format: "CBZ",
+        ..Default::default()
+    };
+    let title = match (&info.title, &info.series, &info.number) {
+        (Some(title), _, _) => Some(title.clone()),
+        (None, Some(series), Some(number)) => Some(format!("{series} #{number}")),
+        (None, series, _) => series.clone(),
+    };
+    metadata.titles.extend(title.map(|value| Title {
+        value,
+        ..Default::default()
+    }));
+    metadata.creators = creators(&info.writers, "aut");
+    for (names, role) in [
+        (&info.pencillers, "art"),
+        (&info.inkers, "art"),
+        (&info.colorists, "clr"),
+        (&info.cover_artists, "cov"),
+        (&info.editors, "edt"),
+        (&info.translators, "trl"),
+    ] {
+        metadata.contributors.extend(creators(names, role));
+    }
+    metadata.series = info.series.clone().map(|name| Series {
+        name,
+        index: info.number.as_deref().and_then(|n| n.parse().ok()),
+    });
+    metadata.languages.extend(info.language.clone());
+    metadata
+        .identifiers
+        .extend(info.gtin.clone().map(|value| Identifier {
+            value,
+            scheme: Some("GTIN".into()),
+        }));
+    metadata.publisher = info.publisher.clone();
+    metadata.published = info.published.clone();
+    metadata.description = info.summary.clone();
+    metadata.subjects = info.genres.iter().chain(&info.tags).cloned().collect();
+    Ok((metadata, cover))
+}
+
+fn creators(names: &[String], role: &str) -> Vec<Creator> {
+    names
+        .iter()
+        .map(|name| Creator {
+            name: name.clone(),
+            file_as: None,
+            roles: vec![role.into()],
+        })
+        .collect()
+}
+
+// The images of the archive in natural order.
+fn pages(entries: Vec<ComicPage>) -> Vec<ComicPage> {
+    let mut pages: Vec<ComicPage> = entries
+        .into_iter()
+        .filter(|entry| is_page(&entry.name))
+        .collect();
+    pages.sort_by(|a, b| natord::compare_ignore_case(&a.name, &b.name));
+    pages
+}
+
+// Whether the entry is an image, skipping hidden files and macOS resource forks.
+pub(crate) fn is_page(name: &str) -> bool {
+    let is_hidden = name
+        .split('/')
+        .any(|segment| segment.starts_with('.') || segment == "__MACOSX");
+    let extension = name.rsplit_once('.').map(|(_, ext)| ext.to_lowercase());
+    !is_hidden && extension.is_some_and(|ext| IMAGE_EXTENSIONS.contains(&ext.as_str()))
+}
+
+fn rar_entry_name(path: &Path) -> String {
+    path.to_string_lossy().replace('\\', "/")
+}
+
+fn parse_comic_info(xml: &str) -> Result<ComicInfo> {
+    let mut reader = quick_xml::Reader::from_str(xml);
+    let mut info = ComicInfo::default();
+    let mut path: Vec<Vec<u8>> = Vec::new();
+    let mut text = String::new();
+    let (mut year, mut month, mut day) = (None, None, None);
+    loop {
+        let event = reader.read_event()?;
+        match &event {
+            Event::Start(element) | Event::Empty(element) => {
+                let is_cover = element.local_name().as_ref() == b"Page"
+                    && xml_attribute(element, b"Type").as_deref() == Some("FrontCover");
+                if is_cover && info.cover_page.is_none() {
+                    info.cover_page =
+                        xml_attribute(element, b"Image").and_then(|image| image.parse().ok());
+                }
+                if matches!(event, Event::Start(_)) {
+                    path.push(element.local_name().as_ref().to_vec());
+                    text.clear();
+                }
+            }
+            Event::Text(t) => text.push_str(&xml_text(t)),
+            Event::CData(data) => text.push_str(&String::from_utf8_lossy(data)),
+            Event::End(_) => {
+                let name = path.pop().unwrap_or_default();
+                // Only the direct children of the root hold metadata.
+                if path.len() != 1 {
+                    continue;
+                }
+                let value = text.trim().to_string();
+                text.clear();
+                if value.is_empty() {
+                    continue;
+                }
+                let list = || {
+                    value
+                        .split(',')
+                        .map(|item| item.trim().to_string())
+                        .filter(|item| !item.is_empty())
+                        .collect::<Vec<_>>()
+                };
+                match name.as_slice() {
+                    b"Title" => info.title = Some(value),
+                    b"Series" => info.series = Some(value),
+                    b"Number" => info.number = Some(value),
+                    b"Volume" => info.volume = Some(value),
+                    b"Summary" => info.summary = Some(value),
+                    b"Year" => year = value.parse::<u32>().ok().filter(|&year| year > 0),
+                    b"Month" => month = value.parse::<u32>().ok().filter(|m| (1..=12).contains(m)),
+                    b"Day" => day = value.parse::<u32>().ok().filter(|d| (1..=31).contains(d)),
+                    b"Writer" => info.writers = list(),
+                    b"Penciller" => info.pencillers = list(),
+                    b"Inker" => info.inkers = list(),
+                    b"Colorist" => info.colorists = list(),
+                    b"Letterer" => info.letterers = list(),
+                    b"CoverArtist" => info.cover_artists = list(),
+                    b"Editor" => info.editors = list(),
+                    b"Translator" => info.translators = list(),
+                    b"Publisher" => info.publisher = Some(value),
+                    b"Genre" => info.genres = list(),
+                    b"Tags" => info.tags = list(),
+                    b"LanguageISO" => info.language = Some(value),
+                    b"Web" => info.web = Some(value),
+                    b"GTIN" => info.gtin = Some(value),
+                    b"Manga" => info.right_to_left = value == "YesAndRightToLeft",
+                    _ => {}
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    info.published = match (year, month, day) {
+        (Some(year), Some(month), Some(day)) => Some(format!("{year:04}-{month:02}-{day:02}")),
+        (Some(year), Some(month), None) => Some(format!("{year:04}-{month:02}")),
+        (Some(year), _, _) => Some(format!("{year:04}")),
+        _ => None,
+    };
+    Ok(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+    use std::time::Duration;
+    use tempfile::NamedTempFile;
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    fn write_comic(file: &NamedTempFile, names: &[&str]) {
+        let mut zip = ZipWriter::new(file.reopen().unwrap());
+        for name in names {
+            zip.start_file(*name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(name.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    #[test]
+    fn caches_the_pages_until_the_archive_changes() {
+        let file = NamedTempFile::new().unwrap();
+        write_comic(&file, &["page10.jpg", "page2.jpg", "ComicInfo.xml"]);
+        let state = ComicArchiveState::default();
+        let archive = Archive::open(file.path()).unwrap();
+
+        let pages = state.pages(&archive).unwrap();
+        let names: Vec<_> = pages.iter().map(|page| page.name.as_str()).collect();
+        assert_eq!(names, ["page2.jpg", "page10.jpg"]);
+        assert!(Arc::ptr_eq(&pages, &state.pages(&archive).unwrap()));
+        let (page, data) = archive.page(&pages, 1).unwrap();
+        assert_eq!(page.name, "page10.jpg");
+        assert_eq!(data, b"page10.jpg");
+
+        write_comic(&file, &["page1.png"]);
+        let modified = SystemTime::now() + Duration::from_secs(60);
+        file.as_file().set_modified(modified).unwrap();
+        let pages = state.pages(&archive).unwrap();
+        assert_eq!(pages.len(), 1);
+        assert!(matches!(
+            archive.page(&pages, 1),
+            Err(Error::PageNotFound(1))
+        ));
+    }
+}
@@ -0,0 +1,42 @@
+//! Keep secrets in the keychain of the operating system.
+//!
+//! Where no keychain is available nothing is stored, the secrets then only last until
+//! the app quits.
+
+const SERVICE: &str = "com.bilingify.readest";
+
+#[cfg(desktop)]
+pub fn get(account: &str) -> Option<String> {
+    keyring::Entry::new(SERVICE, account)
+        .and_then(|entry| entry.get_password())
+        .ok()
+}
+
+#[cfg(desktop)]
+pub fn set(account: &str, secret: &str) -> std::io::Result<()> {
+    keyring::Entry::new(SERVICE, account)
+        .and_then(|entry| entry.set_password(secret))
+        .map_err(|e| {
+            std::io::Error::other(format!("failed to store {account} in the keychain: {e}"))
+        })
+}
+
+#[cfg(desktop)]
+pub fn delete(account: &str) {
+    if let Ok(entry) = keyring::Entry::new(SERVICE, account) {
+        let _ = entry.delete_credential();
+    }
+}
+
+#[cfg(not(desktop))]
+pub fn get(_account: &str) -> Option<String> {
+    None
+}
+
+#[cfg(not(desktop))]
+pub fn set(_account: &str, _secret: &str) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(not(desktop))]
+pub fn delete(_account: &str) {}
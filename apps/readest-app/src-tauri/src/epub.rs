@@ -0,0 +1,639 @@
+//! Read the metadata and the cover from the package document of an EPUB 2 or 3 book.
+
+use percent_encoding::percent_decode_str;
+use quick_xml::events::{BytesStart, Event};
+use zip::ZipArchive;
+
+use crate::book_metadata::{
+    image_mime_type, read_limited, xml_attribute, xml_text, BookMetadata, Cover, Creator, Error,
+    Identifier, Result, Series, Title, MAX_ENTRY_SIZE,
+};
+
+use std::fs::File;
+use std::io::{BufReader, Read, Seek};
+use std::path::Path;
+
+pub(crate) const CONTAINER_PATH: &str = "META-INF/container.xml";
+const PACKAGE_MIME_TYPE: &str = "application/oebps-package+xml";
+
+// A Dublin Core element of the package metadata.
+#[derive(Default)]
+struct DcElement {
+    name: Vec<u8>,
+    id: Option<String>,
+    language: Option<String>,
+    // The EPUB 2 `opf:role`, `opf:file-as`, `opf:scheme` and `opf:event` attributes.
+    role: Option<String>,
+    file_as: Option<String>,
+    scheme: Option<String>,
+    event: Option<String>,
+    text: String,
+}
+
+// An EPUB 3 `<meta property="...">` element.
+#[derive(Default)]
+struct Meta {
+    property: String,
+    id: Option<String>,
+    refines: Option<String>,
+    scheme: Option<String>,
+    text: String,
+}
+
+struct ManifestItem {
+    id: Option<String>,
+    href: String,
+    media_type: Option<String>,
+    properties: Vec<String>,
+}
+
+#[derive(Default)]
+struct Package {
+    elements: Vec<DcElement>,
+    metas: Vec<Meta>,
+    // The EPUB 2 `<meta name="..." content="..."/>` elements.
+    named_metas: Vec<(String, String)>,
+    manifest: Vec<ManifestItem>,
+}
+
+enum Open {
+    Element(DcElement),
+    Meta(Meta),
+}
+
+pub(crate) fn read(path: &Path, with_cover: bool) -> Result<(BookMetadata, Option<Cover>)> {
+    let mut archive = ZipArchive::new(BufReader::new(File::open(path)?))?;
+    let container = read_text(&mut archive, CONTAINER_PATH)?;
+    let package_path = package_path(&container)?
+        .ok_or_else(|| Error::InvalidBook("no package document in the container".into()))?;
+    let package = parse_package(&read_text(&mut archive, &package_path)?)?;
+
+    let metadata = package.metadata();
+    let cover = match package.cover() {
+        Some(item) if with_cover => {
+            let data = read_entry(&mut archive, &resolve(&package_path, &item.href))?;
+            let mime_type = item
+                .media_type
+                .clone()
+                .filter(|mime_type| mime_type.starts_with("image/"))
+                .or_else(|| image_mime_type(&data).map(str::to_string));
+            mime_type.map(|mime_type| Cover { data, mime_type })
+        }
+        _ => None,
+    };
+    Ok((metadata, cover))
+}
+
+fn read_entry<R: Read + Seek>(archive: &mut ZipArchive<R>, name: &str) -> Result<Vec<u8>> {
+    let entry = archive.by_name(name)?;
+    Ok(read_limited(entry, MAX_ENTRY_SIZE)?)
+}
+
+fn read_text<R: Read + Seek>(archive: &mut ZipArchive<R>, name: &str) -> Result<String> {
+    let data = read_entry(archive, name)?;
+    let text = String::from_utf8_lossy(&data);
+    Ok(text.trim_start_matches('\u{feff}').to_string())
+}
+
+// Resolves an `href` of the package document to the name of a zip entry.
+fn resolve(package_path: &str, href: &str) -> String {
+    let href = href.split('#').next().unwrap_or_default();
+    let href = percent_decode_str(href).decode_utf8_lossy();
+    let mut segments: Vec<&str> = match package_path.rsplit_once('/') {
+        Some((dir, _)) => dir.split('/').collect(),
+        None => Vec::new(),
+    };
+    for segment in href.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    segments.join("/")
+}
+
+fn package_path(container: &str) -> Result<Option<String>> {
+    let mut reader = quick_xml::Reader::from_str(container);
+    let mut paths = Vec::new();
+    loop {
+        match reader.read_event()? {
+            Event::Start(element) | Event::Empty(element)
+                if element.local_name().as_ref() == b"rootfile" =>
+            {
+                let media_type = xml_attribute(&element, b"media-type");
+                if let Some(path) = xml_attribute(&element, b"full-path") {
+                    paths.push((media_type.as_deref() == Some(PACKAGE_MIME_TYPE), path));
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    // Prefer the package document when the container also lists other renditions.
+    let path = paths
+        .iter()
+        .find(|(is_package, _)| *is_package)
+        .or(paths.first());
+    Ok(path.map(|(_, path)| path.clone()))
+}
+
+fn parse_package(xml: &str) -> Result<Package> {
+    let mut reader = quick_xml::Reader::from_str(xml);
+    reader.config_mut().trim_text(true);
+
+    let mut package = Package::default();
+    let mut section: Option<Vec<u8>> = None;
+    let mut open: Option<Open> = None;
+    loop {
+        let event = reader.read_event()?;
+        match &event {
+            Event::Start(element) | Event::Empty(element) => {
+                let is_empty = matches!(event, Event::Empty(_));
+                let name = element.local_name().as_ref().to_vec();
+                let in_manifest = section.as_deref() == Some(b"manifest");
+                let in_metadata = section.is_some() && !in_manifest;
+                match name.as_slice() {
+                    // EPUB 2.0 may split the metadata into `dc-metadata` and `x-metadata`,
+                    // on their own or nested in `metadata`.
+                    b"metadata" | b"dc-metadata" | b"x-metadata" if !in_manifest => {
+                        section.get_or_insert(name);
+                    }
+                    b"manifest" => section = Some(name),
+                    b"item" if in_manifest => package.manifest.extend(manifest_item(element)),
+                    b"meta" if in_metadata => {
+                        let name = xml_attribute(element, b"name");
+                        let content = xml_attribute(element, b"content");
+                        if let (Some(name), Some(content)) = (name, content) {
+                            package.named_metas.push((name, content));
+                        } else if let Some(property) = xml_attribute(element, b"property") {
+                            let meta = Meta {
+                                property,
+                                id: xml_attribute(element, b"id"),
+                                refines: xml_attribute(element, b"refines")
+                                    .map(|id| id.trim_start_matches('#').to_string()),
+                                scheme: xml_attribute(element, b"scheme"),
+                                text: String::new(),
+                            };
+                            if !is_empty {
+                                open = Some(Open::Meta(meta));
+                            }
+                        }
+                    }
+                    // Elements nested in a Dublin Core element add their text to it.
+                    _ if in_metadata && open.is_none() && !is_empty => {
+                        open = Some(Open::Element(dc_element(element, name)));
+                    }
+                    _ => {}
+                }
+            }
+            Event::Text(text) => match open.as_mut() {
+                Some(Open::Element(element)) => element.text.push_str(&xml_text(text)),
+                Some(Open::Meta(meta)) => meta.text.push_str(&xml_text(text)),
+                None => {}
+            },
+            Event::CData(data) => {
+                if let Some(Open::Element(element)) = open.as_mut() {
+                    element.text.push_str(&String::from_utf8_lossy(data));
+                }
+            }
+            Event::End(end) => {
+                let name = end.local_name();
+                match open.take() {
+                    Some(Open::Element(element)) if element.name == name.as_ref() => {
+                        package.elements.push(element)
+                    }
+                    Some(Open::Meta(meta)) if name.as_ref() == b"meta" => package.metas.push(meta),
+                    other => open = other,
+                }
+                if section.as_deref() == Some(name.as_ref()) {
+                    section = None;
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(package)
+}
+
+fn dc_element(element: &BytesStart, name: Vec<u8>) -> DcElement {
+    DcElement {
+        name,
+        id: xml_attribute(element, b"id"),
+        language: xml_attribute(element, b"lang"),
+        role: xml_attribute(element, b"role"),
+        file_as: xml_attribute(element, b"file-as"),
+        scheme: xml_attribute(element, b"scheme"),
+        event: xml_attribute(element, b"event"),
+        text: String::new(),
+    }
+}
+
+fn manifest_item(element: &BytesStart) -> Option<ManifestItem> {
+    Some(ManifestItem {
+        id: xml_attribute(element, b"id"),
+        href: xml_attribute(element, b"href")?,
+        media_type: xml_attribute(element, b"media-type"),
+        properties: xml_attribute(element, b"properties")
+            .map(|properties| properties.split_whitespace().map(str::to_string).collect())
+            .unwrap_or_default(),
+    })
+}
+
+impl Package {
+    // The Dublin Core elements of OEB packages are capitalized, e.g. `dc:Title`.
+    fn elements<'a>(&'a self, name: &'a [u8]) -> impl Iterator<Item = &'a DcElement> {
+        self.elements.iter().filter(move |element| {
+            element.name.eq_ignore_ascii_case(name) && !element.text.trim().is_empty()
+        })
+    }
+
+    fn text(&self, name: &[u8]) -> Option<String> {
+        self.elements(name)
+            .next()
+            .map(|element| element.text.trim().to_string())
+    }
+
+    // The values of the EPUB 3 metas refining the element with the id.
+    fn refinements<'a>(
+        &'a self,
+        id: Option<&'a str>,
+        property: &'a str,
+    ) -> impl Iterator<Item = &'a Meta> {
+        self.metas.iter().filter(move |meta| {
+            id.is_some() && meta.refines.as_deref() == id && meta.property == property
+        })
+    }
+
+    fn refinement(&self, id: Option<&str>, property: &str) -> Option<String> {
+        self.refinements(id, property)
+            .next()
+            .map(|meta| meta.text.trim().to_string())
+    }
+
+    fn named_meta(&self, name: &str) -> Option<&str> {
+        self.named_metas
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, content)| content.as_str())
+    }
+
+    fn metadata(&self) -> BookMetadata {
+        BookMetadata {
+            format: "EPUB",
+            titles: self.titles(),
+            creators: self.creators(b"creator"),
+            contributors: self.creators(b"contributor"),
+            languages: self
+                .elements(b"language")
+                .map(|element| element.text.trim().to_string())
+                .collect(),
+            identifiers: self.identifiers(),
+            series: self.series(),
+            publisher: self.text(b"publisher"),
+            published: self.published(),
+            description: self.text(b"description"),
+            subjects: self
+                .elements(b"subject")
+                .map(|element| element.text.trim().to_string())
+                .collect(),
+            cover_mime_type: None,
+        }
+    }
+
+    fn titles(&self) -> Vec<Title> {
+        let mut titles: Vec<(Option<u32>, Title)> = self
+            .elements(b"title")
+            .map(|element| {
+                let id = element.id.as_deref();
+                let display_seq = self
+                    .refinement(id, "display-seq")
+                    .and_then(|seq| seq.parse().ok());
+                let title = Title {
+                    value: element.text.trim().to_string(),
+                    kind: self.refinement(id, "title-type"),
+                    file_as: element
+                        .file_as
+                        .clone()
+                        .or_else(|| self.refinement(id, "file-as")),
+                    language: element.language.clone(),
+                };
+                (display_seq, title)
+            })
+            .collect();
+        titles.sort_by_key(|(display_seq, _)| display_seq.unwrap_or(u32::MAX));
+        titles.into_iter().map(|(_, title)| title).collect()
+    }
+
+    fn creators(&self, name: &[u8]) -> Vec<Creator> {
+        self.elements(name)
+            .map(|element| {
+                let id = element.id.as_deref();
+                let mut roles: Vec<String> = element.role.iter().cloned().collect();
+                roles.extend(
+                    self.refinements(id, "role")
+                        .map(|meta| meta.text.trim().to_string()),
+                );
+                Creator {
+                    name: element.text.trim().to_string(),
+                    file_as: element
+                        .file_as
+                        .clone()
+                        .or_else(|| self.refinement(id, "file-as")),
+                    roles,
+                }
+            })
+            .collect()
+    }
+
+    fn identifiers(&self) -> Vec<Identifier> {
+        self.elements(b"identifier")
+            .map(|element| {
+                let value = element.text.trim().to_string();
+                let refined = self
+                    .refinements(element.id.as_deref(), "identifier-type")
+                    .next();
+                let scheme = element
+                    .scheme
+                    .clone()
+                    .or_else(|| refined.map(identifier_type))
+                    .or_else(|| {
+                        let (prefix, _) = value.strip_prefix("urn:")?.split_once(':')?;
+                        Some(prefix.to_uppercase())
+                    });
+                Identifier { value, scheme }
+            })
+            .collect()
+    }
+
+    fn published(&self) -> Option<String> {
+        let dates: Vec<_> = self.elements(b"date").collect();
+        dates
+            .iter()
+            .find(|date| date.event.as_deref() == Some("publication"))
+            .or_else(|| dates.iter().find(|date| date.event.is_none()))
+            .or(dates.first())
+            .map(|date| date.text.trim().to_string())
+    }
+
+    fn series(&self) -> Option<Series> {
+        let collections: Vec<&Meta> = self
+            .metas
+            .iter()
+            .filter(|meta| meta.property == "belongs-to-collection" && meta.refines.is_none())
+            .collect();
+        let collection_type = |meta: &Meta| self.refinement(meta.id.as_deref(), "collection-type");
+        let epub3_series = collections
+            .iter()
+            .find(|meta| collection_type(meta).as_deref() == Some("series"))
+            .or_else(|| {
+                collections
+                    .iter()
+                    .find(|meta| collection_type(meta).is_none())
+            })
+            .map(|meta| Series {
+                name: meta.text.trim().to_string(),
+                index: self
+                    .refinement(meta.id.as_deref(), "group-position")
+                    .and_then(|index| index.parse().ok()),
+            });
+        let calibre_series = self.named_meta("calibre:series").map(|name| Series {
+            name: name.to_string(),
+            index: self
+                .named_meta("calibre:series_index")
+                .and_then(|index| index.parse().ok()),
+        });
+        epub3_series
+            .or(calibre_series)
+            .filter(|series| !series.name.is_empty())
+    }
+
+    fn cover(&self) -> Option<&ManifestItem> {
+        let is_image = |item: &&ManifestItem| {
+            item.media_type
+                .as_deref()
+                .is_some_and(|media_type| media_type.starts_with("image/"))
+        };
+        // EPUB 3 marks the cover in the manifest, EPUB 2 names its id, or sometimes its href.
+        self.manifest
+            .iter()
+            .find(|item| {
+                item.properties
+                    .iter()
+                    .any(|property| property == "cover-image")
+            })
+            .or_else(|| {
+                let cover = self.named_meta("cover")?;
+                self.manifest
+                    .iter()
+                    .find(|item| item.id.as_deref() == Some(cover))
+                    .or_else(|| self.manifest.iter().find(|item| item.href == cover))
+            })
+            .or_else(|| {
+                self.manifest.iter().filter(is_image).find(|item| {
+                    let id = item.id.as_deref().unwrap_or_default();
+                    id.to_lowercase().contains("cover")
+                        || item.href.to_lowercase().contains("cover")
+                })
+            })
+    }
+}
+
+// The scheme of an EPUB 3 `identifier-type`, which may be an ONIX code.
+fn identifier_type(meta: &Meta) -> String {
+    let value = meta.text.trim();
+    match (meta.scheme.as_deref(), value) {
+        (Some("onix:codelist5"), "02" | "15") => "ISBN".into(),
+        (Some("onix:codelist5"), "06") => "DOI".into(),
+        (Some("onix:codelist5"), "22") => "URN".into(),
+        _ => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n cover";
+
+    const CONTAINER: &str = r#"<?xml version="1.0"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles>
+    <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>"#;
+
+    const EPUB2_PACKAGE: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="2.0" unique-identifier="uid">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:opf="http://www.idpf.org/2007/opf">
+    <dc:title>The Time Machine</dc:title>
+    <dc:creator opf:role="aut" opf:file-as="Wells, H. G.">H. G. Wells</dc:creator>
+    <dc:contributor opf:role="ill">Anna Artist</dc:contributor>
+    <dc:language>en</dc:language>
+    <dc:identifier id="uid" opf:scheme="ISBN">9780000000000</dc:identifier>
+    <dc:date opf:event="creation">2001</dc:date>
+    <dc:date opf:event="publication">1895</dc:date>
+    <meta name="calibre:series" content="Novels"/>
+    <meta name="calibre:series_index" content="2"/>
+    <meta name="cover" content="cover-image"/>
+  </metadata>
+  <manifest>
+    <item id="cover-image" href="images/cover%20art.png" media-type="image/png"/>
+    <item id="text" href="text.xhtml" media-type="application/xhtml+xml"/>
+  </manifest>
+</package>"#;
+
+    const EPUB3_PACKAGE: &str = r##"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="uid">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:title id="subtitle">A Subtitle</dc:title>
+    <meta refines="#subtitle" property="title-type">subtitle</meta>
+    <meta refines="#subtitle" property="display-seq">2</meta>
+    <dc:title id="main">The Main Title</dc:title>
+    <meta refines="#main" property="title-type">main</meta>
+    <meta refines="#main" property="display-seq">1</meta>
+    <dc:creator id="author">Jane Doe</dc:creator>
+    <meta refines="#author" property="role" scheme="marc:relators">aut</meta>
+    <meta refines="#author" property="file-as">Doe, Jane</meta>
+    <dc:creator id="translator">John Roe</dc:creator>
+    <meta refines="#translator" property="role" scheme="marc:relators">trl</meta>
+    <dc:identifier id="uid">urn:uuid:12345678-1234-1234-1234-123456789abc</dc:identifier>
+    <meta property="belongs-to-collection" id="set">A Set</meta>
+    <meta refines="#set" property="collection-type">set</meta>
+    <meta property="belongs-to-collection" id="series">The Series</meta>
+    <meta refines="#series" property="collection-type">series</meta>
+    <meta refines="#series" property="group-position">3.5</meta>
+    <meta name="calibre:series" content="Ignored"/>
+  </metadata>
+  <manifest>
+    <item id="img" href="../cover.png" media-type="image/png" properties="cover-image"/>
+    <item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>
+  </manifest>
+</package>"##;
+
+    fn write_epub(package: &str, cover: (&str, &[u8])) -> NamedTempFile {
+        let file = NamedTempFile::new().unwrap();
+        let mut zip = ZipWriter::new(file.reopen().unwrap());
+        let options = SimpleFileOptions::default();
+        let entries: [(&str, &[u8]); 4] = [
+            ("mimetype", b"application/epub+zip"),
+            (CONTAINER_PATH, CONTAINER.as_bytes()),
+            ("OEBPS/content.opf", package.as_bytes()),
+            cover,
+        ];
+        for (name, data) in entries {
+            zip.start_file(name, options).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap();
+        file
+    }
+
+    #[test]
+    fn reads_epub2_metadata() {
+        let file = write_epub(EPUB2_PACKAGE, ("OEBPS/images/cover art.png", PNG));
+        let (metadata, cover) = read(file.path(), true).unwrap();
+
+        assert_eq!(metadata.titles.len(), 1);
+        assert_eq!(metadata.titles[0].value, "The Time Machine");
+        assert_eq!(metadata.creators.len(), 1);
+        assert_eq!(metadata.creators[0].name, "H. G. Wells");
+        assert_eq!(
+            metadata.creators[0].file_as.as_deref(),
+            Some("Wells, H. G.")
+        );
+        assert_eq!(metadata.creators[0].roles, ["aut"]);
+        assert_eq!(metadata.contributors[0].roles, ["ill"]);
+        assert_eq!(metadata.languages, ["en"]);
+        assert_eq!(metadata.identifiers[0].scheme.as_deref(), Some("ISBN"));
+        assert_eq!(metadata.published.as_deref(), Some("1895"));
+        let series = metadata.series.unwrap();
+        assert_eq!(series.name, "Novels");
+        assert_eq!(series.index, Some(2.0));
+
+        let cover = cover.unwrap();
+        assert_eq!(cover.data, PNG);
+        assert_eq!(cover.mime_type, "image/png");
+    }
+
+    #[test]
+    fn reads_metadata_nested_in_dc_metadata() {
+        let package = r#"<?xml version="1.0"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="2.0" unique-identifier="uid">
+  <metadata>
+    <dc-metadata xmlns:dc="http://purl.org/dc/elements/1.0/" xmlns:oebpackage="http://openebook.org/namespaces/oeb-package/1.0/">
+      <dc:Title>The Time Machine</dc:Title>
+      <dc:Creator role="aut">H. G. Wells</dc:Creator>
+      <dc:Language>en</dc:Language>
+    </dc-metadata>
+    <x-metadata>
+      <meta name="cover" content="cover-image"/>
+    </x-metadata>
+  </metadata>
+  <manifest>
+    <item id="cover-image" href="images/cover%20art.png" media-type="image/png"/>
+  </manifest>
+</package>"#;
+        let file = write_epub(package, ("OEBPS/images/cover art.png", PNG));
+        let (metadata, cover) = read(file.path(), true).unwrap();
+
+        assert_eq!(metadata.titles.len(), 1);
+        assert_eq!(metadata.titles[0].value, "The Time Machine");
+        assert_eq!(metadata.creators[0].name, "H. G. Wells");
+        assert_eq!(metadata.languages, ["en"]);
+        assert_eq!(cover.unwrap().data, PNG);
+    }
+
+    #[test]
+    fn reads_epub3_metadata() {
+        let file = write_epub(EPUB3_PACKAGE, ("cover.png", PNG));
+        let (metadata, cover) = read(file.path(), true).unwrap();
+
+        let titles: Vec<_> = metadata
+            .titles
+            .iter()
+            .map(|title| (title.value.as_str(), title.kind.as_deref()))
+            .collect();
+        assert_eq!(
+            titles,
+            [
+                ("The Main Title", Some("main")),
+                ("A Subtitle", Some("subtitle"))
+            ]
+        );
+        let creators: Vec<_> = metadata
+            .creators
+            .iter()
+            .map(|creator| (creator.name.as_str(), creator.roles.clone()))
+            .collect();
+        assert_eq!(
+            creators,
+            [
+                ("Jane Doe", vec!["aut".to_string()]),
+                ("John Roe", vec!["trl".to_string()])
+            ]
+        );
+        assert_eq!(metadata.creators[0].file_as.as_deref(), Some("Doe, Jane"));
+        assert_eq!(metadata.identifiers[0].scheme.as_deref(), Some("UUID"));
+        let series = metadata.series.unwrap();
+        assert_eq!(series.name, "The Series");
+        assert_eq!(series.index, Some(3.5));
+
+        assert_eq!(cover.unwrap().data, PNG);
+    }
+
+    #[test]
+    fn skips_the_cover_when_not_asked_for() {
+        let file = write_epub(EPUB3_PACKAGE, ("cover.png", PNG));
+        let (_, cover) = read(file.path(), false).unwrap();
+        assert!(cover.is_none());
+    }
+}
@@ -0,0 +1,503 @@
+//! Read the metadata and the cover from the description of a FictionBook 2 book.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use encoding_rs::{Encoding, UTF_8, WINDOWS_1251};
+use quick_xml::events::{BytesStart, Event};
+use zip::ZipArchive;
+
+use crate::book_metadata::{
+    image_mime_type, read_limited, xml_attribute, xml_text, BookMetadata, Cover, Creator, Error,
+    Identifier, Result, Series, Title, MAX_ENTRY_SIZE,
+};
+
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::Path;
+
+// The elements of the annotation that end a paragraph.
+const ANNOTATION_BLOCKS: &[&str] = &["p", "v", "subtitle", "text-author", "empty-line"];
+
+#[derive(Default)]
+struct Person {
+    first_name: String,
+    middle_name: String,
+    last_name: String,
+    nickname: String,
+}
+
+impl Person {
+    fn into_creator(self, role: &str) -> Option<Creator> {
+        let [first_name, middle_name, last_name, nickname] = [
+            self.first_name,
+            self.middle_name,
+            self.last_name,
+            self.nickname,
+        ]
+        .map(|name| collapse_whitespace(&name));
+        let given_names = join_names(&[&first_name, &middle_name]);
+        let name = join_names(&[&given_names, &last_name]);
+        let file_as = (!last_name.is_empty() && !given_names.is_empty())
+            .then(|| format!("{last_name}, {given_names}"));
+        let name = if name.is_empty() { nickname } else { name };
+        (!name.is_empty()).then(|| Creator {
+            name,
+            file_as,
+            roles: vec![role.into()],
+        })
+    }
+}
+
+#[derive(Default)]
+struct Parser {
+    // The local names of the open elements, below the `FictionBook` root.
+    path: Vec<String>,
+    // The text of the innermost element.
+    text: String,
+    metadata: BookMetadata,
+    title: String,
+    person: Person,
+    annotation: Vec<String>,
+    paragraph: String,
+    year: Option<String>,
+    cover_id: Option<String>,
+    // The content type and the base64 text of the cover binary while it is read.
+    cover_binary: Option<(Option<String>, String)>,
+    cover: Option<Cover>,
+}
+
+pub(crate) fn read(path: &Path, with_cover: bool) -> Result<(BookMetadata, Option<Cover>)> {
+    parse(&fs::read(path)?, "FB2", with_cover)
+}
+
+// Reads the first FB2 document of a zip archive, e.g. `book.fb2.zip`.
+pub(crate) fn read_zip(path: &Path, with_cover: bool) -> Result<(BookMetadata, Option<Cover>)> {
+    let mut archive = ZipArchive::new(BufReader::new(File::open(path)?))?;
+    let name = archive
+        .file_names()
+        .find(|name| name.to_lowercase().ends_with(".fb2"))
+        .map(str::to_string)
+        .ok_or_else(|| Error::InvalidBook("no FB2 document in the archive".into()))?;
+    let data = read_limited(archive.by_name(&name)?, MAX_ENTRY_SIZE)?;
+    parse(&data, "FBZ", with_cover)
+}
+
+fn parse(
+    data: &[u8],
+    format: &'static str,
+    with_cover: bool,
+) -> Result<(BookMetadata, Option<Cover>)> {
+    let xml = decode(data);
+    let mut reader = quick_xml::Reader::from_str(&xml);
+    let mut parser = Parser::default();
+    let mut depth = 0;
+    let mut has_root = false;
+    loop {
+        match reader.read_event()? {
+            Event::Start(element) => {
+                depth += 1;
+                if depth == 1 {
+                    has_root = element.local_name().as_ref() == b"FictionBook";
+                    if !has_root {
+                        break;
+                    }
+                    continue;
+                }
+                let name = String::from_utf8_lossy(element.local_name().as_ref()).into_owned();
+                parser.path.push(name);
+                parser.start(&element, with_cover);
+            }
+            Event::Empty(element) => {
+                let name = String::from_utf8_lossy(element.local_name().as_ref()).into_owned();
+                parser.path.push(name);
+                parser.start(&element, with_cover);
+                parser.end();
+            }
+            Event::End(_) => {
+                depth -= 1;
+                if depth == 0 {
+                    break;
+                }
+                let name = parser.end();
+                // The body and the other binaries are not needed once the cover is read.
+                let done = match name.as_deref() {
+                    Some("description") => !with_cover || parser.cover_id.is_none(),
+                    Some("binary") => parser.cover.is_some(),
+                    _ => false,
+                };
+                if done {
+                    break;
+                }
+            }
+            Event::Text(text) => parser.text(&xml_text(&text)),
+            Event::CData(data) => parser.text(&String::from_utf8_lossy(&data)),
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    if !has_root {
+        return Err(Error::InvalidBook("not a FictionBook document".into()));
+    }
+    Ok(parser.finish(format))
+}
+
+impl Parser {
+    fn at(&self, path: &[&str]) -> bool {
+        self.path.len() == path.len() && self.within(path)
+    }
+
+    fn within(&self, path: &[&str]) -> bool {
+        self.path.len() >= path.len() && self.path.iter().zip(path).all(|(a, b)| a == b)
+    }
+
+    fn start(&mut self, element: &BytesStart, with_cover: bool) {
+        self.text.clear();
+        if self.at(&["description", "title-info", "sequence"]) && self.metadata.series.is_none() {
+            if let Some(name) = xml_attribute(element, b"name").filter(|name| !name.is_empty()) {
+                let index = xml_attribute(element, b"number").and_then(|n| n.parse().ok());
+                self.metadata.series = Some(Series { name, index });
+            }
+        } else if self.at(&["description", "title-info", "coverpage", "image"]) {
+            if let Some(href) = xml_attribute(element, b"href") {
+                self.cover_id
+                    .get_or_insert_with(|| href.trim_start_matches('#').to_string());
+            }
+        } else if self.at(&["description", "title-info", "date"]) {
+            self.metadata.published = xml_attribute(element, b"value").filter(|v| !v.is_empty());
+        } else if self.at(&["binary"]) && with_cover {
+            let id = xml_attribute(element, b"id");
+            if id.is_some() && id == self.cover_id {
+                let content_type = xml_attribute(element, b"content-type");
+                self.cover_binary = Some((content_type, String::new()));
+            }
+        }
+    }
+
+    fn text(&mut self, text: &str) {
+        if let Some((_, binary)) = self.cover_binary.as_mut() {
+            binary.push_str(text);
+        } else if self.within(&["description"]) {
+            self.text.push_str(text);
+            if self.within(&["description", "title-info", "annotation"]) {
+                self.paragraph.push_str(text);
+            }
+        }
+    }
+
+    // Closes the innermost element and returns its name.
+    fn end(&mut self) -> Option<String> {
+        let text = collapse_whitespace(&std::mem::take(&mut self.text));
+        let path: Vec<&str> = self.path.iter().map(String::as_str).collect();
+        let metadata = &mut self.metadata;
+        match path.as_slice() {
+            ["description", "title-info", "book-title"] => self.title = text,
+            ["description", "title-info", "genre"] => metadata.subjects.push(text),
+            ["description", "title-info", "keywords"] => metadata
+                .subjects
+                .extend(text.split(',').map(|keyword| keyword.trim().to_string())),
+            ["description", "title-info", "lang"] => metadata.languages.push(text),
+            ["description", "title-info", "date"] => {
+                metadata.published.get_or_insert(text);
+            }
+            ["description", "title-info", "author" | "translator", field] => match *field {
+                "first-name" => self.person.first_name = text,
+                "middle-name" => self.person.middle_name = text,
+                "last-name" => self.person.last_name = text,
+                "nickname" => self.person.nickname = text,
+                _ => {}
+            },
+            ["description", "title-info", "author"] => {
+                let person = std::mem::take(&mut self.person);
+                metadata.creators.extend(person.into_creator("aut"));
+            }
+            ["description", "title-info", "translator"] => {
+                let person = std::mem::take(&mut self.person);
+                metadata.contributors.extend(person.into_creator("trl"));
+            }
+            ["description", "title-info", "annotation", .., block]
+                if !ANNOTATION_BLOCKS.contains(block) => {}
+            ["description", "title-info", "annotation", ..] => {
+                let paragraph = collapse_whitespace(&std::mem::take(&mut self.paragraph));
+                if !paragraph.is_empty() {
+                    self.annotation.push(paragraph);
+                }
+            }
+            ["description", "publish-info", "publisher"] => metadata.publisher = Some(text),
+            ["description", "publish-info", "isbn"] => metadata.identifiers.push(Identifier {
+                value: text,
+                scheme: Some("ISBN".into()),
+            }),
+            ["description", "publish-info", "year"] => self.year = Some(text),
+            ["description", "document-info", "id"] => metadata.identifiers.push(Identifier {
+                value: text,
+                scheme: None,
+            }),
+            ["binary"] => {
+                if let Some((content_type, binary)) = self.cover_binary.take() {
+                    let data: String = binary.split_whitespace().collect();
+                    match STANDARD.decode(data) {
+                        Ok(data) => {
+                            let mime_type = content_type
+                                .filter(|mime_type| mime_type.starts_with("image/"))
+                                .or_else(|| image_mime_type(&data).map(str::to_string));
+                            self.cover = mime_type.map(|mime_type| Cover { data, mime_type });
+                        }
+                        Err(e) => log::warn!("Invalid FB2 cover image: {e}"),
+                    }
+                }
+            }
+            _ => {}
+        }
+        self.path.pop()
+    }
+
+    fn finish(self, format: &'static str) -> (BookMetadata, Option<Cover>) {
+        let mut metadata = self.metadata;
+        metadata.format = format;
+        metadata.subjects.retain(|subject| !subject.is_empty());
+        metadata.languages.retain(|language| !language.is_empty());
+        metadata.identifiers.retain(|id| !id.value.is_empty());
+        metadata.publisher = metadata.publisher.filter(|publisher| !publisher.is_empty());
+        metadata.published = metadata
+            .published
+            .filter(|published| !published.is_empty())
+            .or(self.year.filter(|year| !year.is_empty()));
+        if !self.annotation.is_empty() {
+            metadata.description = Some(self.annotation.join("\n"));
+        }
+        if !self.title.is_empty() {
+            metadata.titles.push(Title {
+                value: self.title,
+                language: metadata.languages.first().cloned(),
+                ..Default::default()
+            });
+        }
+        (metadata, self.cover)
+    }
+}
+
+// Joins the non-empty names with spaces.
+fn join_names(names: &[&str]) -> String {
+    let names: Vec<&str> = names
+        .iter()
+        .copied()
+        .filter(|name| !name.is_empty())
+        .collect();
+    names.join(" ")
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// Decodes the document with its byte order mark or its XML declaration. Legacy books often
+// omit both, so text that is not UTF-8 is assumed to be windows-1251.
+fn decode(data: &[u8]) -> String {
+    if let Some((encoding, bom_len)) = Encoding::for_bom(data) {
+        return encoding
+            .decode_without_bom_handling(&data[bom_len..])
+            .0
+            .into_owned();
+    }
+    let encoding = declared_encoding(data)
+        .and_then(|label| Encoding::for_label(label.as_bytes()))
+        .unwrap_or_else(|| match std::str::from_utf8(data) {
+            Ok(_) => UTF_8,
+            Err(_) => WINDOWS_1251,
+        });
+    encoding.decode_without_bom_handling(data).0.into_owned()
+}
+
+fn declared_encoding(data: &[u8]) -> Option<String> {
+    let head = String::from_utf8_lossy(&data[..data.len().min(200)]);
+    let declaration = head.strip_prefix("<?xml")?.split("?>").next()?;
+    let value = declaration.split("encoding").nth(1)?.trim_start();
+    let value = value.strip_prefix('=')?.trim_start();
+    let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    Some(value[1..].split(quote).next()?.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+    use zip::{write::SimpleFileOptions, ZipWriter};
+
+    const PNG: &[u8] = &[0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a, 1, 2, 3];
+
+    fn book(binary: &str) -> String {
+        format!(
+            r##"<?xml version="1.0" encoding="UTF-8"?>
+<FictionBook xmlns="http://www.gribuser.ru/xml/fictionbook/2.0" xmlns:l="http://www.w3.org/1999/xlink">
+  <description>
+    <title-info>
+      <genre>sf</genre>
+      <author>
+        <first-name>Arkady</first-name>
+        <middle-name>Natanovich</middle-name>
+        <last-name>Strugatsky</last-name>
+      </author>
+      <author><nickname>anonymous</nickname></author>
+      <book-title>Roadside   Picnic</book-title>
+      <annotation>
+        <p>First <emphasis>contact</emphasis>.</p>
+        <empty-line/>
+        <p>Second paragraph.</p>
+      </annotation>
+      <keywords>zone, stalker</keywords>
+      <date value="1972-01-01">1972</date>
+      <coverpage><image l:href="#cover.png"/></coverpage>
+      <lang>ru</lang>
+      <translator>
+        <first-name>Olena</first-name>
+        <last-name>Bormashenko</last-name>
+      </translator>
+      <sequence name="Noon Universe" number="7"/>
+    </title-info>
+    <document-info><id>doc-1</id></document-info>
+    <publish-info>
+      <publisher>Chicago Review Press</publisher>
+      <year>2012</year>
+      <isbn>978-1-61374-341-6</isbn>
+    </publish-info>
+  </description>
+  <body><section><p>Text</p></section></body>
+  <binary id="other.jpg" content-type="image/jpeg">AAAA</binary>
+  {binary}
+</FictionBook>"##
+        )
+    }
+
+    fn cover_binary() -> String {
+        let data = STANDARD.encode(PNG);
+        let (first, second) = data.split_at(data.len() / 2);
+        format!(
+            "<binary id=\"cover.png\" content-type=\"image/png\">\n{first}\n{second}\n</binary>"
+        )
+    }
+
+    #[test]
+    fn reads_the_title_info() {
+        let (metadata, cover) = parse(book("").as_bytes(), "FB2", false).unwrap();
+
+        assert_eq!(metadata.format, "FB2");
+        assert_eq!(metadata.titles[0].value, "Roadside Picnic");
+        assert_eq!(metadata.titles[0].language.as_deref(), Some("ru"));
+        let authors: Vec<_> = metadata
+            .creators
+            .iter()
+            .map(|creator| (creator.name.as_str(), creator.file_as.as_deref()))
+            .collect();
+        assert_eq!(
+            authors,
+            [
+                (
+                    "Arkady Natanovich Strugatsky",
+                    Some("Strugatsky, Arkady Natanovich")
+                ),
+                ("anonymous", None),
+            ]
+        );
+        assert_eq!(metadata.contributors.len(), 1);
+        assert_eq!(metadata.contributors[0].name, "Olena Bormashenko");
+        assert_eq!(metadata.contributors[0].roles, ["trl"]);
+        let series = metadata.series.unwrap();
+        assert_eq!(series.name, "Noon Universe");
+        assert_eq!(series.index, Some(7.0));
+        assert_eq!(
+            metadata.description.as_deref(),
+            Some("First contact.\nSecond paragraph.")
+        );
+        assert_eq!(metadata.subjects, ["sf", "zone", "stalker"]);
+        assert_eq!(metadata.languages, ["ru"]);
+        assert_eq!(metadata.published.as_deref(), Some("1972-01-01"));
+        assert_eq!(metadata.publisher.as_deref(), Some("Chicago Review Press"));
+        let identifiers: Vec<_> = metadata
+            .identifiers
+            .iter()
+            .map(|id| (id.value.as_str(), id.scheme.as_deref()))
+            .collect();
+        assert_eq!(
+            identifiers,
+            [("doc-1", None), ("978-1-61374-341-6", Some("ISBN"))]
+        );
+        assert!(cover.is_none());
+    }
+
+    #[test]
+    fn reads_the_cover_binary() {
+        let xml = book(&cover_binary());
+        let cover = parse(xml.as_bytes(), "FB2", true).unwrap().1.unwrap();
+        assert_eq!(cover.data, PNG);
+        assert_eq!(cover.mime_type, "image/png");
+
+        // The cover page refers to a binary that is not in the book.
+        let (_, cover) = parse(book("").as_bytes(), "FB2", true).unwrap();
+        assert!(cover.is_none());
+    }
+
+    #[test]
+    fn reads_the_book_in_an_fbz_archive() {
+        let file = NamedTempFile::new().unwrap();
+        let mut zip = ZipWriter::new(file.reopen().unwrap());
+        zip.start_file("book/Roadside Picnic.fb2", SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(book(&cover_binary()).as_bytes()).unwrap();
+        zip.finish().unwrap();
+
+        let (metadata, cover) = read_zip(file.path(), true).unwrap();
+        assert_eq!(metadata.format, "FBZ");
+        assert_eq!(metadata.titles[0].value, "Roadside Picnic");
+        assert_eq!(cover.unwrap().data, PNG);
+    }
+
+    #[test]
+    fn rejects_documents_that_are_not_fictionbooks() {
+        let result = parse(b"<html><body/></html>", "FB2", false);
+        assert!(matches!(result, Err(Error::InvalidBook(_))));
+    }
+
+    // "Пикник" in windows-1251.
+    const PICNIC_1251: &[u8] = &[0xcf, 0xe8, 0xea, 0xed, 0xe8, 0xea];
+
+    #[test]
+    fn decodes_the_encoding_of_the_byte_order_mark() {
+        let mut utf8 = vec![0xef, 0xbb, 0xbf];
+        utf8.extend("<?xml version=\"1.0\" encoding=\"windows-1251\"?>Пикник".as_bytes());
+        assert_eq!(
+            decode(&utf8),
+            "<?xml version=\"1.0\" encoding=\"windows-1251\"?>Пикник"
+        );
+
+        let mut utf16 = vec![0xff, 0xfe];
+        utf16.extend("Пикник".encode_utf16().flat_map(u16::to_le_bytes));
+        assert_eq!(decode(&utf16), "Пикник");
+    }
+
+    #[test]
+    fn decodes_the_declared_encoding() {
+        let mut data = b"<?xml version='1.0' encoding='windows-1251'?><p>".to_vec();
+        data.extend(PICNIC_1251);
+        assert_eq!(
+            decode(&data),
+            "<?xml version='1.0' encoding='windows-1251'?><p>Пикник"
+        );
+
+        // The declaration wins over text that happens to be valid UTF-8.
+        let data = "<?xml version=\"1.0\" encoding=\"ISO-8859-1\"?>é".as_bytes();
+        assert_eq!(
+            decode(data),
+            "<?xml version=\"1.0\" encoding=\"ISO-8859-1\"?>Ã©"
+        );
+    }
+
+    #[test]
+    fn falls_back_to_windows_1251_for_undeclared_legacy_text() {
+        let mut data = b"<?xml version=\"1.0\"?><p>".to_vec();
+        data.extend(PICNIC_1251);
+        assert_eq!(decode(&data), "<?xml version=\"1.0\"?><p>Пикник");
+
+        assert_eq!(decode("<p>Пикник</p>".as_bytes()), "<p>Пикник</p>");
+    }
+}
@@ -0,0 +1,136 @@
+//! The HTTP client shared by all native transfers.
+//!
+//! Reusing one client keeps connection pools, TLS sessions and HTTP/2 connections
+//! alive across transfers. It is rebuilt whenever the configuration changes.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::Deserialize;
+use tauri::{command, State};
+
+use crate::transfer_file::{Error, Result};
+
+use std::sync::RwLock;
+use std::time::Duration;
+
+const USER_AGENT: &str = concat!("Readest/", env!("CARGO_PKG_VERSION"));
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase", tag = "mode")]
+pub enum ProxyConfig {
+    // Use the proxy configured in the environment or the operating system.
+    #[default]
+    System,
+    // Connect directly, ignoring any system proxy.
+    None,
+    // An http://, https://, socks5:// or socks5h:// proxy.
+    Custom {
+        url: String,
+        username: Option<String>,
+        password: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct HttpClientConfig {
+    pub connect_timeout_ms: u64,
+    // Maximum time to wait for data on an established connection, 0 to disable.
+    pub read_timeout_ms: u64,
+    pub user_agent: String,
+    pub proxy: ProxyConfig,
+    // Paths to PEM or DER encoded certificates trusted in addition to the system roots.
+    pub root_certificates: Vec<String>,
+}
+
+impl Default for HttpClientConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout_ms: 30_000,
+            read_timeout_ms: 60_000,
+            user_agent: USER_AGENT.to_string(),
+            proxy: ProxyConfig::default(),
+            root_certificates: Vec::new(),
+        }
+    }
+}
+
+impl HttpClientConfig {
+    fn build(&self) -> Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(Duration::from_millis(self.connect_timeout_ms))
+            .user_agent(&self.user_agent);
+        if self.read_timeout_ms > 0 {
+            builder = builder.read_timeout(Duration::from_millis(self.read_timeout_ms));
+        }
+
+        builder = match &self.proxy {
+            ProxyConfig::System => builder,
+            ProxyConfig::None => builder.no_proxy(),
+            ProxyConfig::Custom {
+                url,
+                username,
+                password,
+            } => {
+                let mut proxy = reqwest::Proxy::all(url)?;
+                if let Some(username) = username {
+                    proxy = proxy.basic_auth(username, password.as_deref().unwrap_or_default());
+                }
+                builder.proxy(proxy)
+            }
+        };
+
+        for path in &self.root_certificates {
+            let content = std::fs::read(path)?;
+            let certificates = reqwest::Certificate::from_pem_bundle(&content)
+                .ok()
+                .filter(|certificates| !certificates.is_empty())
+                .map_or_else(
+                    || reqwest::Certificate::from_der(&content).map(|cert| vec![cert]),
+                    Ok,
+                )?;
+            for certificate in certificates {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+
+        builder.build().map_err(Error::from)
+    }
+}
+
+// The value of an Authorization header for HTTP basic authentication.
+pub fn basic_auth(username: &str, password: Option<&str>) -> String {
+    let credentials = format!("{username}:{}", password.unwrap_or_default());
+    format!("Basic {}", STANDARD.encode(credentials))
+}
+
+pub struct HttpClientState(RwLock<reqwest::Client>);
+
+impl HttpClientState {
+    // Clients are reference counted, a clone shares the connection pool.
+    pub fn client(&self) -> reqwest::Client {
+        self.0.read().unwrap().clone()
+    }
+
+    fn replace(&self, client: reqwest::Client) {
+        *self.0.write().unwrap() = client;
+    }
+}
+
+impl Default for HttpClientState {
+    fn default() -> Self {
+        let client = HttpClientConfig::default()
+            .build()
+            .expect("failed to build the HTTP client");
+        Self(RwLock::new(client))
+    }
+}
+
+// Replaces the shared client, transfers already running keep using the previous one.
+#[command]
+pub fn set_http_client_config(
+    config: HttpClientConfig,
+    http_client: State<'_, HttpClientState>,
+) -> Result<()> {
+    http_client.replace(config.build()?);
+    Ok(())
+}
@@ -0,0 +1,451 @@
+//! An opt-in HTTP server for sending books to the app from another device on the LAN.
+//!
+//! The server serves a small upload page. Uploads are only accepted from browsers that
+//! entered the pairing code shown in the app, and each pairing code can be used once.
+//! Received files are saved to the books directory of the app, `Readest/Books` in the app
+//! data directory, and requests are handled by a small fixed pool of threads.
+
+use percent_encoding::percent_decode_str;
+use rand::Rng;
+use serde::{ser::Serializer, Deserialize, Serialize};
+use tauri::{command, AppHandle, Emitter, Manager, State};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::random_string;
+
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read};
+use std::net::{IpAddr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+type Result<T> = std::result::Result<T, Error>;
+
+const DEFAULT_PORT: u16 = 7878;
+const UPLOAD_EVENT: &str = "lan-upload";
+const TOKEN_HEADER: &str = "X-Readest-Token";
+const FILE_NAME_HEADER: &str = "X-File-Name";
+// The pairing code is discarded after this many wrong guesses.
+const MAX_PAIRING_ATTEMPTS: u32 = 5;
+const ALLOWED_EXTENSIONS: &[&str] = &[
+    "epub", "mobi", "azw", "azw3", "fb2", "fbz", "zip", "cbz", "cbr", "cb7", "pdf", "txt",
+];
+const UPLOAD_PAGE: &str = include_str!("lan_upload.html");
+// The same folder as `LOCAL_BOOKS_SUBDIR` in the frontend.
+const BOOKS_DIR: &str = "Readest/Books";
+const MAX_UPLOAD_SIZE: u64 = 1024 * 1024 * 1024;
+// The number of requests handled at the same time, further connections wait their turn.
+const WORKER_COUNT: usize = 4;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("the LAN server is already running")]
+    AlreadyRunning,
+    #[error("failed to start the LAN server: {0}")]
+    Bind(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl Serialize for Error {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.to_string().as_ref())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LanServerInfo {
+    // The addresses to open on the other device, empty if no LAN address was found.
+    urls: Vec<String>,
+    port: u16,
+    pairing_code: String,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct UploadPayload {
+    path: PathBuf,
+    name: String,
+    size: u64,
+}
+
+#[derive(Deserialize)]
+struct PairRequest {
+    code: String,
+}
+
+#[derive(Default)]
+struct Pairing {
+    code: Option<String>,
+    attempts: u32,
+    tokens: HashSet<String>,
+}
+
+impl Pairing {
+    // Exchanges the pairing code for a token that authorizes the uploads of one browser.
+    fn pair(&mut self, code: &str) -> Option<String> {
+        let expected = self.code.as_deref()?;
+        if code.trim() != expected {
+            self.attempts += 1;
+            if self.attempts >= MAX_PAIRING_ATTEMPTS {
+                self.code = None;
+            }
+            return None;
+        }
+        self.code = None;
+        let token = random_string(32);
+        self.tokens.insert(token.clone());
+        Some(token)
+    }
+}
+
+struct Context {
+    app: AppHandle,
+    upload_dir: PathBuf,
+    pairing: Mutex<Pairing>,
+}
+
+struct RunningServer {
+    server: Arc<Server>,
+    info: LanServerInfo,
+}
+
+#[derive(Default)]
+pub struct LanServerState {
+    running: Mutex<Option<RunningServer>>,
+}
+
+#[command]
+pub fn start_lan_server(
+    port: Option<u16>,
+    app: AppHandle,
+    state: State<'_, LanServerState>,
+) -> Result<LanServerInfo> {
+    let mut running = state.running.lock().unwrap();
+    if running.is_some() {
+        return Err(Error::AlreadyRunning);
+    }
+    let upload_dir = app
+        .path()
+        .app_data_dir()
+        .map_err(|e| io::Error::other(e.to_string()))?
+        .join(BOOKS_DIR);
+    fs::create_dir_all(&upload_dir)?;
+
+    let server = match port {
+        Some(port) => Server::http(("0.0.0.0", port)),
+        // Prefer a stable port that is easy to type, fall back to any free one.
+        None => Server::http(("0.0.0.0", DEFAULT_PORT)).or_else(|_| Server::http(("0.0.0.0", 0))),
+    }
+    .map_err(|e| Error::Bind(e.to_string()))?;
+    let port = server
+        .server_addr()
+        .to_ip()
+        .map(|addr| addr.port())
+        .ok_or_else(|| Error::Bind("not listening on an IP address".into()))?;
+
+    let pairing_code = format!("{:06}", rand::thread_rng().gen_range(0..1_000_000));
+    let info = LanServerInfo {
+        urls: lan_address()
+            .map(|ip| format!("http://{ip}:{port}/"))
+            .into_iter()
+            .collect(),
+        port,
+        pairing_code: pairing_code.clone(),
+    };
+
+    let server = Arc::new(server);
+    let context = Arc::new(Context {
+        app,
+        upload_dir,
+        pairing: Mutex::new(Pairing {
+            code: Some(pairing_code),
+            ..Default::default()
+        }),
+    });
+    for _ in 0..WORKER_COUNT {
+        let server = server.clone();
+        let context = context.clone();
+        std::thread::spawn(move || {
+            for request in server.incoming_requests() {
+                handle_request(request, &context);
+            }
+        });
+    }
+
+    *running = Some(RunningServer {
+        server,
+        info: info.clone(),
+    });
+    Ok(info)
+}
+
+#[command]
+pub fn stop_lan_server(state: State<'_, LanServerState>) {
+    if let Some(running) = state.running.lock().unwrap().take() {
+        // Each call stops one of the workers.
+        for _ in 0..WORKER_COUNT {
+            running.server.unblock();
+        }
+    }
+}
+
+#[command]
+pub fn get_lan_server_info(state: State<'_, LanServerState>) -> Option<LanServerInfo> {
+    state
+        .running
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|running| running.info.clone())
+}
+
+fn handle_request(mut request: Request, context: &Context) {
+    let path = request
+        .url()
+        .split('?')
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    let response = match (request.method(), path.as_str()) {
+        (Method::Get, "/") => text_response(200, UPLOAD_PAGE, "text/html; charset=utf-8"),
+        (Method::Post, "/pair") => pair(&mut request, context),
+        (Method::Post, "/upload") => upload(&mut request, context),
+        _ => text_response(404, "Not found", "text/plain; charset=utf-8"),
+    };
+    if let Err(e) = request.respond(response) {
+        eprintln!("Failed to respond to LAN request: {}", e);
+    }
+}
+
+fn pair(request: &mut Request, context: &Context) -> Response<io::Cursor<Vec<u8>>> {
+    let mut body = String::new();
+    let code = request
+        .as_reader()
+        .take(1024)
+        .read_to_string(&mut body)
+        .ok()
+        .and_then(|_| serde_json::from_str::<PairRequest>(&body).ok())
+        .map(|request| request.code)
+        .unwrap_or_default();
+    match context.pairing.lock().unwrap().pair(&code) {
+        Some(token) => text_response(
+            200,
+            &serde_json::json!({ "token": token }).to_string(),
+            "application/json",
+        ),
+        None => text_response(403, "Invalid pairing code", "text/plain; charset=utf-8"),
+    }
+}
+
+fn upload(request: &mut Request, context: &Context) -> Response<io::Cursor<Vec<u8>>> {
+    let authorized = header_value(request, TOKEN_HEADER)
+        .is_some_and(|token| context.pairing.lock().unwrap().tokens.contains(&token));
+    if !authorized {
+        return text_response(401, "Not paired", "text/plain; charset=utf-8");
+    }
+    let Some(name) = header_value(request, FILE_NAME_HEADER)
+        .map(|name| sanitize_file_name(&percent_decode_str(&name).decode_utf8_lossy()))
+        .filter(|name| is_allowed(name))
+    else {
+        return text_response(415, "Unsupported file type", "text/plain; charset=utf-8");
+    };
+
+    if request
+        .body_length()
+        .is_some_and(|len| len as u64 > MAX_UPLOAD_SIZE)
+    {
+        return text_response(413, "File too large", "text/plain; charset=utf-8");
+    }
+
+    match save_upload(
+        request.as_reader(),
+        &context.upload_dir,
+        &name,
+        MAX_UPLOAD_SIZE,
+    ) {
+        Ok((path, size)) => {
+            crate::allow_file_in_scopes(&context.app, vec![path.clone()]);
+            let _ = context
+                .app
+                .emit(UPLOAD_EVENT, UploadPayload { path, name, size });
+            text_response(200, "OK", "text/plain; charset=utf-8")
+        }
+        Err(SaveError::TooLarge) => {
+            text_response(413, "File too large", "text/plain; charset=utf-8")
+        }
+        Err(SaveError::Io(e)) => {
+            eprintln!("Failed to save LAN upload {}: {}", name, e);
+            text_response(500, "Failed to save the file", "text/plain; charset=utf-8")
+        }
+    }
+}
+
+enum SaveError {
+    // The body is larger than the size limit.
+    TooLarge,
+    Io(io::Error),
+}
+
+impl From<io::Error> for SaveError {
+    fn from(e: io::Error) -> Self {
+        SaveError::Io(e)
+    }
+}
+
+// Reserves the destination name, then writes the body to a temporary file next to it, so
+// concurrent uploads of the same name do not collide and an interrupted upload leaves no
+// partial book behind for the library to import.
+fn save_upload(
+    body: &mut dyn Read,
+    dir: &Path,
+    name: &str,
+    max_size: u64,
+) -> std::result::Result<(PathBuf, u64), SaveError> {
+    let path = reserve_path(dir, name)?;
+    let mut part_name = path.file_name().unwrap_or_default().to_os_string();
+    part_name.push(format!(".{}.part", random_string(8)));
+    let part_path = path.with_file_name(part_name);
+
+    let result = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&part_path)
+        .map_err(SaveError::from)
+        .and_then(|mut file| {
+            let size = io::copy(&mut body.take(max_size + 1), &mut file)?;
+            if size > max_size {
+                return Err(SaveError::TooLarge);
+            }
+            file.sync_all()?;
+            Ok(size)
+        });
+    match result.and_then(|size| Ok(fs::rename(&part_path, &path).map(|_| size)?)) {
+        Ok(size) => Ok((path, size)),
+        Err(e) => {
+            let _ = fs::remove_file(&part_path);
+            let _ = fs::remove_file(&path);
+            Err(e)
+        }
+    }
+}
+
+fn header_value(request: &Request, name: &'static str) -> Option<String> {
+    request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv(name))
+        .map(|header| header.value.to_string())
+}
+
+fn text_response(status: u16, body: &str, content_type: &str) -> Response<io::Cursor<Vec<u8>>> {
+    let header = Header::from_bytes("Content-Type", content_type).unwrap();
+    Response::from_string(body)
+        .with_status_code(status)
+        .with_header(header)
+}
+
+// Keeps only the last path component and replaces characters that are invalid on any platform.
+fn sanitize_file_name(name: &str) -> String {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    name.trim().trim_start_matches('.').to_string()
+}
+
+fn is_allowed(name: &str) -> bool {
+    Path::new(name)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| {
+            ALLOWED_EXTENSIONS
+                .iter()
+                .any(|allowed| ext.eq_ignore_ascii_case(allowed))
+        })
+}
+
+// Creates an empty file with the name, or with ` (n)` appended to its stem when taken.
+fn reserve_path(dir: &Path, name: &str) -> io::Result<PathBuf> {
+    let stem = Path::new(name)
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy();
+    let ext = Path::new(name)
+        .extension()
+        .unwrap_or_default()
+        .to_string_lossy();
+    let mut path = dir.join(name);
+    let mut n = 0;
+    loop {
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(_) => return Ok(path),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                n += 1;
+                path = dir.join(format!("{stem} ({n}).{ext}"));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// The address of the interface that routes to the internet, which is the one other devices
+// on the same network can reach. Connecting a UDP socket sends no packets.
+fn lan_address() -> Option<IpAddr> {
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect("8.8.8.8:80").ok()?;
+    let ip = socket.local_addr().ok()?.ip();
+    (!ip.is_loopback() && !ip.is_unspecified()).then_some(ip)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn saves_uploads_under_unique_names() {
+        let dir = tempfile::tempdir().unwrap();
+        for content in [&b"first"[..], b"second", b"third"] {
+            let Ok((_, size)) = save_upload(&mut &content[..], dir.path(), "book.epub", 100) else {
+                panic!("failed to save the upload");
+            };
+            assert_eq!(size, content.len() as u64);
+        }
+        assert_eq!(
+            file_names(dir.path()),
+            ["book (1).epub", "book (2).epub", "book.epub"]
+        );
+        assert_eq!(
+            fs::read(dir.path().join("book (1).epub")).unwrap(),
+            b"second"
+        );
+    }
+
+    #[test]
+    fn rejects_uploads_over_the_size_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut body = io::repeat(0).take(101);
+        let result = save_upload(&mut body, dir.path(), "book.pdf", 100);
+        assert!(matches!(result, Err(SaveError::TooLarge)));
+        assert!(file_names(dir.path()).is_empty());
+    }
+}
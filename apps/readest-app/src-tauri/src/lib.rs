@@ -14,20 +14,63 @@ mod traffic_light;
 #[cfg(target_os = "macos")]
 use tauri::TitleBarStyle;
 
-#[cfg(desktop)]
+use rand::{distributions::Alphanumeric, Rng};
 use std::path::PathBuf;
 #[cfg(desktop)]
-use tauri::{AppHandle, Listener, Manager, Url};
-#[cfg(desktop)]
+use tauri::{Listener, Url};
 use tauri_plugin_fs::FsExt;
-
-mod transfer_file;
-use tauri::{command, Emitter, WebviewUrl, WebviewWindowBuilder, Window};
-use tauri_plugin_oauth::start;
-use transfer_file::{download_file, upload_file};
-
 #[cfg(desktop)]
-fn allow_file_in_scopes(app: &AppHandle, files: Vec<PathBuf>) {
+use tauri_plugin_safari_auth::SafariAuthExt;
+
+mod bandwidth;
+mod book_metadata;
+mod checksum;
+mod comic_archive;
+mod credentials;
+mod epub;
+mod fb2;
+mod http_client;
+mod lan_server;
+mod memory_download;
+mod mobi;
+mod multipart_upload;
+mod oauth;
+mod opds;
+mod pdf;
+mod s3;
+mod transfer_file;
+mod transfer_queue;
+mod webdav;
+use bandwidth::{set_bandwidth_limits, BandwidthLimiter};
+use book_metadata::read_book_metadata;
+use comic_archive::{
+    create_comic_thumbnail, extract_comic_page, open_comic_archive, read_comic_page,
+    ComicArchiveState,
+};
+use http_client::{set_http_client_config, HttpClientState};
+use lan_server::{get_lan_server_info, start_lan_server, stop_lan_server, LanServerState};
+use memory_download::download_to_memory;
+use multipart_upload::upload_file_multipart;
+use oauth::{cancel_oauth_server, start_server, OAuthServerState};
+use opds::{opds_download, opds_fetch_feed};
+use pdf::read_pdf_document;
+use s3::{
+    s3_create_multipart_upload, s3_delete_object, s3_is_configured, s3_list_objects,
+    s3_presign_url, s3_set_config, S3State,
+};
+use tauri::{command, AppHandle, Emitter, Manager, WebviewUrl, WebviewWindowBuilder};
+use transfer_file::{cancel_transfer, download_file, upload_file, TransferState};
+use transfer_queue::{
+    enqueue_transfer, get_transfer_queue, remove_transfer, set_transfer_priority,
+    set_transfer_queue_concurrency, TransferQueue,
+};
+use webdav::{
+    webdav_create_dir, webdav_delete, webdav_download, webdav_is_configured, webdav_list,
+    webdav_move, webdav_read_text, webdav_set_config, webdav_stat, webdav_upload,
+    webdav_write_text, WebDavState,
+};
+
+pub(crate) fn allow_file_in_scopes(app: &AppHandle, files: Vec<PathBuf>) {
     let fs_scope = app.fs_scope();
     let asset_protocol_scope = app.asset_protocol_scope();
     for file in &files {
@@ -44,6 +87,14 @@ fn allow_file_in_scopes(app: &AppHandle, files: Vec<PathBuf>) {
     }
 }
 
+pub(crate) fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
 #[cfg(desktop)]
 fn set_window_open_with_files(app: &AppHandle, files: Vec<PathBuf>) {
     let files = files
@@ -61,16 +112,6 @@ fn set_window_open_with_files(app: &AppHandle, files: Vec<PathBuf>) {
     }
 }
 
-#[command]
-async fn start_server(window: Window) -> Result<u16, String> {
-    start(move |url| {
-        // Because of the unprotected localhost port, you must verify the URL here.
-        // Preferebly send back only the token, or nothing at all if you can handle everything else in Rust.
-        let _ = window.emit("redirect_uri", url);
-    })
-    .map_err(|err| err.to_string())
-}
-
 #[cfg(desktop)]
 #[command]
 async fn list_fonts() -> Result<Vec<String>, String> {
@@ -93,11 +134,55 @@ struct Payload {
 pub fn run() {
     let builder = tauri::Builder::default()
         .plugin(tauri_plugin_process::init())
-        .plugin(tauri_plugin_oauth::init())
+        .manage(TransferState::default())
+        .manage(HttpClientState::default())
+        .manage(BandwidthLimiter::default())
+        .manage(LanServerState::default())
+        .manage(OAuthServerState::default())
+        .manage(ComicArchiveState::default())
         .invoke_handler(tauri::generate_handler![
             start_server,
+            cancel_oauth_server,
             download_file,
+            download_to_memory,
             upload_file,
+            upload_file_multipart,
+            cancel_transfer,
+            enqueue_transfer,
+            set_transfer_priority,
+            set_transfer_queue_concurrency,
+            get_transfer_queue,
+            remove_transfer,
+            set_http_client_config,
+            set_bandwidth_limits,
+            s3_set_config,
+            s3_is_configured,
+            s3_presign_url,
+            s3_list_objects,
+            s3_delete_object,
+            s3_create_multipart_upload,
+            webdav_set_config,
+            webdav_is_configured,
+            webdav_list,
+            webdav_stat,
+            webdav_create_dir,
+            webdav_delete,
+            webdav_move,
+            webdav_download,
+            webdav_upload,
+            webdav_read_text,
+            webdav_write_text,
+            opds_fetch_feed,
+            opds_download,
+            start_lan_server,
+            stop_lan_server,
+            get_lan_server_info,
+            read_book_metadata,
+            open_comic_archive,
+            read_comic_page,
+            extract_comic_page,
+            create_comic_thumbnail,
+            read_pdf_document,
             #[cfg(desktop)]
             list_fonts
         ])
@@ -114,6 +199,13 @@ pub fn run() {
             .get_webview_window("main")
             .expect("no main window")
             .set_focus();
+        // A sign-in in progress takes its redirect from the deep link the app is opened with.
+        if argv
+            .iter()
+            .any(|arg| app.safari_auth().handle_redirect_url(arg))
+        {
+            return;
+        }
         app.emit("single-instance", Payload { args: argv, cwd })
             .unwrap();
     }));
@@ -132,7 +224,7 @@ pub fn run() {
     #[cfg(target_os = "ios")]
     let builder = builder.plugin(tauri_plugin_sign_in_with_apple::init());
 
-    #[cfg(target_os = "ios")]
+    #[cfg(any(target_os = "ios", desktop))]
     let builder = builder.plugin(tauri_plugin_safari_auth::init());
 
     #[cfg(any(target_os = "ios", target_os = "android"))]
@@ -140,6 +232,11 @@ pub fn run() {
 
     builder
         .setup(|#[allow(unused_variables)] app| {
+            app.manage(S3State::load(app.handle()));
+            app.manage(WebDavState::load(app.handle()));
+            app.manage(TransferQueue::load(app.handle()));
+            transfer_queue::schedule(app.handle());
+
             #[cfg(desktop)]
             {
                 let mut files = Vec::new();
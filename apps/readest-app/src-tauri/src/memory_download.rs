@@ -0,0 +1,176 @@
+//! Download a response into the webview instead of a file.
+//!
+//! Small bodies are returned by the command itself, larger ones are streamed to the
+//! webview in chunks, so previewing a remote book or a cover needs no temporary file.
+
+use futures_util::TryStreamExt;
+use reqwest::{header, StatusCode};
+use tauri::{
+    command,
+    ipc::{Channel, InvokeResponseBody, Response},
+    State,
+};
+
+use crate::bandwidth::{BandwidthLimiter, TokenBucket};
+use crate::http_client::HttpClientState;
+use crate::transfer_file::{
+    build_download_request, channel_progress, content_range_start, Error, OnProgress,
+    ProgressPayload, ProgressThrottle, Result, ResumeInfo, RetryPolicy, TransferState,
+    TransferStats,
+};
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// Bodies up to this size are returned at once rather than streamed.
+const DEFAULT_INLINE_LIMIT: u64 = 1024 * 1024;
+
+struct MemoryDownloadParams<'a> {
+    client: reqwest::Client,
+    bandwidth: &'a TokenBucket,
+    url: &'a str,
+    headers: HashMap<String, String>,
+    body: Option<String>,
+    inline_limit: u64,
+    on_data: Channel<InvokeResponseBody>,
+}
+
+// The part of the body already handed to the webview, a retry continues after it.
+#[derive(Default)]
+struct Delivered {
+    len: u64,
+    validators: Option<ResumeInfo>,
+}
+
+// Returns the body if it fits in `inline_limit` bytes, otherwise the body is sent in chunks
+// to `on_data` and an empty response is returned. Either way `on_data` then receives
+// `{ "size": <body size> }`, channel messages may still arrive after the command returns.
+#[command]
+#[allow(clippy::too_many_arguments)]
+pub async fn download_to_memory(
+    id: u32,
+    url: &str,
+    headers: HashMap<String, String>,
+    body: Option<String>,
+    inline_limit: Option<u64>,
+    retry: Option<RetryPolicy>,
+    progress_throttle: Option<ProgressThrottle>,
+    on_progress: Channel<ProgressPayload>,
+    on_data: Channel<InvokeResponseBody>,
+    transfers: State<'_, TransferState>,
+    http_client: State<'_, HttpClientState>,
+    bandwidth: State<'_, BandwidthLimiter>,
+) -> Result<Response> {
+    let transfer = transfers.register(id)?;
+    let params = MemoryDownloadParams {
+        client: http_client.client(),
+        bandwidth: &bandwidth.download,
+        url,
+        headers,
+        body,
+        inline_limit: inline_limit.unwrap_or(DEFAULT_INLINE_LIMIT),
+        on_data,
+    };
+    let on_progress = channel_progress(on_progress, progress_throttle);
+    let delivered = Mutex::new(Delivered::default());
+    let retry = retry.unwrap_or_default();
+    let body = transfer
+        .run(retry.run(|attempt| download(&params, &delivered, attempt, &on_progress)))
+        .await?;
+    let size = delivered.lock().unwrap().len + body.len() as u64;
+    params
+        .on_data
+        .send(InvokeResponseBody::Json(
+            serde_json::json!({ "size": size }).to_string(),
+        ))
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    Ok(Response::new(body))
+}
+
+async fn download(
+    params: &MemoryDownloadParams<'_>,
+    delivered: &Mutex<Delivered>,
+    attempt: u32,
+    on_progress: &OnProgress,
+) -> Result<Vec<u8>> {
+    let MemoryDownloadParams {
+        client,
+        bandwidth,
+        url,
+        headers,
+        body,
+        inline_limit,
+        on_data,
+    } = params;
+
+    let (offset, validators) = {
+        let delivered = delivered.lock().unwrap();
+        (delivered.len, delivered.validators.clone())
+    };
+    let mut request = build_download_request(client, url, headers, body.as_ref());
+    if offset > 0 {
+        request = request.header(header::RANGE, format!("bytes={offset}-"));
+        if let Some(validator) = validators.as_ref().and_then(ResumeInfo::if_range) {
+            request = request.header(header::IF_RANGE, validator);
+        }
+    }
+
+    let response = request.send().await?;
+    if !response.status().is_success() {
+        return Err(Error::from_response(response).await);
+    }
+    if offset == 0 {
+        delivered.lock().unwrap().validators = Some(ResumeInfo::from_response(url, &response));
+    } else if response.status() != StatusCode::PARTIAL_CONTENT
+        || content_range_start(&response) != Some(offset)
+        || !validators.is_some_and(|info| info.matches(&response))
+    {
+        // The chunks already sent to the webview cannot be taken back.
+        return Err(Error::ResumeNotSupported);
+    }
+    let total = response
+        .content_length()
+        .map(|len| len + offset)
+        .unwrap_or(0);
+
+    let deliver = |chunk: Vec<u8>| -> Result<()> {
+        let len = chunk.len() as u64;
+        on_data
+            .send(InvokeResponseBody::Raw(chunk))
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+        delivered.lock().unwrap().len += len;
+        Ok(())
+    };
+
+    let mut buffer = Vec::new();
+    let mut streaming = offset > 0;
+    let mut stream = response.bytes_stream();
+    let mut stats = TransferStats::default();
+    while let Some(chunk) = stream.try_next().await? {
+        bandwidth.acquire(chunk.len()).await;
+        if streaming {
+            deliver(chunk.to_vec())?;
+        } else {
+            buffer.extend_from_slice(&chunk);
+            // Switch to streaming once the body turns out to be too large to return at once.
+            if buffer.len() as u64 > *inline_limit {
+                streaming = true;
+                deliver(std::mem::take(&mut buffer))?;
+            }
+        }
+        stats.record_chunk_transfer(chunk.len());
+        on_progress(ProgressPayload::new(
+            offset + stats.total_transferred,
+            total,
+            &stats,
+            attempt,
+        ));
+    }
+    on_progress(ProgressPayload::completed(
+        offset + stats.total_transferred,
+        &stats,
+        attempt,
+    ));
+
+    Ok(buffer)
+}
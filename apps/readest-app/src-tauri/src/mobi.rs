@@ -0,0 +1,529 @@
+//! Read the metadata and the cover from the headers of a MOBI, AZW or AZW3 book.
+//!
+//! These are Palm database files: a header, a table of records, then the records. The first
+//! record holds the PalmDOC header, followed by the MOBI header and its EXTH metadata, and the
+//! cover is one of the image records that follow the text.
+
+use crate::book_metadata::{
+    image_mime_type, BookMetadata, Cover, Creator, Error, Identifier, Result, Title,
+};
+
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+const PDB_HEADER_LEN: usize = 78;
+const PDB_RECORD_INFO_LEN: usize = 8;
+const PALMDOC_HEADER_LEN: usize = 16;
+// The offsets of the MOBI header fields, from the start of the first record.
+const MOBI_HEADER_LEN: usize = 20;
+const MOBI_ENCODING: usize = 28;
+const MOBI_FULL_NAME_OFFSET: usize = 84;
+const MOBI_FULL_NAME_LEN: usize = 88;
+const MOBI_LOCALE: usize = 92;
+const MOBI_FIRST_IMAGE: usize = 108;
+const MOBI_EXTH_FLAGS: usize = 128;
+const EXTH_PRESENT: u32 = 0x40;
+const ENCODING_UTF8: u32 = 65001;
+const NO_RECORD: u32 = 0xffff_ffff;
+
+// EXTH record types, see https://wiki.mobileread.com/wiki/MOBI#EXTH_Header
+const EXTH_AUTHOR: u32 = 100;
+const EXTH_PUBLISHER: u32 = 101;
+const EXTH_DESCRIPTION: u32 = 103;
+const EXTH_ISBN: u32 = 104;
+const EXTH_SUBJECT: u32 = 105;
+const EXTH_PUBLISHED: u32 = 106;
+const EXTH_ASIN: u32 = 113;
+const EXTH_COVER_OFFSET: u32 = 201;
+const EXTH_THUMB_OFFSET: u32 = 202;
+const EXTH_UPDATED_TITLE: u32 = 503;
+const EXTH_ASIN_ALT: u32 = 504;
+const EXTH_LANGUAGE: u32 = 524;
+
+struct Database {
+    reader: BufReader<File>,
+    name: String,
+    // The start of every record, followed by the end of the file.
+    offsets: Vec<u64>,
+}
+
+impl Database {
+    fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+
+        let mut header = [0; PDB_HEADER_LEN];
+        reader
+            .read_exact(&mut header)
+            .map_err(|_| invalid("the file is too short for a Palm database header"))?;
+        let kind = &header[60..68];
+        if kind != b"BOOKMOBI" && kind != b"TEXtREAd" {
+            return Err(invalid(format!(
+                "not a MOBI book, its type is {:?}",
+                String::from_utf8_lossy(kind)
+            )));
+        }
+        let name_len = header[..32].iter().position(|&b| b == 0).unwrap_or(32);
+        let name = String::from_utf8_lossy(&header[..name_len]).into_owned();
+
+        let count = u16::from_be_bytes([header[76], header[77]]) as usize;
+        if count == 0 {
+            return Err(invalid("the Palm database has no records"));
+        }
+        let mut table = vec![0; count * PDB_RECORD_INFO_LEN];
+        reader
+            .read_exact(&mut table)
+            .map_err(|_| invalid(format!("the table of {count} records is truncated")))?;
+        let mut offsets: Vec<u64> = table
+            .chunks_exact(PDB_RECORD_INFO_LEN)
+            .map(|info| u32::from_be_bytes([info[0], info[1], info[2], info[3]]) as u64)
+            .collect();
+        offsets.push(len);
+        if offsets.windows(2).any(|pair| pair[0] > pair[1]) {
+            return Err(invalid(
+                "the record offsets are out of order or past the end",
+            ));
+        }
+        Ok(Self {
+            reader,
+            name,
+            offsets,
+        })
+    }
+
+    fn record_count(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    fn record(&mut self, index: usize) -> Result<Vec<u8>> {
+        if index >= self.record_count() {
+            return Err(invalid(format!("record {index} does not exist")));
+        }
+        let (start, end) = (self.offsets[index], self.offsets[index + 1]);
+        let mut data = vec![0; (end - start) as usize];
+        self.reader.seek(SeekFrom::Start(start))?;
+        self.reader.read_exact(&mut data)?;
+        Ok(data)
+    }
+}
+
+// The first record of the book, with the MOBI header when there is one.
+struct Header {
+    data: Vec<u8>,
+    is_mobi: bool,
+    utf8: bool,
+}
+
+impl Header {
+    fn u32_at(&self, offset: usize) -> Option<u32> {
+        u32_at(&self.data, offset).filter(|_| self.is_mobi && offset < self.mobi_end())
+    }
+
+    // The end of the MOBI header, whose length grew with every version of the format.
+    fn mobi_end(&self) -> usize {
+        PALMDOC_HEADER_LEN + u32_at(&self.data, MOBI_HEADER_LEN).unwrap_or(0) as usize
+    }
+
+    fn text(&self, data: &[u8]) -> String {
+        decode(data, self.utf8)
+    }
+
+    fn full_name(&self) -> Option<String> {
+        let offset = self.u32_at(MOBI_FULL_NAME_OFFSET)? as usize;
+        let len = self.u32_at(MOBI_FULL_NAME_LEN)? as usize;
+        let name = self.data.get(offset..offset.checked_add(len)?)?;
+        Some(self.text(name))
+    }
+
+    fn language(&self) -> Option<&'static str> {
+        locale_language(self.u32_at(MOBI_LOCALE)?)
+    }
+
+    // The EXTH records, ignoring whatever follows a malformed one.
+    fn exth(&self) -> Vec<(u32, &[u8])> {
+        let mut records = Vec::new();
+        let has_exth = self
+            .u32_at(MOBI_EXTH_FLAGS)
+            .is_some_and(|flags| flags & EXTH_PRESENT != 0);
+        let start = self.mobi_end();
+        if !has_exth || self.data.get(start..start + 4) != Some(b"EXTH") {
+            return records;
+        }
+        let count = u32_at(&self.data, start + 8).unwrap_or(0);
+        let mut offset = start + 12;
+        for _ in 0..count {
+            let (Some(kind), Some(len)) =
+                (u32_at(&self.data, offset), u32_at(&self.data, offset + 4))
+            else {
+                break;
+            };
+            let len = len as usize;
+            let Some(value) = (len >= 8)
+                .then(|| self.data.get(offset + 8..offset + len))
+                .flatten()
+            else {
+                log::warn!("Ignoring the EXTH records after the malformed record {kind}");
+                break;
+            };
+            records.push((kind, value));
+            offset += len;
+        }
+        records
+    }
+}
+
+pub(crate) fn read(path: &Path, with_cover: bool) -> Result<(BookMetadata, Option<Cover>)> {
+    let mut database = Database::open(path)?;
+    let data = database.record(0)?;
+    if data.len() < PALMDOC_HEADER_LEN {
+        return Err(invalid("the PalmDOC header is truncated"));
+    }
+    let is_mobi = data.get(PALMDOC_HEADER_LEN..PALMDOC_HEADER_LEN + 4) == Some(b"MOBI");
+    let mut header = Header {
+        data,
+        is_mobi,
+        utf8: false,
+    };
+    if is_mobi && header.mobi_end() > header.data.len() {
+        return Err(invalid(format!(
+            "the MOBI header claims {} bytes but the record has {}",
+            header.mobi_end(),
+            header.data.len()
+        )));
+    }
+    header.utf8 = header.u32_at(MOBI_ENCODING) == Some(ENCODING_UTF8);
+
+    let mut metadata = BookMetadata {
+        format: "MOBI",
+        ..Default::default()
+    };
+    let mut title = None;
+    let mut cover_offset = None;
+    let mut thumb_offset = None;
+    for (kind, value) in header.exth() {
+        let text = || header.text(value).trim().to_string();
+        match kind {
+            EXTH_AUTHOR => metadata.creators.push(Creator {
+                name: text(),
+                roles: vec!["aut".into()],
+                ..Default::default()
+            }),
+            EXTH_PUBLISHER => metadata.publisher = Some(text()),
+            EXTH_DESCRIPTION => metadata.description = Some(text()),
+            EXTH_SUBJECT => metadata.subjects.push(text()),
+            EXTH_PUBLISHED => metadata.published = Some(text()),
+            EXTH_UPDATED_TITLE => title = Some(text()),
+            EXTH_LANGUAGE => metadata.languages.push(text()),
+            EXTH_ISBN => metadata.identifiers.push(Identifier {
+                value: text(),
+                scheme: Some("ISBN".into()),
+            }),
+            EXTH_ASIN | EXTH_ASIN_ALT => {
+                let value = text();
+                if !metadata.identifiers.iter().any(|id| id.value == value) {
+                    metadata.identifiers.push(Identifier {
+                        value,
+                        scheme: Some("ASIN".into()),
+                    });
+                }
+            }
+            EXTH_COVER_OFFSET => cover_offset = u32_at(value, 0),
+            EXTH_THUMB_OFFSET => thumb_offset = u32_at(value, 0),
+            _ => {}
+        }
+    }
+    metadata.creators.retain(|creator| !creator.name.is_empty());
+    metadata.subjects.retain(|subject| !subject.is_empty());
+    metadata.languages.retain(|language| !language.is_empty());
+    metadata.identifiers.retain(|id| !id.value.is_empty());
+    if metadata.languages.is_empty() {
+        metadata
+            .languages
+            .extend(header.language().map(str::to_string));
+    }
+
+    let title = title
+        .filter(|title| !title.is_empty())
+        .or_else(|| header.full_name().filter(|name| !name.is_empty()))
+        .unwrap_or_else(|| database.name.replace('_', " "));
+    metadata.titles.push(Title {
+        value: title,
+        ..Default::default()
+    });
+
+    let cover = if with_cover {
+        let first_image = header.u32_at(MOBI_FIRST_IMAGE);
+        [cover_offset, thumb_offset]
+            .into_iter()
+            .flatten()
+            .filter(|&offset| offset != NO_RECORD)
+            .find_map(|offset| {
+                let index = first_image?.checked_add(offset)? as usize;
+                let data = database.record(index).ok()?;
+                let mime_type = image_mime_type(&data)?.to_string();
+                Some(Cover { data, mime_type })
+            })
+    } else {
+        None
+    };
+    Ok((metadata, cover))
+}
+
+fn invalid(message: impl Into<String>) -> Error {
+    Error::InvalidBook(message.into())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_be_bytes(bytes.try_into().ok()?))
+}
+
+// MOBI books are either UTF-8 or Windows-1252.
+fn decode(data: &[u8], utf8: bool) -> String {
+    if utf8 {
+        String::from_utf8_lossy(data).into_owned()
+    } else {
+        encoding_rs::WINDOWS_1252
+            .decode_without_bom_handling(data)
+            .0
+            .into_owned()
+    }
+}
+
+// Maps the Windows language id in the low byte of the MOBI locale to a language tag.
+fn locale_language(locale: u32) -> Option<&'static str> {
+    let language = match locale & 0xff {
+        0x01 => "ar",
+        0x02 => "bg",
+        0x03 => "ca",
+        0x04 => "zh",
+        0x05 => "cs",
+        0x06 => "da",
+        0x07 => "de",
+        0x08 => "el",
+        0x09 => "en",
+        0x0a => "es",
+        0x0b => "fi",
+        0x0c => "fr",
+        0x0d => "he",
+        0x0e => "hu",
+        0x0f => "is",
+        0x10 => "it",
+        0x11 => "ja",
+        0x12 => "ko",
+        0x13 => "nl",
+        0x14 => "nb",
+        0x15 => "pl",
+        0x16 => "pt",
+        0x18 => "ro",
+        0x19 => "ru",
+        0x1a => "hr",
+        0x1b => "sk",
+        0x1d => "sv",
+        0x1e => "th",
+        0x1f => "tr",
+        0x22 => "uk",
+        0x2a => "vi",
+        _ => return None,
+    };
+    Some(language)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    const JPEG: &[u8] = &[0xff, 0xd8, 0xff, 0xe0, 1, 2, 3];
+    const PNG: &[u8] = &[0x89, b'P', b'N', b'G', 4, 5, 6];
+    // The MOBI header, from its magic to the EXTH header.
+    const MOBI_LEN: usize = 232;
+
+    fn put_u32(data: &mut [u8], offset: usize, value: u32) {
+        data[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+    }
+
+    fn exth(records: &[(u32, &[u8])]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (kind, value) in records {
+            body.extend(kind.to_be_bytes());
+            body.extend((value.len() as u32 + 8).to_be_bytes());
+            body.extend(*value);
+        }
+        let mut exth = b"EXTH".to_vec();
+        exth.extend((body.len() as u32 + 12).to_be_bytes());
+        exth.extend((records.len() as u32).to_be_bytes());
+        exth.extend(body);
+        exth
+    }
+
+    // The first record: the PalmDOC and MOBI headers, the EXTH records and the full name.
+    fn first_record(exth: &[u8], full_name: &str, first_image: u32) -> Vec<u8> {
+        let mut record = vec![0; PALMDOC_HEADER_LEN + MOBI_LEN];
+        record[PALMDOC_HEADER_LEN..PALMDOC_HEADER_LEN + 4].copy_from_slice(b"MOBI");
+        put_u32(&mut record, MOBI_HEADER_LEN, MOBI_LEN as u32);
+        put_u32(&mut record, MOBI_ENCODING, ENCODING_UTF8);
+        put_u32(&mut record, MOBI_LOCALE, 0x0409);
+        put_u32(&mut record, MOBI_FIRST_IMAGE, first_image);
+        put_u32(&mut record, MOBI_EXTH_FLAGS, EXTH_PRESENT);
+        record.extend(exth);
+        let full_name_offset = record.len() as u32;
+        put_u32(&mut record, MOBI_FULL_NAME_OFFSET, full_name_offset);
+        put_u32(&mut record, MOBI_FULL_NAME_LEN, full_name.len() as u32);
+        record.extend(full_name.as_bytes());
+        record.extend([0; 4]);
+        record
+    }
+
+    fn database(name: &str, kind: &[u8; 8], records: &[&[u8]]) -> Vec<u8> {
+        let mut data = vec![0; PDB_HEADER_LEN];
+        data[..name.len()].copy_from_slice(name.as_bytes());
+        data[60..68].copy_from_slice(kind);
+        data[76..78].copy_from_slice(&(records.len() as u16).to_be_bytes());
+        let mut offset = PDB_HEADER_LEN + records.len() * PDB_RECORD_INFO_LEN + 2;
+        for (index, record) in records.iter().enumerate() {
+            data.extend((offset as u32).to_be_bytes());
+            data.extend((index as u32).to_be_bytes());
+            offset += record.len();
+        }
+        data.extend([0; 2]);
+        for record in records {
+            data.extend(*record);
+        }
+        data
+    }
+
+    fn write(data: &[u8]) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(data).unwrap();
+        file
+    }
+
+    fn book(exth_records: &[(u32, &[u8])]) -> NamedTempFile {
+        let first = first_record(&exth(exth_records), "Full Name", 2);
+        let records: [&[u8]; 4] = [&first, b"text", PNG, JPEG];
+        write(&database("Database_Name", b"BOOKMOBI", &records))
+    }
+
+    #[test]
+    fn reads_the_metadata_from_the_exth_records() {
+        let file = book(&[
+            (EXTH_AUTHOR, "Jane Doe".as_bytes()),
+            (EXTH_AUTHOR, "Émile Roe".as_bytes()),
+            (EXTH_PUBLISHER, b"Acme"),
+            (EXTH_UPDATED_TITLE, b"The Title"),
+            (EXTH_ASIN, b"B000000001"),
+            (EXTH_ASIN_ALT, b"B000000001"),
+            (EXTH_LANGUAGE, b"fr"),
+        ]);
+        let (metadata, cover) = read(file.path(), false).unwrap();
+
+        assert_eq!(metadata.titles[0].value, "The Title");
+        let authors: Vec<_> = metadata.creators.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(authors, ["Jane Doe", "Émile Roe"]);
+        assert_eq!(metadata.creators[0].roles, ["aut"]);
+        assert_eq!(metadata.publisher.as_deref(), Some("Acme"));
+        assert_eq!(metadata.languages, ["fr"]);
+        assert_eq!(metadata.identifiers.len(), 1);
+        assert_eq!(metadata.identifiers[0].value, "B000000001");
+        assert_eq!(metadata.identifiers[0].scheme.as_deref(), Some("ASIN"));
+        assert!(cover.is_none());
+    }
+
+    #[test]
+    fn falls_back_to_the_full_name_and_the_locale() {
+        let (metadata, _) = read(book(&[]).path(), false).unwrap();
+        assert_eq!(metadata.titles[0].value, "Full Name");
+        assert_eq!(metadata.languages, ["en"]);
+    }
+
+    #[test]
+    fn finds_the_cover_after_the_first_image_record() {
+        let file = book(&[
+            (EXTH_COVER_OFFSET, &1u32.to_be_bytes()),
+            (EXTH_THUMB_OFFSET, &0u32.to_be_bytes()),
+        ]);
+        let cover = read(file.path(), true).unwrap().1.unwrap();
+        assert_eq!(cover.data, JPEG);
+        assert_eq!(cover.mime_type, "image/jpeg");
+
+        // Without a cover record the thumbnail is used.
+        let file = book(&[
+            (EXTH_COVER_OFFSET, &NO_RECORD.to_be_bytes()),
+            (EXTH_THUMB_OFFSET, &0u32.to_be_bytes()),
+        ]);
+        let cover = read(file.path(), true).unwrap().1.unwrap();
+        assert_eq!(cover.data, PNG);
+        assert_eq!(cover.mime_type, "image/png");
+
+        // An offset past the last record is no cover rather than an error.
+        let file = book(&[(EXTH_COVER_OFFSET, &7u32.to_be_bytes())]);
+        assert!(read(file.path(), true).unwrap().1.is_none());
+    }
+
+    #[test]
+    fn ignores_the_exth_records_after_one_past_the_end() {
+        let mut exth = exth(&[(EXTH_PUBLISHER, b"Acme"), (EXTH_AUTHOR, b"Jane Doe")]);
+        // The length of the author record now reaches past the end of the first record.
+        let author = exth.len() - 8 - "Jane Doe".len();
+        put_u32(&mut exth, author + 4, 10_000);
+        let first = first_record(&exth, "Full Name", NO_RECORD);
+        let file = write(&database("Book", b"BOOKMOBI", &[&first]));
+
+        let (metadata, _) = read(file.path(), false).unwrap();
+        assert_eq!(metadata.publisher.as_deref(), Some("Acme"));
+        assert!(metadata.creators.is_empty());
+    }
+
+    #[test]
+    fn reads_a_first_record_without_the_mobi_magic_as_palmdoc() {
+        let mut first = first_record(&exth(&[(EXTH_UPDATED_TITLE, b"Ignored")]), "Name", 2);
+        first[PALMDOC_HEADER_LEN..PALMDOC_HEADER_LEN + 4].copy_from_slice(b"XOBI");
+        let file = write(&database("Palm_Book", b"TEXtREAd", &[&first, PNG]));
+
+        let (metadata, cover) = read(file.path(), true).unwrap();
+        assert_eq!(metadata.titles[0].value, "Palm Book");
+        assert!(metadata.languages.is_empty());
+        assert!(cover.is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_databases() {
+        let first = first_record(&exth(&[]), "Name", NO_RECORD);
+        let invalid_book = |data: &[u8]| {
+            let file = write(data);
+            matches!(read(file.path(), false), Err(Error::InvalidBook(_)))
+        };
+
+        assert!(invalid_book(&database("Book", b"PDF PDF ", &[&first])));
+
+        // The header announces more records than the table holds.
+        let mut truncated = database("Book", b"BOOKMOBI", &[&first]);
+        truncated[76..78].copy_from_slice(&4000u16.to_be_bytes());
+        truncated.truncate(PDB_HEADER_LEN + 3 * PDB_RECORD_INFO_LEN);
+        assert!(invalid_book(&truncated));
+
+        let mut unordered = database("Book", b"BOOKMOBI", &[&first, b"text"]);
+        let (first_offset, second_offset) = (PDB_HEADER_LEN, PDB_HEADER_LEN + PDB_RECORD_INFO_LEN);
+        let offset = unordered[first_offset..first_offset + 4].to_vec();
+        unordered.copy_within(second_offset..second_offset + 4, first_offset);
+        unordered[second_offset..second_offset + 4].copy_from_slice(&offset);
+        assert!(invalid_book(&unordered));
+
+        // The MOBI header claims to be longer than the first record.
+        let mut long_header = first.clone();
+        put_u32(&mut long_header, MOBI_HEADER_LEN, 100_000);
+        assert!(invalid_book(&database(
+            "Book",
+            b"BOOKMOBI",
+            &[&long_header]
+        )));
+
+        assert!(invalid_book(&database("Book", b"BOOKMOBI", &[])));
+        assert!(invalid_book(&[0; 10]));
+    }
+}
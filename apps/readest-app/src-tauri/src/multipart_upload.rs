@@ -0,0 +1,587 @@
+//! Upload large files to S3-compatible storage in parts.
+//!
+//! The multipart upload is created beforehand, this module uploads the parts with
+//! bounded parallelism, retrying each part on its own, and then completes the upload
+//! or aborts it on failure so the storage does not keep orphaned parts.
+
+use futures_util::{stream, StreamExt, TryStreamExt};
+use reqwest::header;
+use serde::{Deserialize, Serialize};
+use tauri::{command, ipc::Channel, State};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+use tokio_util::codec::{BytesCodec, FramedRead};
+
+use crate::bandwidth::{BandwidthLimiter, TokenBucket};
+use crate::http_client::HttpClientState;
+use crate::transfer_file::{
+    channel_progress, Error, OnProgress, ProgressPayload, ProgressThrottle, Result, RetryPolicy,
+    Transfer, TransferState, TransferStats,
+};
+
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+const DEFAULT_CONCURRENCY: usize = 4;
+
+// The presigned URLs of a multipart upload that has already been created.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MultipartUpload {
+    pub part_size: u64,
+    // One URL per part, in part number order.
+    pub part_urls: Vec<String>,
+    pub complete_url: String,
+    pub abort_url: Option<String>,
+    pub concurrency: Option<usize>,
+}
+
+struct Part<'a> {
+    number: usize,
+    offset: u64,
+    len: u64,
+    url: &'a str,
+}
+
+// Aggregates the progress of all parts into the transfer's progress channel.
+struct MultipartProgress {
+    uploaded: AtomicU64,
+    total: u64,
+    // The highest attempt of any part so far.
+    attempt: AtomicU32,
+    stats: Mutex<TransferStats>,
+    on_progress: OnProgress,
+}
+
+impl MultipartProgress {
+    fn record(&self, len: u64, attempt: u32) {
+        let progress = self.uploaded.fetch_add(len, Ordering::SeqCst) + len;
+        self.attempt.fetch_max(attempt, Ordering::SeqCst);
+        let mut stats = self.stats.lock().unwrap();
+        stats.record_chunk_transfer(len as usize);
+        (self.on_progress)(ProgressPayload::new(progress, self.total, &stats, attempt));
+    }
+
+    // Reports the completion once all parts are uploaded, also of an empty file.
+    fn complete(&self) {
+        let stats = self.stats.lock().unwrap();
+        let attempt = self.attempt.load(Ordering::SeqCst);
+        (self.on_progress)(ProgressPayload::completed(self.total, &stats, attempt));
+    }
+
+    // Takes back the bytes of a failed part attempt, they will be sent again.
+    fn rollback(&self, len: u64) {
+        self.uploaded.fetch_sub(len, Ordering::SeqCst);
+    }
+}
+
+#[command]
+#[allow(clippy::too_many_arguments)]
+pub async fn upload_file_multipart(
+    id: u32,
+    file_path: &str,
+    upload: MultipartUpload,
+    headers: HashMap<String, String>,
+    retry: Option<RetryPolicy>,
+    progress_throttle: Option<ProgressThrottle>,
+    on_progress: Channel<ProgressPayload>,
+    transfers: State<'_, TransferState>,
+    http_client: State<'_, HttpClientState>,
+    bandwidth: State<'_, BandwidthLimiter>,
+) -> Result<String> {
+    let transfer = transfers.register(id)?;
+    run_multipart_upload(
+        &transfer,
+        &http_client.client(),
+        file_path,
+        &upload,
+        &headers,
+        &retry.unwrap_or_default(),
+        channel_progress(on_progress, progress_throttle),
+        bandwidth.upload.clone(),
+    )
+    .await
+}
+
+// Uploads the parts and completes the upload, or aborts it when it fails or is cancelled.
+#[allow(clippy::too_many_arguments)]
+async fn run_multipart_upload(
+    transfer: &Transfer<'_>,
+    client: &reqwest::Client,
+    file_path: &str,
+    upload: &MultipartUpload,
+    headers: &HashMap<String, String>,
+    retry: &RetryPolicy,
+    on_progress: OnProgress,
+    bandwidth: Arc<TokenBucket>,
+) -> Result<String> {
+    let result = transfer
+        .run(upload_parts(
+            client,
+            file_path,
+            upload,
+            headers,
+            retry,
+            on_progress,
+            bandwidth,
+        ))
+        .await;
+    if result.is_err() {
+        if let Some(abort_url) = &upload.abort_url {
+            let _ = client.delete(abort_url).send().await;
+        }
+    }
+    result
+}
+
+async fn upload_parts(
+    client: &reqwest::Client,
+    file_path: &str,
+    upload: &MultipartUpload,
+    headers: &HashMap<String, String>,
+    retry: &RetryPolicy,
+    on_progress: OnProgress,
+    bandwidth: Arc<TokenBucket>,
+) -> Result<String> {
+    let file_len = tokio::fs::metadata(file_path).await?.len();
+    if upload.part_size == 0 {
+        return Err(Error::InvalidArgument("part size must not be zero".into()));
+    }
+    let part_count = file_len.div_ceil(upload.part_size).max(1) as usize;
+    if upload.part_urls.len() != part_count {
+        return Err(Error::InvalidArgument(format!(
+            "expected {part_count} part URLs for {file_len} bytes, got {}",
+            upload.part_urls.len()
+        )));
+    }
+
+    let parts = upload.part_urls.iter().enumerate().map(|(index, url)| {
+        let offset = index as u64 * upload.part_size;
+        Part {
+            number: index + 1,
+            offset,
+            len: upload.part_size.min(file_len - offset),
+            url,
+        }
+    });
+
+    let progress = Arc::new(MultipartProgress {
+        uploaded: AtomicU64::new(0),
+        total: file_len,
+        attempt: AtomicU32::new(1),
+        stats: Mutex::new(TransferStats::default()),
+        on_progress,
+    });
+    // `buffered` keeps the part order, so the ETags line up with the part numbers.
+    let etags: Vec<String> = stream::iter(parts)
+        .map(|part| {
+            let progress = progress.clone();
+            let bandwidth = bandwidth.clone();
+            async move {
+                retry
+                    .run(|attempt| {
+                        upload_part(
+                            client,
+                            file_path,
+                            &part,
+                            headers,
+                            attempt,
+                            progress.clone(),
+                            bandwidth.clone(),
+                        )
+                    })
+                    .await
+            }
+        })
+        .buffered(upload.concurrency.unwrap_or(DEFAULT_CONCURRENCY).max(1))
+        .try_collect()
+        .await?;
+
+    let response = complete_upload(client, &upload.complete_url, &etags).await?;
+    progress.complete();
+    Ok(response)
+}
+
+async fn upload_part(
+    client: &reqwest::Client,
+    file_path: &str,
+    part: &Part<'_>,
+    headers: &HashMap<String, String>,
+    attempt: u32,
+    progress: Arc<MultipartProgress>,
+    bandwidth: Arc<TokenBucket>,
+) -> Result<String> {
+    let mut file = File::open(file_path).await?;
+    file.seek(SeekFrom::Start(part.offset)).await?;
+
+    let sent = Arc::new(AtomicU64::new(0));
+    let stream = {
+        let sent = sent.clone();
+        let progress = progress.clone();
+        FramedRead::new(file.take(part.len), BytesCodec::new()).and_then(move |chunk| {
+            let sent = sent.clone();
+            let progress = progress.clone();
+            let bandwidth = bandwidth.clone();
+            async move {
+                bandwidth.acquire(chunk.len()).await;
+                sent.fetch_add(chunk.len() as u64, Ordering::SeqCst);
+                progress.record(chunk.len() as u64, attempt);
+                Ok(chunk.freeze())
+            }
+        })
+    };
+
+    let mut request = client
+        .put(part.url)
+        .header(header::CONTENT_LENGTH, part.len)
+        .body(reqwest::Body::wrap_stream(stream));
+    for (key, value) in headers {
+        request = request.header(key, value);
+    }
+
+    let result = match request.send().await {
+        Ok(response) if response.status().is_success() => response
+            .headers()
+            .get(header::ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .map(str::to_string)
+            .ok_or(Error::MissingETag(part.number)),
+        Ok(response) => Err(Error::from_response(response).await),
+        Err(e) => Err(e.into()),
+    };
+    if result.is_err() {
+        progress.rollback(sent.load(Ordering::SeqCst));
+    }
+    result
+}
+
+async fn complete_upload(
+    client: &reqwest::Client,
+    complete_url: &str,
+    etags: &[String],
+) -> Result<String> {
+    let parts: String = etags
+        .iter()
+        .enumerate()
+        .map(|(index, etag)| {
+            format!(
+                "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                index + 1,
+                etag
+            )
+        })
+        .collect();
+    let body = format!("<CompleteMultipartUpload>{parts}</CompleteMultipartUpload>");
+
+    let response = client
+        .post(complete_url)
+        .header(header::CONTENT_TYPE, "application/xml")
+        .body(body)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(Error::from_response(response).await);
+    }
+
+    // S3 may report a failed completion in the body of a 200 response.
+    let status = response.status().as_u16();
+    let headers = response.headers().clone();
+    let text = response.text().await?;
+    if text.contains("<Error>") {
+        return Err(Error::HttpErrorCode(status, text, headers));
+    }
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::s3::S3Config;
+    use reqwest::Method;
+    use std::io::Write;
+    use std::time::SystemTime;
+
+    const MIB: u64 = 1024 * 1024;
+
+    fn minio_config() -> Option<S3Config> {
+        let var = |name: &str| std::env::var(format!("READEST_TEST_S3_{name}")).ok();
+        Some(S3Config {
+            endpoint: var("ENDPOINT")?,
+            region: var("REGION").unwrap_or_else(|| "us-east-1".into()),
+            bucket: var("BUCKET")?,
+            access_key_id: var("ACCESS_KEY_ID")?,
+            secret_access_key: var("SECRET_ACCESS_KEY")?,
+            session_token: None,
+            force_path_style: true,
+        })
+    }
+
+    // Start MinIO and create a bucket, then run with the READEST_TEST_S3_* variables set:
+    //
+    //   docker run -p 9000:9000 minio/minio server /data
+    //   READEST_TEST_S3_ENDPOINT=http://localhost:9000 READEST_TEST_S3_BUCKET=readest \
+    //   READEST_TEST_S3_ACCESS_KEY_ID=minioadmin READEST_TEST_S3_SECRET_ACCESS_KEY=minioadmin \
+    //   cargo test multipart_upload -- --ignored
+    #[tokio::test]
+    #[ignore = "needs a MinIO server"]
+    async fn uploads_parts_to_minio() {
+        let config = minio_config().expect("READEST_TEST_S3_* variables are not set");
+        let client = reqwest::Client::new();
+        let key = format!("multipart-{}.bin", rand::random::<u32>());
+
+        // Every part but the last must be at least 5 MiB.
+        let content: Vec<u8> = (0..11 * MIB).map(|i| (i % 251) as u8).collect();
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&content).unwrap();
+        let file_path = file.path().to_str().unwrap();
+
+        let upload = config
+            .create_multipart_upload(&client, &key, content.len() as u64, 5 * MIB, 600)
+            .await
+            .unwrap();
+        assert_eq!(upload.part_urls.len(), 3);
+
+        let events = Arc::new(AtomicU64::new(0));
+        let on_progress: OnProgress = {
+            let events = events.clone();
+            Arc::new(move |_| {
+                events.fetch_add(1, Ordering::SeqCst);
+            })
+        };
+        let result = upload_parts(
+            &client,
+            file_path,
+            &upload,
+            &HashMap::new(),
+            &RetryPolicy::default(),
+            on_progress,
+            Arc::new(TokenBucket::default()),
+        )
+        .await;
+        if result.is_err() {
+            let _ = client
+                .delete(upload.abort_url.as_ref().unwrap())
+                .send()
+                .await;
+        }
+        assert!(result.unwrap().contains("CompleteMultipartUploadResult"));
+        assert!(events.load(Ordering::SeqCst) > 0);
+
+        let url = config
+            .presign(&Method::GET, &key, &[], 600, SystemTime::now())
+            .unwrap();
+        let downloaded = client.get(url).send().await.unwrap().bytes().await.unwrap();
+        assert!(downloaded == content);
+
+        let url = config
+            .presign(&Method::DELETE, &key, &[], 600, SystemTime::now())
+            .unwrap();
+        client.delete(url).send().await.unwrap();
+    }
+
+    // The path and the body of a request.
+    type Received = (String, Vec<u8>);
+
+    // A stand-in for the storage: parts are PUT to `/part/<number>`, the upload is completed
+    // with a POST to `/complete` and aborted with a DELETE to `/abort`.
+    struct Storage {
+        url: String,
+        requests: Arc<Mutex<Vec<Received>>>,
+    }
+
+    impl Storage {
+        // `part` answers a part upload given the part number and how often it was sent,
+        // with the status and the ETag.
+        fn serve(part: impl Fn(usize, usize) -> (u16, Option<String>) + Send + 'static) -> Self {
+            let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+            let url = format!("http://{}", server.server_addr().to_ip().unwrap());
+            let requests = Arc::new(Mutex::new(Vec::new()));
+            let received = requests.clone();
+            std::thread::spawn(move || {
+                for mut request in server.incoming_requests() {
+                    let mut body = Vec::new();
+                    request.as_reader().read_to_end(&mut body).unwrap();
+                    let path = request.url().to_string();
+                    let mut requests = received.lock().unwrap();
+                    let sent = requests.iter().filter(|(p, _)| *p == path).count();
+                    requests.push((path.clone(), body));
+                    drop(requests);
+
+                    let (status, etag, body) = match path.strip_prefix("/part/") {
+                        Some(number) => {
+                            let (status, etag) = part(number.parse().unwrap(), sent + 1);
+                            (status, etag, "")
+                        }
+                        None if path == "/complete" => {
+                            (200, None, "<CompleteMultipartUploadResult/>")
+                        }
+                        None => (204, None, ""),
+                    };
+                    let mut response =
+                        tiny_http::Response::from_string(body).with_status_code(status);
+                    if let Some(etag) = etag {
+                        let header = tiny_http::Header::from_bytes("ETag", etag).unwrap();
+                        response = response.with_header(header);
+                    }
+                    let _ = request.respond(response);
+                }
+            });
+            Self { url, requests }
+        }
+
+        fn upload(&self, part_count: usize, part_size: u64) -> MultipartUpload {
+            MultipartUpload {
+                part_size,
+                part_urls: (1..=part_count)
+                    .map(|number| format!("{}/part/{number}", self.url))
+                    .collect(),
+                complete_url: format!("{}/complete", self.url),
+                abort_url: Some(format!("{}/abort", self.url)),
+                concurrency: Some(2),
+            }
+        }
+
+        fn requests_to(&self, path: &str) -> Vec<Vec<u8>> {
+            let requests = self.requests.lock().unwrap();
+            requests
+                .iter()
+                .filter(|(p, _)| p == path)
+                .map(|(_, body)| body.clone())
+                .collect()
+        }
+    }
+
+    fn retry() -> RetryPolicy {
+        RetryPolicy {
+            base_delay_ms: 1,
+            jitter: 0.0,
+            ..Default::default()
+        }
+    }
+
+    async fn run(
+        file: &tempfile::NamedTempFile,
+        upload: &MultipartUpload,
+        on_progress: OnProgress,
+    ) -> Result<String> {
+        let state = TransferState::default();
+        let transfer = state.register(1).unwrap();
+        run_multipart_upload(
+            &transfer,
+            &reqwest::Client::new(),
+            file.path().to_str().unwrap(),
+            upload,
+            &HashMap::new(),
+            &retry(),
+            on_progress,
+            Arc::new(TokenBucket::default()),
+        )
+        .await
+    }
+
+    fn book() -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(b"0123456789").unwrap();
+        file
+    }
+
+    #[tokio::test]
+    async fn uploads_the_parts_and_completes_with_their_etags() {
+        let storage = Storage::serve(|number, _| (200, Some(format!("\"etag-{number}\""))));
+        let progress = Arc::new(Mutex::new(Vec::new()));
+        let on_progress: OnProgress = {
+            let progress = progress.clone();
+            Arc::new(move |payload| progress.lock().unwrap().push(payload))
+        };
+
+        let file = book();
+        let response = run(&file, &storage.upload(3, 4), on_progress)
+            .await
+            .unwrap();
+        assert_eq!(response, "<CompleteMultipartUploadResult/>");
+        assert_eq!(storage.requests_to("/part/1"), [b"0123"]);
+        assert_eq!(storage.requests_to("/part/2"), [b"4567"]);
+        assert_eq!(storage.requests_to("/part/3"), [b"89"]);
+        let complete = String::from_utf8(storage.requests_to("/complete").remove(0)).unwrap();
+        assert_eq!(
+            complete,
+            "<CompleteMultipartUpload>\
+             <Part><PartNumber>1</PartNumber><ETag>\"etag-1\"</ETag></Part>\
+             <Part><PartNumber>2</PartNumber><ETag>\"etag-2\"</ETag></Part>\
+             <Part><PartNumber>3</PartNumber><ETag>\"etag-3\"</ETag></Part>\
+             </CompleteMultipartUpload>"
+        );
+        assert!(storage.requests_to("/abort").is_empty());
+        let value = serde_json::to_value(progress.lock().unwrap().last().unwrap()).unwrap();
+        assert_eq!(
+            (value["progress"].as_u64(), value["total"].as_u64()),
+            (Some(10), Some(10))
+        );
+    }
+
+    #[tokio::test]
+    async fn retries_a_failed_part_on_its_own() {
+        let storage = Storage::serve(|number, sent| match (number, sent) {
+            (2, 1) => (503, None),
+            _ => (200, Some(format!("\"etag-{number}\""))),
+        });
+
+        let file = book();
+        run(&file, &storage.upload(3, 4), Arc::new(|_| {}))
+            .await
+            .unwrap();
+        assert_eq!(storage.requests_to("/part/1").len(), 1);
+        assert_eq!(storage.requests_to("/part/2"), [b"4567", b"4567"]);
+        assert_eq!(storage.requests_to("/part/3").len(), 1);
+        assert_eq!(storage.requests_to("/complete").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn aborts_the_upload_when_a_part_has_no_etag() {
+        let storage = Storage::serve(|number, _| match number {
+            2 => (200, None),
+            _ => (200, Some(format!("\"etag-{number}\""))),
+        });
+
+        let file = book();
+        let result = run(&file, &storage.upload(3, 4), Arc::new(|_| {})).await;
+        assert!(matches!(result, Err(Error::MissingETag(2))));
+        assert!(storage.requests_to("/complete").is_empty());
+        assert_eq!(storage.requests_to("/abort").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn aborts_the_upload_when_a_part_is_rejected() {
+        let storage = Storage::serve(|number, _| match number {
+            3 => (403, None),
+            _ => (200, Some(format!("\"etag-{number}\""))),
+        });
+
+        let file = book();
+        let result = run(&file, &storage.upload(3, 4), Arc::new(|_| {})).await;
+        assert!(matches!(result, Err(Error::HttpErrorCode(403, ..))));
+        // Client errors are not retried.
+        assert_eq!(storage.requests_to("/part/3").len(), 1);
+        assert_eq!(storage.requests_to("/abort").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rejects_part_urls_that_do_not_match_the_file() {
+        let storage = Storage::serve(|_, _| (200, Some("\"etag\"".into())));
+
+        let file = book();
+        for upload in [
+            storage.upload(2, 4),
+            storage.upload(4, 4),
+            storage.upload(1, 0),
+        ] {
+            let result = run(&file, &upload, Arc::new(|_| {})).await;
+            assert!(matches!(result, Err(Error::InvalidArgument(_))));
+        }
+        assert!(storage.requests_to("/complete").is_empty());
+    }
+}
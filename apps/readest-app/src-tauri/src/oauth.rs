@@ -0,0 +1,428 @@
+//! Receive the OAuth redirect on a loopback port, as the desktop app does in development.
+//!
+//! The listener only accepts a redirect to the expected path that carries the `state` it
+//! issued, then shuts down and emits the authorization code or the tokens, never the URL.
+//! Each flow has its own PKCE verifier, which is only handed out with the code it redeems.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use reqwest::Url;
+use serde::{ser::Serializer, Serialize};
+use sha2::{Digest, Sha256};
+use tauri::{command, Emitter, Manager, State, Window};
+use tiny_http::{Header, Request, Response, Server};
+
+use crate::random_string;
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+type Result<T> = std::result::Result<T, Error>;
+
+const CALLBACK_EVENT: &str = "oauth-callback";
+const DEFAULT_CALLBACK_PATH: &str = "/auth/callback";
+const DEFAULT_TIMEOUT_SECS: u64 = 300;
+
+// Providers that return tokens put them in the URL fragment, which browsers never send to
+// the server, so this page moves the fragment into the query and loads the callback again.
+const FRAGMENT_PAGE: &str = r#"<!doctype html>
+<html><head><meta charset="utf-8"><title>Readest</title></head><body><script>
+if (location.hash.length > 1) {
+  location.replace(location.pathname + location.search + (location.search ? '&' : '?') + location.hash.slice(1));
+} else {
+  document.body.textContent = 'Sign-in failed: the response is missing. You can close this window.';
+}
+</script></body></html>"#;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to start the OAuth server: {0}")]
+    Bind(String),
+}
+
+impl Serialize for Error {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.to_string().as_ref())
+    }
+}
+
+// Everything the webview needs to build the authorization request.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OAuthSession {
+    port: u16,
+    // Carries `state` in its query, so it survives providers that drop the state parameter.
+    redirect_uri: String,
+    state: String,
+    code_challenge: String,
+    code_challenge_method: &'static str,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase", tag = "type")]
+enum OAuthCallback {
+    // `code_verifier` is the PKCE verifier matching the challenge of the session.
+    #[serde(rename_all = "camelCase")]
+    Code {
+        code: String,
+        code_verifier: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    Tokens {
+        access_token: String,
+        refresh_token: Option<String>,
+        expires_in: Option<u64>,
+        token_type: Option<String>,
+    },
+    Error {
+        error: String,
+        description: Option<String>,
+    },
+    // No valid redirect arrived in time.
+    Timeout,
+}
+
+struct Flow {
+    path: String,
+    state: String,
+    code_verifier: String,
+}
+
+struct Listener {
+    server: Arc<Server>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Listener {
+    fn stop(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.server.unblock();
+    }
+}
+
+#[derive(Default)]
+pub struct OAuthServerState {
+    listener: Mutex<Option<Listener>>,
+}
+
+// Starts listening for a single redirect, replacing any flow that is still waiting.
+#[command]
+pub fn start_server(
+    path: Option<String>,
+    timeout_secs: Option<u64>,
+    window: Window,
+    state: State<'_, OAuthServerState>,
+) -> Result<OAuthSession> {
+    let server = Server::http("127.0.0.1:0").map_err(|e| Error::Bind(e.to_string()))?;
+    let port = server
+        .server_addr()
+        .to_ip()
+        .map(|addr| addr.port())
+        .ok_or_else(|| Error::Bind("not listening on an IP address".into()))?;
+
+    let path = path.unwrap_or_else(|| DEFAULT_CALLBACK_PATH.into());
+    let path = format!("/{}", path.trim_start_matches('/'));
+    let flow = Flow {
+        path,
+        state: random_string(32),
+        code_verifier: random_string(64),
+    };
+    let session = OAuthSession {
+        port,
+        redirect_uri: format!("http://localhost:{port}{}?state={}", flow.path, flow.state),
+        state: flow.state.clone(),
+        code_challenge: code_challenge(&flow.code_verifier),
+        code_challenge_method: "S256",
+    };
+
+    let listener = Listener {
+        server: Arc::new(server),
+        cancelled: Arc::new(AtomicBool::new(false)),
+    };
+    let server = listener.server.clone();
+    let cancelled = listener.cancelled.clone();
+    if let Some(previous) = state.listener.lock().unwrap().replace(listener) {
+        previous.stop();
+    }
+
+    let timeout = Duration::from_secs(timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS));
+    std::thread::spawn(move || {
+        if let Some(callback) = listen(&server, &flow, timeout, &cancelled) {
+            let _ = window.emit(CALLBACK_EVENT, callback);
+        }
+        // Release the port unless a newer flow has replaced this one.
+        let state = window.state::<OAuthServerState>();
+        let mut listener = state.listener.lock().unwrap();
+        if listener
+            .as_ref()
+            .is_some_and(|listener| Arc::ptr_eq(&listener.server, &server))
+        {
+            *listener = None;
+        }
+    });
+
+    Ok(session)
+}
+
+#[command]
+pub fn cancel_oauth_server(state: State<'_, OAuthServerState>) {
+    if let Some(listener) = state.listener.lock().unwrap().take() {
+        listener.stop();
+    }
+}
+
+// Serves requests until a valid redirect arrives, returns None when cancelled.
+fn listen(
+    server: &Server,
+    flow: &Flow,
+    timeout: Duration,
+    cancelled: &AtomicBool,
+) -> Option<OAuthCallback> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Some(OAuthCallback::Timeout);
+        }
+        let request = server.recv_timeout(remaining).ok().flatten();
+        if cancelled.load(Ordering::SeqCst) {
+            return None;
+        }
+        if let Some(callback) = request.and_then(|request| handle_request(request, flow)) {
+            return Some(callback);
+        }
+    }
+}
+
+fn handle_request(request: Request, flow: &Flow) -> Option<OAuthCallback> {
+    let Ok(url) = Url::parse(&format!("http://localhost{}", request.url())) else {
+        respond(request, 400, &result_page(false, "The request is invalid."));
+        return None;
+    };
+    if url.path() != flow.path {
+        respond(request, 404, &result_page(false, "Not found."));
+        return None;
+    }
+    let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+    if !["code", "access_token", "error"]
+        .iter()
+        .any(|key| params.contains_key(*key))
+    {
+        respond(request, 200, FRAGMENT_PAGE);
+        return None;
+    }
+    // A redirect without the issued state may be forged by any page the browser visits.
+    if params.get("state") != Some(&flow.state) {
+        respond(
+            request,
+            400,
+            &result_page(false, "The sign-in request is invalid."),
+        );
+        return None;
+    }
+
+    let callback = match params.get("error") {
+        Some(error) => OAuthCallback::Error {
+            error: error.clone(),
+            description: params.get("error_description").cloned(),
+        },
+        None => match params.get("code") {
+            Some(code) => OAuthCallback::Code {
+                code: code.clone(),
+                code_verifier: flow.code_verifier.clone(),
+            },
+            None => OAuthCallback::Tokens {
+                access_token: params["access_token"].clone(),
+                refresh_token: params.get("refresh_token").cloned(),
+                expires_in: params
+                    .get("expires_in")
+                    .and_then(|value| value.parse().ok()),
+                token_type: params.get("token_type").cloned(),
+            },
+        },
+    };
+    let (success, message) = match &callback {
+        OAuthCallback::Error { .. } => (false, "Sign-in was not completed."),
+        _ => (true, "You are signed in."),
+    };
+    respond(request, 200, &result_page(success, message));
+    Some(callback)
+}
+
+// The S256 challenge of RFC 7636.
+fn code_challenge(code_verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+fn respond(request: Request, status: u16, page: &str) {
+    let header = Header::from_bytes("Content-Type", "text/html; charset=utf-8").unwrap();
+    let response = Response::from_string(page)
+        .with_status_code(status)
+        .with_header(header);
+    if let Err(e) = request.respond(response) {
+        eprintln!("Failed to respond to OAuth redirect: {}", e);
+    }
+}
+
+fn result_page(success: bool, message: &str) -> String {
+    let title = if success {
+        "Sign-in complete"
+    } else {
+        "Sign-in failed"
+    };
+    format!(
+        r#"<!doctype html>
+<html><head><meta charset="utf-8"><title>Readest</title>
+<style>body {{ font-family: system-ui, sans-serif; text-align: center; margin-top: 20vh; }}</style>
+</head><body><h1>{title}</h1><p>{message}</p><p>You can close this window and return to Readest.</p></body></html>"#
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Redirect {
+        server: Arc<Server>,
+        url: String,
+        flow: Flow,
+    }
+
+    fn redirect_server() -> Redirect {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", server.server_addr().to_ip().unwrap());
+        Redirect {
+            server: Arc::new(server),
+            url,
+            flow: Flow {
+                path: DEFAULT_CALLBACK_PATH.into(),
+                state: "expected-state".into(),
+                code_verifier: "verifier".into(),
+            },
+        }
+    }
+
+    // Runs the listener in the background and returns what it emits once it stops.
+    fn listen_in_background(
+        redirect: Redirect,
+        timeout: Duration,
+        cancelled: Arc<AtomicBool>,
+    ) -> std::thread::JoinHandle<Option<OAuthCallback>> {
+        std::thread::spawn(move || listen(&redirect.server, &redirect.flow, timeout, &cancelled))
+    }
+
+    async fn get(url: &str) -> (u16, String) {
+        let response = reqwest::get(url).await.unwrap();
+        (response.status().as_u16(), response.text().await.unwrap())
+    }
+
+    #[test]
+    fn derives_the_s256_challenge() {
+        // The example of RFC 7636, appendix B.
+        assert_eq!(
+            code_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    #[tokio::test]
+    async fn accepts_only_the_redirect_with_the_issued_state() {
+        let redirect = redirect_server();
+        let url = format!("{}{}", redirect.url, DEFAULT_CALLBACK_PATH);
+        let other_path = format!("{}/favicon.ico", redirect.url);
+        let listener = listen_in_background(
+            redirect,
+            Duration::from_secs(10),
+            Arc::new(AtomicBool::new(false)),
+        );
+
+        let (status, _) = get(&other_path).await;
+        assert_eq!(status, 404);
+
+        // Without parameters the page moves the fragment into the query.
+        let (status, page) = get(&format!("{url}?state=expected-state")).await;
+        assert_eq!(status, 200);
+        assert!(page.contains("location.hash"));
+
+        let (status, _) = get(&format!("{url}?state=forged&code=stolen")).await;
+        assert_eq!(status, 400);
+        let (status, _) = get(&format!("{url}?code=stolen")).await;
+        assert_eq!(status, 400);
+        assert!(!listener.is_finished());
+
+        let (status, page) = get(&format!("{url}?state=expected-state&code=abc")).await;
+        assert_eq!(status, 200);
+        assert!(page.contains("You are signed in."));
+        let callback = tokio::task::spawn_blocking(|| listener.join().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            serde_json::to_value(callback).unwrap(),
+            serde_json::json!({"type": "code", "code": "abc", "codeVerifier": "verifier"})
+        );
+    }
+
+    #[tokio::test]
+    async fn emits_tokens_and_errors_from_the_query() {
+        for (query, expected) in [
+            (
+                "access_token=at&refresh_token=rt&expires_in=3600&token_type=bearer",
+                serde_json::json!({
+                    "type": "tokens",
+                    "accessToken": "at",
+                    "refreshToken": "rt",
+                    "expiresIn": 3600,
+                    "tokenType": "bearer",
+                }),
+            ),
+            (
+                "error=access_denied&error_description=Denied",
+                serde_json::json!({
+                    "type": "error",
+                    "error": "access_denied",
+                    "description": "Denied",
+                }),
+            ),
+        ] {
+            let redirect = redirect_server();
+            let url = format!(
+                "{}{DEFAULT_CALLBACK_PATH}?state=expected-state&{query}",
+                redirect.url
+            );
+            let listener = listen_in_background(
+                redirect,
+                Duration::from_secs(10),
+                Arc::new(AtomicBool::new(false)),
+            );
+            get(&url).await;
+            let callback = tokio::task::spawn_blocking(|| listener.join().unwrap())
+                .await
+                .unwrap();
+            assert_eq!(serde_json::to_value(callback).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn stops_on_timeout_or_when_cancelled() {
+        let listener = listen_in_background(
+            redirect_server(),
+            Duration::from_millis(50),
+            Arc::new(AtomicBool::new(false)),
+        );
+        assert!(matches!(
+            listener.join().unwrap(),
+            Some(OAuthCallback::Timeout)
+        ));
+
+        let redirect = redirect_server();
+        let server = redirect.server.clone();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let listener = listen_in_background(redirect, Duration::from_secs(10), cancelled.clone());
+        Listener { server, cancelled }.stop();
+        assert!(listener.join().unwrap().is_none());
+    }
+}
@@ -0,0 +1,782 @@
+//! Browse OPDS catalogs such as Calibre-Web and download their books.
+//!
+//! Both OPDS 1.2 Atom feeds and OPDS 2.0 JSON feeds are parsed into the same types,
+//! with all links resolved against the URL of the feed.
+
+use quick_xml::events::Event;
+use reqwest::{header, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tauri::{command, ipc::Channel, State};
+
+use crate::bandwidth::BandwidthLimiter;
+use crate::book_metadata::{xml_attribute, xml_text};
+use crate::http_client::{basic_auth, HttpClientState};
+use crate::transfer_file::{
+    channel_progress, run_download, DownloadParams, Error, ProgressPayload, ProgressThrottle,
+    Result, RetryPolicy, TransferState,
+};
+
+use std::collections::HashMap;
+
+const ACCEPT_FEED: &str =
+    "application/atom+xml, application/opds+json, application/json;q=0.9, */*;q=0.8";
+const ACQUISITION_REL: &str = "http://opds-spec.org/acquisition";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OpdsAuth {
+    // The credentials are only sent to the origin of this URL, not to the hosts that
+    // feeds link to, such as a CDN serving the books.
+    pub catalog_url: String,
+    pub username: String,
+    pub password: Option<String>,
+}
+
+impl OpdsAuth {
+    fn headers(auth: Option<&OpdsAuth>, url: &str) -> HashMap<String, String> {
+        auth.filter(|auth| same_origin(&auth.catalog_url, url))
+            .map(|auth| {
+                HashMap::from([(
+                    header::AUTHORIZATION.to_string(),
+                    basic_auth(&auth.username, auth.password.as_deref()),
+                )])
+            })
+            .unwrap_or_default()
+    }
+}
+
+fn same_origin(a: &str, b: &str) -> bool {
+    match (Url::parse(a), Url::parse(b)) {
+        (Ok(a), Ok(b)) => a.origin().is_tuple() && a.origin() == b.origin(),
+        _ => false,
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OpdsFeed {
+    pub id: Option<String>,
+    pub title: String,
+    pub updated: Option<String>,
+    // The URL the feed was loaded from, after redirects.
+    pub url: String,
+    pub start: Option<String>,
+    pub up: Option<String>,
+    pub next: Option<String>,
+    pub previous: Option<String>,
+    // An OpenSearch description document or a URL template with `{searchTerms}`.
+    pub search: Option<String>,
+    pub entries: Vec<OpdsEntry>,
+}
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OpdsEntry {
+    pub id: Option<String>,
+    pub title: String,
+    pub authors: Vec<String>,
+    pub summary: Option<String>,
+    pub language: Option<String>,
+    pub publisher: Option<String>,
+    pub published: Option<String>,
+    pub updated: Option<String>,
+    pub categories: Vec<String>,
+    pub cover: Option<String>,
+    pub thumbnail: Option<String>,
+    // The feed this entry leads to in a navigation feed.
+    pub navigation: Option<String>,
+    pub acquisitions: Vec<OpdsAcquisition>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OpdsAcquisition {
+    pub href: String,
+    // `acquisition` for a generic link, otherwise `open-access`, `borrow`, `buy`, `sample`...
+    pub rel: String,
+    pub mime_type: Option<String>,
+}
+
+struct Link {
+    rel: String,
+    href: String,
+    mime_type: Option<String>,
+}
+
+impl OpdsFeed {
+    fn add_link(&mut self, link: Link) {
+        let target = match link.rel.as_str() {
+            "start" => &mut self.start,
+            "up" => &mut self.up,
+            "next" => &mut self.next,
+            "previous" | "prev" => &mut self.previous,
+            "search" => &mut self.search,
+            _ => return,
+        };
+        target.get_or_insert(link.href);
+    }
+}
+
+impl OpdsEntry {
+    fn add_link(&mut self, link: Link) {
+        let mime_type = link.mime_type.as_deref().unwrap_or_default();
+        if let Some(rel) = link.rel.strip_prefix(ACQUISITION_REL) {
+            let rel = match rel.trim_start_matches('/') {
+                "" => "acquisition",
+                rel => rel,
+            };
+            self.acquisitions.push(OpdsAcquisition {
+                href: link.href,
+                rel: rel.to_string(),
+                mime_type: link.mime_type,
+            });
+        } else if link.rel.ends_with("/thumbnail") {
+            self.thumbnail.get_or_insert(link.href);
+        } else if link.rel.ends_with("/image") || link.rel.ends_with("/cover") {
+            self.cover.get_or_insert(link.href);
+        } else if is_feed_type(mime_type) && !mime_type.contains("type=entry") {
+            self.navigation.get_or_insert(link.href);
+        }
+    }
+}
+
+fn is_feed_type(mime_type: &str) -> bool {
+    mime_type.starts_with("application/atom+xml") || mime_type.starts_with("application/opds+json")
+}
+
+fn invalid_feed(e: impl std::fmt::Display) -> Error {
+    Error::InvalidFeed(e.to_string())
+}
+
+async fn fetch_feed(
+    client: &reqwest::Client,
+    url: &str,
+    auth: Option<&OpdsAuth>,
+) -> Result<OpdsFeed> {
+    let mut request = client.get(url).header(header::ACCEPT, ACCEPT_FEED);
+    for (name, value) in OpdsAuth::headers(auth, url) {
+        request = request.header(name, value);
+    }
+    let response = request.send().await?;
+    if !response.status().is_success() {
+        return Err(Error::from_response(response).await);
+    }
+    let base = response.url().clone();
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.contains("json"));
+    let body = response.text().await?;
+
+    let mut feed = if is_json || body.trim_start().starts_with('{') {
+        let json = serde_json::from_str(&body).map_err(invalid_feed)?;
+        parse_json_feed(&base, &json)
+    } else {
+        parse_atom_feed(&base, &body)?
+    };
+    feed.url = base.into();
+    Ok(feed)
+}
+
+fn resolve(base: &Url, href: &str) -> String {
+    base.join(href)
+        .map(String::from)
+        .unwrap_or_else(|_| href.to_string())
+}
+
+fn parse_atom_feed(base: &Url, xml: &str) -> Result<OpdsFeed> {
+    let mut reader = quick_xml::Reader::from_str(xml);
+    reader.config_mut().trim_text(true);
+
+    let mut feed = OpdsFeed::default();
+    let mut entry: Option<OpdsEntry> = None;
+    // The local names of the open elements.
+    let mut path: Vec<Vec<u8>> = Vec::new();
+    loop {
+        let event = reader.read_event().map_err(invalid_feed)?;
+        let (element, is_empty) = match &event {
+            Event::Start(element) => (Some(element), false),
+            Event::Empty(element) => (Some(element), true),
+            _ => (None, false),
+        };
+        if let Some(element) = element {
+            let name = element.local_name().as_ref().to_vec();
+            match name.as_slice() {
+                b"entry" => entry = Some(OpdsEntry::default()),
+                b"link" => {
+                    if let Some(href) = xml_attribute(element, b"href") {
+                        let link = Link {
+                            rel: xml_attribute(element, b"rel").unwrap_or_default(),
+                            href: resolve(base, &href),
+                            mime_type: xml_attribute(element, b"type"),
+                        };
+                        match entry.as_mut() {
+                            Some(entry) => entry.add_link(link),
+                            None => feed.add_link(link),
+                        }
+                    }
+                }
+                b"category" => {
+                    if let Some(entry) = entry.as_mut() {
+                        let label =
+                            xml_attribute(element, b"label").or(xml_attribute(element, b"term"));
+                        entry.categories.extend(label);
+                    }
+                }
+                _ => {}
+            }
+            if !is_empty {
+                path.push(name);
+            }
+            continue;
+        }
+
+        let text = match event {
+            Event::Text(text) => xml_text(&text),
+            Event::CData(data) => String::from_utf8_lossy(&data).into_owned(),
+            Event::End(end) => {
+                if end.local_name().as_ref() == b"entry" {
+                    feed.entries.extend(entry.take());
+                }
+                path.pop();
+                continue;
+            }
+            Event::Eof => break,
+            _ => continue,
+        };
+        let name = path.last().map(Vec::as_slice).unwrap_or_default();
+        match entry.as_mut() {
+            Some(entry) => {
+                // XHTML content is split into the texts of its elements.
+                if path
+                    .iter()
+                    .any(|name| name == b"summary" || name == b"content")
+                {
+                    let summary = entry.summary.get_or_insert_with(String::new);
+                    if !summary.is_empty() {
+                        summary.push('\n');
+                    }
+                    summary.push_str(&text);
+                    continue;
+                }
+                match name {
+                    b"id" | b"identifier" => entry.id = Some(text),
+                    b"title" => entry.title = text,
+                    b"name" if path.iter().any(|name| name == b"author") => {
+                        entry.authors.push(text)
+                    }
+                    b"language" => entry.language = Some(text),
+                    b"publisher" => entry.publisher = Some(text),
+                    b"issued" | b"published" => entry.published = Some(text),
+                    b"updated" => entry.updated = Some(text),
+                    _ => {}
+                }
+            }
+            None => match name {
+                b"id" => feed.id = Some(text),
+                b"title" => feed.title = text,
+                b"updated" => feed.updated = Some(text),
+                _ => {}
+            },
+        }
+    }
+    Ok(feed)
+}
+
+fn json_str(value: &Value) -> Option<String> {
+    value.as_str().map(str::to_string)
+}
+
+// A value that may be a single item or an array of them.
+fn json_items(value: &Value) -> Vec<&Value> {
+    match value {
+        Value::Array(items) => items.iter().collect(),
+        Value::Null => Vec::new(),
+        item => vec![item],
+    }
+}
+
+// Contributors and subjects are either strings or objects with a `name`.
+fn json_names(value: &Value) -> Vec<String> {
+    json_items(value)
+        .into_iter()
+        .filter_map(|item| json_str(item).or_else(|| json_str(&item["name"])))
+        .collect()
+}
+
+fn json_links(base: &Url, value: &Value) -> Vec<Link> {
+    json_items(value)
+        .into_iter()
+        .filter_map(|link| {
+            let href = link["href"].as_str()?;
+            let mut href = resolve(base, href);
+            // Templated links such as search must keep their braces unescaped.
+            if link["templated"].as_bool().unwrap_or(false) {
+                href = href.replace("%7B", "{").replace("%7D", "}");
+            }
+            let rels = json_items(&link["rel"]);
+            Some(Link {
+                rel: rels
+                    .first()
+                    .and_then(|rel| json_str(rel))
+                    .unwrap_or_default(),
+                href,
+                mime_type: json_str(&link["type"]),
+            })
+        })
+        .collect()
+}
+
+fn parse_json_publication(base: &Url, publication: &Value) -> OpdsEntry {
+    let metadata = &publication["metadata"];
+    let mut entry = OpdsEntry {
+        id: json_str(&metadata["identifier"]),
+        title: json_str(&metadata["title"]).unwrap_or_default(),
+        authors: json_names(&metadata["author"]),
+        summary: json_str(&metadata["description"]),
+        language: json_items(&metadata["language"])
+            .first()
+            .and_then(|language| json_str(language)),
+        publisher: json_names(&metadata["publisher"]).into_iter().next(),
+        published: json_str(&metadata["published"]),
+        updated: json_str(&metadata["modified"]),
+        categories: json_names(&metadata["subject"]),
+        ..Default::default()
+    };
+    for link in json_links(base, &publication["links"]) {
+        entry.add_link(link);
+    }
+    // Images are listed from the largest to the smallest.
+    let mut images = json_links(base, &publication["images"]).into_iter();
+    if let Some(cover) = images.next() {
+        entry.thumbnail = Some(
+            images
+                .last()
+                .map_or_else(|| cover.href.clone(), |image| image.href),
+        );
+        entry.cover = Some(cover.href);
+    }
+    entry
+}
+
+fn parse_json_collection(base: &Url, collection: &Value, feed: &mut OpdsFeed) {
+    for navigation in json_items(&collection["navigation"]) {
+        feed.entries.push(OpdsEntry {
+            title: json_str(&navigation["title"]).unwrap_or_default(),
+            navigation: navigation["href"].as_str().map(|href| resolve(base, href)),
+            ..Default::default()
+        });
+    }
+    for publication in json_items(&collection["publications"]) {
+        feed.entries.push(parse_json_publication(base, publication));
+    }
+}
+
+fn parse_json_feed(base: &Url, json: &Value) -> OpdsFeed {
+    let metadata = &json["metadata"];
+    let mut feed = OpdsFeed {
+        id: json_str(&metadata["identifier"]),
+        title: json_str(&metadata["title"]).unwrap_or_default(),
+        updated: json_str(&metadata["modified"]),
+        ..Default::default()
+    };
+    for link in json_links(base, &json["links"]) {
+        feed.add_link(link);
+    }
+    parse_json_collection(base, json, &mut feed);
+    // Groups are flattened into the entries of the feed.
+    for group in json_items(&json["groups"]) {
+        parse_json_collection(base, group, &mut feed);
+    }
+    feed
+}
+
+// Loads a feed and, up to `max_pages` pages in total, the pages that follow it.
+#[command]
+pub async fn opds_fetch_feed(
+    url: &str,
+    auth: Option<OpdsAuth>,
+    max_pages: Option<u32>,
+    http_client: State<'_, HttpClientState>,
+) -> Result<OpdsFeed> {
+    fetch_pages(
+        &http_client.client(),
+        url,
+        auth.as_ref(),
+        max_pages.unwrap_or(1),
+    )
+    .await
+}
+
+async fn fetch_pages(
+    client: &reqwest::Client,
+    url: &str,
+    auth: Option<&OpdsAuth>,
+    max_pages: u32,
+) -> Result<OpdsFeed> {
+    let mut feed = fetch_feed(client, url, auth).await?;
+    for _ in 1..max_pages {
+        let Some(next) = feed.next.take() else {
+            break;
+        };
+        let page = fetch_feed(client, &next, auth).await?;
+        feed.entries.extend(page.entries);
+        feed.next = page.next;
+    }
+    Ok(feed)
+}
+
+// Downloads an acquisition link with the credentials of its catalog.
+#[command]
+#[allow(clippy::too_many_arguments)]
+pub async fn opds_download(
+    id: u32,
+    url: &str,
+    file_path: &str,
+    auth: Option<OpdsAuth>,
+    retry: Option<RetryPolicy>,
+    progress_throttle: Option<ProgressThrottle>,
+    on_progress: Channel<ProgressPayload>,
+    transfers: State<'_, TransferState>,
+    http_client: State<'_, HttpClientState>,
+    bandwidth: State<'_, BandwidthLimiter>,
+) -> Result<()> {
+    let transfer = transfers.register(id)?;
+    let params = DownloadParams {
+        client: http_client.client(),
+        bandwidth: bandwidth.download.clone(),
+        url,
+        file_path,
+        headers: OpdsAuth::headers(auth.as_ref(), url),
+        body: None,
+        checksum: None,
+    };
+    run_download(
+        &transfer,
+        &params,
+        &retry.unwrap_or_default(),
+        &channel_progress(on_progress, progress_throttle),
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAVIGATION_FEED: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <id>urn:uuid:catalog</id>
+  <title>My Library</title>
+  <updated>2024-05-01T10:00:00Z</updated>
+  <link rel="start" href="/opds" type="application/atom+xml;profile=opds-catalog;kind=navigation"/>
+  <link rel="search" href="search.xml" type="application/opensearchdescription+xml"/>
+  <entry>
+    <title>New &amp; Noteworthy</title>
+    <id>urn:new</id>
+    <link rel="subsection" href="new?page=1" type="application/atom+xml;profile=opds-catalog;kind=acquisition"/>
+    <content type="text">Recently added books</content>
+  </entry>
+  <entry>
+    <title>Authors</title>
+    <link rel="subsection" href="https://other.example.com/authors" type="application/atom+xml;profile=opds-catalog;kind=navigation"/>
+  </entry>
+</feed>"#;
+
+    const ACQUISITION_FEED: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom" xmlns:dc="http://purl.org/dc/terms/"
+      xmlns:opds="http://opds-spec.org/2010/catalog">
+  <title>New</title>
+  <link rel="next" href="new?page=2" type="application/atom+xml;profile=opds-catalog;kind=acquisition"/>
+  <link rel="up" href="../opds"/>
+  <entry>
+    <title>Moby-Dick</title>
+    <id>urn:isbn:9780142437247</id>
+    <author><name>Herman Melville</name><uri>/authors/1</uri></author>
+    <dc:language>en</dc:language>
+    <dc:publisher>Penguin</dc:publisher>
+    <dc:issued>1851</dc:issued>
+    <updated>2024-04-30T08:00:00Z</updated>
+    <category term="fiction" label="Fiction"/>
+    <category term="sea"/>
+    <summary type="text">A whale&nbsp;of a tale.</summary>
+    <link rel="http://opds-spec.org/image" href="/covers/1.jpg" type="image/jpeg"/>
+    <link rel="http://opds-spec.org/image/thumbnail" href="/covers/1-small.jpg" type="image/jpeg"/>
+    <link rel="http://opds-spec.org/acquisition" href="/download/1.epub" type="application/epub+zip"/>
+    <link rel="http://opds-spec.org/acquisition/open-access" href="http://cdn.example.com/1.pdf" type="application/pdf"/>
+    <link rel="alternate" href="/books/1" type="application/atom+xml;type=entry;profile=opds-catalog"/>
+  </entry>
+</feed>"#;
+
+    const JSON_FEED: &str = r#"{
+  "metadata": {"title": "Popular", "identifier": "urn:popular", "modified": "2024-05-01"},
+  "links": [
+    {"rel": "self", "href": "/opds2/popular", "type": "application/opds+json"},
+    {"rel": ["next"], "href": "popular?page=2", "type": "application/opds+json"},
+    {"rel": "search", "href": "/opds2/search{?query}", "type": "application/opds+json", "templated": true}
+  ],
+  "navigation": [
+    {"title": "Classics", "href": "classics", "type": "application/opds+json"}
+  ],
+  "groups": [{
+    "metadata": {"title": "Featured"},
+    "publications": [{
+      "metadata": {
+        "title": "Pride and Prejudice",
+        "identifier": "urn:isbn:9780141439518",
+        "author": [{"name": "Jane Austen"}],
+        "language": ["en", "fr"],
+        "publisher": "Penguin",
+        "published": "1813",
+        "subject": ["Romance", {"name": "Classics"}],
+        "description": "A novel of manners."
+      },
+      "links": [
+        {"rel": "http://opds-spec.org/acquisition", "href": "/books/2.epub", "type": "application/epub+zip"}
+      ],
+      "images": [
+        {"href": "/covers/2.jpg", "type": "image/jpeg", "width": 1400},
+        {"href": "/covers/2-small.jpg", "type": "image/jpeg", "width": 200}
+      ]
+    }]
+  }]
+}"#;
+
+    fn base() -> Url {
+        Url::parse("https://books.example.com/opds/catalog").unwrap()
+    }
+
+    #[test]
+    fn parses_an_atom_navigation_feed() {
+        let feed = parse_atom_feed(&base(), NAVIGATION_FEED).unwrap();
+        assert_eq!(feed.id.as_deref(), Some("urn:uuid:catalog"));
+        assert_eq!(feed.title, "My Library");
+        assert_eq!(feed.updated.as_deref(), Some("2024-05-01T10:00:00Z"));
+        assert_eq!(
+            feed.start.as_deref(),
+            Some("https://books.example.com/opds")
+        );
+        assert_eq!(
+            feed.search.as_deref(),
+            Some("https://books.example.com/opds/search.xml")
+        );
+        assert_eq!(feed.next, None);
+
+        let [new, authors] = feed.entries.as_slice() else {
+            panic!("expected two entries, got {:?}", feed.entries);
+        };
+        assert_eq!(new.title, "New & Noteworthy");
+        assert_eq!(new.summary.as_deref(), Some("Recently added books"));
+        assert_eq!(
+            new.navigation.as_deref(),
+            Some("https://books.example.com/opds/new?page=1")
+        );
+        assert!(new.acquisitions.is_empty());
+        assert_eq!(
+            authors.navigation.as_deref(),
+            Some("https://other.example.com/authors")
+        );
+    }
+
+    #[test]
+    fn parses_an_atom_acquisition_feed() {
+        let feed = parse_atom_feed(&base(), ACQUISITION_FEED).unwrap();
+        assert_eq!(
+            feed.next.as_deref(),
+            Some("https://books.example.com/opds/new?page=2")
+        );
+        assert_eq!(feed.up.as_deref(), Some("https://books.example.com/opds"));
+
+        let [entry] = feed.entries.as_slice() else {
+            panic!("expected one entry, got {:?}", feed.entries);
+        };
+        assert_eq!(entry.id.as_deref(), Some("urn:isbn:9780142437247"));
+        assert_eq!(entry.title, "Moby-Dick");
+        assert_eq!(entry.authors, ["Herman Melville"]);
+        assert_eq!(entry.language.as_deref(), Some("en"));
+        assert_eq!(entry.publisher.as_deref(), Some("Penguin"));
+        assert_eq!(entry.published.as_deref(), Some("1851"));
+        assert_eq!(entry.updated.as_deref(), Some("2024-04-30T08:00:00Z"));
+        assert_eq!(entry.categories, ["Fiction", "sea"]);
+        assert_eq!(entry.summary.as_deref(), Some("A whale\u{a0}of a tale."));
+        assert_eq!(
+            entry.cover.as_deref(),
+            Some("https://books.example.com/covers/1.jpg")
+        );
+        assert_eq!(
+            entry.thumbnail.as_deref(),
+            Some("https://books.example.com/covers/1-small.jpg")
+        );
+        // The link to the entry document is not a feed to navigate to.
+        assert_eq!(entry.navigation, None);
+
+        let acquisitions: Vec<_> = entry
+            .acquisitions
+            .iter()
+            .map(|link| {
+                (
+                    link.rel.as_str(),
+                    link.href.as_str(),
+                    link.mime_type.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            acquisitions,
+            [
+                (
+                    "acquisition",
+                    "https://books.example.com/download/1.epub",
+                    Some("application/epub+zip")
+                ),
+                (
+                    "open-access",
+                    "http://cdn.example.com/1.pdf",
+                    Some("application/pdf")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_an_opds2_feed() {
+        let json = serde_json::from_str(JSON_FEED).unwrap();
+        let feed = parse_json_feed(&base(), &json);
+        assert_eq!(feed.id.as_deref(), Some("urn:popular"));
+        assert_eq!(feed.title, "Popular");
+        assert_eq!(feed.updated.as_deref(), Some("2024-05-01"));
+        assert_eq!(
+            feed.next.as_deref(),
+            Some("https://books.example.com/opds/popular?page=2")
+        );
+        assert_eq!(
+            feed.search.as_deref(),
+            Some("https://books.example.com/opds2/search{?query}")
+        );
+
+        let [classics, book] = feed.entries.as_slice() else {
+            panic!("expected two entries, got {:?}", feed.entries);
+        };
+        assert_eq!(classics.title, "Classics");
+        assert_eq!(
+            classics.navigation.as_deref(),
+            Some("https://books.example.com/opds/classics")
+        );
+
+        assert_eq!(book.id.as_deref(), Some("urn:isbn:9780141439518"));
+        assert_eq!(book.title, "Pride and Prejudice");
+        assert_eq!(book.authors, ["Jane Austen"]);
+        assert_eq!(book.language.as_deref(), Some("en"));
+        assert_eq!(book.publisher.as_deref(), Some("Penguin"));
+        assert_eq!(book.published.as_deref(), Some("1813"));
+        assert_eq!(book.categories, ["Romance", "Classics"]);
+        assert_eq!(book.summary.as_deref(), Some("A novel of manners."));
+        assert_eq!(
+            book.cover.as_deref(),
+            Some("https://books.example.com/covers/2.jpg")
+        );
+        assert_eq!(
+            book.thumbnail.as_deref(),
+            Some("https://books.example.com/covers/2-small.jpg")
+        );
+        assert_eq!(book.acquisitions.len(), 1);
+        assert_eq!(
+            book.acquisitions[0].href,
+            "https://books.example.com/books/2.epub"
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_atom_feeds() {
+        let result = parse_atom_feed(&base(), "<feed><title>Broken</entry></feed>");
+        assert!(matches!(result, Err(Error::InvalidFeed(_))));
+    }
+
+    fn serve(handler: impl Fn(tiny_http::Request) + Send + 'static) -> String {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", server.server_addr().to_ip().unwrap());
+        std::thread::spawn(move || {
+            for request in server.incoming_requests() {
+                handler(request);
+            }
+        });
+        url
+    }
+
+    fn page(number: u32, next: bool) -> String {
+        let next = if next {
+            format!(r#"<link rel="next" href="?page={}"/>"#, number + 1)
+        } else {
+            String::new()
+        };
+        format!(
+            r#"<feed xmlns="http://www.w3.org/2005/Atom"><title>Page {number}</title>{next}
+               <entry><title>Book {number}</title></entry></feed>"#
+        )
+    }
+
+    #[tokio::test]
+    async fn follows_the_next_links_up_to_the_page_limit() {
+        let server = serve(|request| {
+            let number = match request.url().split_once("page=") {
+                Some((_, number)) => number.parse().unwrap(),
+                None => 1,
+            };
+            let _ = request.respond(tiny_http::Response::from_string(page(number, number < 3)));
+        });
+        let client = reqwest::Client::new();
+        let url = format!("{server}/opds/new");
+        let titles = |feed: &OpdsFeed| -> Vec<String> {
+            feed.entries
+                .iter()
+                .map(|entry| entry.title.clone())
+                .collect()
+        };
+
+        let feed = fetch_pages(&client, &url, None, 2).await.unwrap();
+        assert_eq!(feed.title, "Page 1");
+        assert_eq!(feed.url, url);
+        assert_eq!(titles(&feed), ["Book 1", "Book 2"]);
+        assert_eq!(feed.next, Some(format!("{url}?page=3")));
+
+        let feed = fetch_pages(&client, &url, None, 10).await.unwrap();
+        assert_eq!(titles(&feed), ["Book 1", "Book 2", "Book 3"]);
+        assert_eq!(feed.next, None);
+    }
+
+    #[tokio::test]
+    async fn rejects_feeds_that_cannot_be_parsed() {
+        let server = serve(|request| {
+            let content_type =
+                tiny_http::Header::from_bytes("Content-Type", "application/opds+json");
+            let _ = request.respond(
+                tiny_http::Response::from_string("{\"metadata\":")
+                    .with_header(content_type.unwrap()),
+            );
+        });
+        let result = fetch_pages(&reqwest::Client::new(), &server, None, 1).await;
+        assert!(matches!(result, Err(Error::InvalidFeed(_))));
+    }
+
+    #[test]
+    fn sends_credentials_only_to_the_catalog() {
+        let auth = OpdsAuth {
+            catalog_url: "https://books.example.com/opds".into(),
+            username: "alice".into(),
+            password: Some("secret".into()),
+        };
+        let headers = |url| OpdsAuth::headers(Some(&auth), url);
+
+        assert!(headers("https://books.example.com/opds/new?page=2").contains_key("authorization"));
+        assert!(
+            headers("https://books.example.com:443/download/1.epub").contains_key("authorization")
+        );
+        assert!(headers("https://cdn.example.com/1.epub").is_empty());
+        assert!(headers("http://books.example.com/download/1.epub").is_empty());
+        assert!(headers("https://books.example.com:8443/download/1.epub").is_empty());
+        assert!(headers("not a url").is_empty());
+        assert!(OpdsAuth::headers(None, "https://books.example.com/opds").is_empty());
+    }
+}
@@ -0,0 +1,899 @@
+//! Read the metadata, the outline and a cover image of a PDF without pdf.js.
+//!
+//! Metadata comes from the XMP packet of the catalog, falling back to the Info dictionary.
+//! Pages are not rendered, the cover is the image filling the first page, which is what
+//! scanned books and most ebooks exported to PDF have.
+
+use image::{DynamicImage, GrayImage, ImageFormat, RgbImage};
+use lopdf::{decode_text_string, xobject::PdfImage, Dictionary, Document, Object, ObjectId};
+use quick_xml::events::Event;
+use quick_xml::name::{Namespace, ResolveResult};
+use serde::{ser::Serializer, Serialize};
+use tauri::command;
+
+use crate::book_metadata::{self, write_cover, xml_text};
+use crate::book_metadata::{BookMetadata, Cover, Creator, Identifier, Title};
+use crate::comic_archive::DEFAULT_THUMBNAIL_SIZE;
+
+use std::collections::{HashMap, HashSet};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+const NS_RDF: &[u8] = b"http://www.w3.org/1999/02/22-rdf-syntax-ns#";
+const NS_DC: &[u8] = b"http://purl.org/dc/elements/1.1/";
+const NS_XMP: &[u8] = b"http://ns.adobe.com/xap/1.0/";
+const NS_PDF: &[u8] = b"http://ns.adobe.com/pdf/1.3/";
+// PRISM has several versions, e.g. `.../basic/2.0/` and `.../basic/3.0/`.
+const NS_PRISM_PREFIX: &[u8] = b"http://prismstandard.org/namespaces/basic/";
+
+// Guards against outlines and name trees that reference themselves.
+const MAX_DEPTH: usize = 32;
+// How much the aspect ratio of the cover image may differ from the first page.
+const COVER_ASPECT_TOLERANCE: f32 = 0.25;
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("the PDF is password protected")]
+    Encrypted,
+    #[error(transparent)]
+    Pdf(#[from] lopdf::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl Serialize for Error {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.to_string().as_ref())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OutlineItem {
+    pub title: String,
+    // The 0-based index of the page the item points to.
+    pub page_index: Option<usize>,
+    pub children: Vec<OutlineItem>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PdfDocument {
+    pub metadata: BookMetadata,
+    pub page_count: usize,
+    pub outline: Vec<OutlineItem>,
+}
+
+// The XMP properties used for the metadata, each with all of its values.
+#[derive(Default)]
+struct Xmp {
+    titles: Vec<String>,
+    creators: Vec<String>,
+    descriptions: Vec<String>,
+    subjects: Vec<String>,
+    languages: Vec<String>,
+    publishers: Vec<String>,
+    identifiers: Vec<String>,
+    isbns: Vec<String>,
+    dates: Vec<String>,
+    create_dates: Vec<String>,
+    keywords: Vec<String>,
+}
+
+impl Xmp {
+    fn values(&mut self, namespace: &[u8], name: &[u8]) -> Option<&mut Vec<String>> {
+        let values = match (namespace, name) {
+            (NS_DC, b"title") => &mut self.titles,
+            (NS_DC, b"creator") => &mut self.creators,
+            (NS_DC, b"description") => &mut self.descriptions,
+            (NS_DC, b"subject") => &mut self.subjects,
+            (NS_DC, b"language") => &mut self.languages,
+            (NS_DC, b"publisher") => &mut self.publishers,
+            (NS_DC, b"identifier") => &mut self.identifiers,
+            (NS_DC, b"date") => &mut self.dates,
+            (NS_XMP, b"CreateDate") => &mut self.create_dates,
+            (NS_PDF, b"Keywords") => &mut self.keywords,
+            (namespace, b"isbn") if namespace.starts_with(NS_PRISM_PREFIX) => &mut self.isbns,
+            _ => return None,
+        };
+        Some(values)
+    }
+}
+
+// Reads the metadata and the outline of a PDF, and writes its cover image, if any, to
+// `cover_path`.
+#[command]
+pub async fn read_pdf_document(
+    file_path: PathBuf,
+    cover_path: Option<PathBuf>,
+) -> Result<PdfDocument> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let document = load(&file_path)?;
+        let (mut metadata, cover) = metadata(&document, cover_path.is_some());
+        write_cover(&mut metadata, cover, cover_path.as_deref())?;
+        Ok(PdfDocument {
+            metadata,
+            page_count: document.get_pages().len(),
+            outline: outline(&document),
+        })
+    })
+    .await
+    .map_err(|e| Error::Io(std::io::Error::other(e.to_string())))?
+}
+
+// Reads the metadata of a PDF for `read_book_metadata`.
+pub(crate) fn read(
+    path: &Path,
+    with_cover: bool,
+) -> book_metadata::Result<(BookMetadata, Option<Cover>)> {
+    Ok(metadata(&load(path)?, with_cover))
+}
+
+fn load(path: &Path) -> Result<Document> {
+    let mut document = Document::load(path)?;
+    // Many PDFs are encrypted only to restrict editing, with an empty user password.
+    if document.is_encrypted() && document.decrypt("").is_err() {
+        return Err(Error::Encrypted);
+    }
+    Ok(document)
+}
+
+fn metadata(document: &Document, with_cover: bool) -> (BookMetadata, Option<Cover>) {
+    let catalog = document.catalog().ok();
+    let xmp = catalog
+        .and_then(|catalog| catalog.get(b"Metadata").ok())
+        .and_then(|metadata| dereference(document, metadata).as_stream().ok())
+        .and_then(|stream| stream.get_plain_content().ok())
+        .map(|content| parse_xmp(&String::from_utf8_lossy(&content)))
+        .unwrap_or_default();
+    let info = document
+        .trailer
+        .get(b"Info")
+        .ok()
+        .and_then(|info| dereference(document, info).as_dict().ok());
+    let info_text = |key: &[u8]| {
+        info.and_then(|info| info.get(key).ok())
+            .and_then(|value| text(document, value))
+    };
+
+    let mut metadata = BookMetadata {
+        format: "PDF",
+        ..Default::default()
+    };
+    let title = first(xmp.titles).or_else(|| info_text(b"Title"));
+    metadata.titles.extend(title.map(|value| Title {
+        value,
+        ..Default::default()
+    }));
+    let creators = match xmp.creators {
+        creators if !creators.is_empty() => creators,
+        _ => info_text(b"Author")
+            .map(|author| split(&author, &[';']))
+            .unwrap_or_default(),
+    };
+    metadata.creators = creators
+        .into_iter()
+        .map(|name| Creator {
+            name,
+            file_as: None,
+            roles: vec!["aut".into()],
+        })
+        .collect();
+    metadata.languages = xmp.languages;
+    if metadata.languages.is_empty() {
+        let language = catalog
+            .and_then(|catalog| catalog.get(b"Lang").ok())
+            .and_then(|language| text(document, language));
+        metadata.languages.extend(language);
+    }
+    metadata.identifiers = xmp
+        .isbns
+        .into_iter()
+        .map(|value| Identifier {
+            value,
+            scheme: Some("ISBN".into()),
+        })
+        .chain(xmp.identifiers.into_iter().map(|value| Identifier {
+            value,
+            scheme: None,
+        }))
+        .collect();
+    metadata.publisher = first(xmp.publishers);
+    metadata.published = first(xmp.dates)
+        .or_else(|| first(xmp.create_dates))
+        .map(|date| date.split('T').next().unwrap_or_default().to_string())
+        .or_else(|| info_text(b"CreationDate").and_then(|date| pdf_date(&date)))
+        .filter(|date| !date.is_empty());
+    metadata.description = first(xmp.descriptions).or_else(|| info_text(b"Subject"));
+    metadata.subjects = xmp.subjects;
+    let keywords = first(xmp.keywords).or_else(|| info_text(b"Keywords"));
+    for keyword in keywords.map(|k| split(&k, &[',', ';'])).unwrap_or_default() {
+        if !metadata.subjects.contains(&keyword) {
+            metadata.subjects.push(keyword);
+        }
+    }
+
+    let cover = if with_cover { cover(document) } else { None };
+    (metadata, cover)
+}
+
+fn parse_xmp(xml: &str) -> Xmp {
+    let mut reader = quick_xml::NsReader::from_str(xml);
+    let mut xmp = Xmp::default();
+    // The namespace and the name of the open property, the text of its value.
+    let mut property: Option<(Vec<u8>, Vec<u8>)> = None;
+    let mut has_items = false;
+    let mut text = String::new();
+    loop {
+        let (namespace, event) = match reader.read_resolved_event() {
+            Ok((ResolveResult::Bound(Namespace(namespace)), event)) => (namespace.to_vec(), event),
+            Ok((_, event)) => (Vec::new(), event),
+            Err(e) => {
+                log::warn!("Invalid XMP metadata: {e}");
+                break;
+            }
+        };
+        match event {
+            Event::Start(element) | Event::Empty(element) => {
+                let name = element.local_name().as_ref().to_vec();
+                if namespace == NS_RDF && name == b"Description" {
+                    // Simple properties may be written as attributes of the description.
+                    for attribute in element.attributes().flatten() {
+                        let (ResolveResult::Bound(Namespace(namespace)), name) =
+                            reader.resolve_attribute(attribute.key)
+                        else {
+                            continue;
+                        };
+                        let value = attribute.unescape_value().unwrap_or_default();
+                        if let Some(values) = xmp.values(namespace, name.as_ref()) {
+                            values.push(value.trim().to_string());
+                        }
+                    }
+                } else if namespace == NS_RDF && name == b"li" {
+                    has_items = true;
+                    text.clear();
+                } else if property.is_none() && xmp.values(&namespace, &name).is_some() {
+                    property = Some((namespace, name));
+                    has_items = false;
+                    text.clear();
+                }
+            }
+            Event::Text(t) if property.is_some() => text.push_str(&xml_text(&t)),
+            Event::CData(data) if property.is_some() => {
+                text.push_str(&String::from_utf8_lossy(&data))
+            }
+            Event::End(element) => {
+                let name = element.local_name().as_ref().to_vec();
+                let Some((property_namespace, property_name)) = property.as_ref() else {
+                    continue;
+                };
+                let ends_item = namespace == NS_RDF && name == b"li";
+                let ends_property = namespace == *property_namespace && name == *property_name;
+                if ends_item || (ends_property && !has_items) {
+                    if let Some(values) = xmp.values(property_namespace, property_name) {
+                        values.push(text.trim().to_string());
+                    }
+                    text.clear();
+                }
+                if ends_property {
+                    property = None;
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    for values in [
+        &mut xmp.titles,
+        &mut xmp.creators,
+        &mut xmp.descriptions,
+        &mut xmp.subjects,
+        &mut xmp.languages,
+        &mut xmp.publishers,
+        &mut xmp.identifiers,
+        &mut xmp.isbns,
+        &mut xmp.dates,
+        &mut xmp.create_dates,
+        &mut xmp.keywords,
+    ] {
+        values.retain(|value| !value.is_empty());
+    }
+    xmp
+}
+
+fn outline(document: &Document) -> Vec<OutlineItem> {
+    let Ok(catalog) = document.catalog() else {
+        return Vec::new();
+    };
+    let first = catalog
+        .get(b"Outlines")
+        .ok()
+        .and_then(|outlines| dereference(document, outlines).as_dict().ok())
+        .and_then(|outlines| outlines.get(b"First").ok());
+    let Some(first) = first else {
+        return Vec::new();
+    };
+    let pages: HashMap<ObjectId, usize> = document
+        .page_iter()
+        .enumerate()
+        .map(|(index, id)| (id, index))
+        .collect();
+    let destinations = named_destinations(document, catalog);
+    let mut visited = HashSet::new();
+    outline_items(document, first, &pages, &destinations, &mut visited, 0)
+}
+
+fn outline_items(
+    document: &Document,
+    first: &Object,
+    pages: &HashMap<ObjectId, usize>,
+    destinations: &HashMap<Vec<u8>, &Object>,
+    visited: &mut HashSet<ObjectId>,
+    depth: usize,
+) -> Vec<OutlineItem> {
+    let mut items = Vec::new();
+    let mut next = Some(first);
+    while let Some(Ok(id)) = next.map(Object::as_reference) {
+        if depth > MAX_DEPTH || !visited.insert(id) {
+            break;
+        }
+        let Ok(item) = document.get_dictionary(id) else {
+            break;
+        };
+        let title = item
+            .get(b"Title")
+            .ok()
+            .and_then(|title| text(document, title))
+            .unwrap_or_default();
+        let page_index = destination(document, item)
+            .and_then(|destination| page_index(document, destination, pages, destinations));
+        let children = match item.get(b"First") {
+            Ok(first) => outline_items(document, first, pages, destinations, visited, depth + 1),
+            Err(_) => Vec::new(),
+        };
+        items.push(OutlineItem {
+            title,
+            page_index,
+            children,
+        });
+        next = item.get(b"Next").ok();
+    }
+    items
+}
+
+// The destination of an outline item, either direct or through a GoTo action.
+fn destination<'a>(document: &'a Document, item: &'a Dictionary) -> Option<&'a Object> {
+    if let Ok(destination) = item.get(b"Dest") {
+        return Some(destination);
+    }
+    let action = dereference(document, item.get(b"A").ok()?).as_dict().ok()?;
+    (action.get(b"S").ok()?.as_name().ok()? == b"GoTo")
+        .then(|| action.get(b"D").ok())
+        .flatten()
+}
+
+fn page_index(
+    document: &Document,
+    destination: &Object,
+    pages: &HashMap<ObjectId, usize>,
+    destinations: &HashMap<Vec<u8>, &Object>,
+) -> Option<usize> {
+    let destination = match dereference(document, destination) {
+        Object::Name(name) | Object::String(name, _) => {
+            dereference(document, destinations.get(name)?)
+        }
+        destination => destination,
+    };
+    // A named destination may be a dictionary holding the destination array in `D`.
+    let destination = match destination {
+        Object::Dictionary(dictionary) => dereference(document, dictionary.get(b"D").ok()?),
+        destination => destination,
+    };
+    let page = destination.as_array().ok()?.first()?;
+    pages.get(&page.as_reference().ok()?).copied()
+}
+
+// The named destinations of the `Dests` dictionary and the `Dests` name tree.
+fn named_destinations<'a>(
+    document: &'a Document,
+    catalog: &'a Dictionary,
+) -> HashMap<Vec<u8>, &'a Object> {
+    let mut destinations = HashMap::new();
+    if let Some(dests) = catalog
+        .get(b"Dests")
+        .ok()
+        .and_then(|dests| dereference(document, dests).as_dict().ok())
+    {
+        for (name, destination) in dests.iter() {
+            destinations.insert(name.clone(), destination);
+        }
+    }
+    let tree = catalog
+        .get(b"Names")
+        .ok()
+        .and_then(|names| dereference(document, names).as_dict().ok())
+        .and_then(|names| names.get(b"Dests").ok());
+    if let Some(tree) = tree {
+        let mut visited = HashSet::new();
+        name_tree(document, tree, &mut destinations, &mut visited, 0);
+    }
+    destinations
+}
+
+fn name_tree<'a>(
+    document: &'a Document,
+    node: &'a Object,
+    destinations: &mut HashMap<Vec<u8>, &'a Object>,
+    visited: &mut HashSet<ObjectId>,
+    depth: usize,
+) {
+    if let Ok(id) = node.as_reference() {
+        if !visited.insert(id) {
+            return;
+        }
+    }
+    let Ok(node) = dereference(document, node).as_dict() else {
+        return;
+    };
+    if depth > MAX_DEPTH {
+        return;
+    }
+    if let Ok(names) = node.get(b"Names").and_then(Object::as_array) {
+        for pair in names.chunks_exact(2) {
+            if let Ok(name) = dereference(document, &pair[0]).as_str() {
+                destinations.insert(name.to_vec(), &pair[1]);
+            }
+        }
+    }
+    if let Ok(kids) = node.get(b"Kids").and_then(Object::as_array) {
+        for kid in kids {
+            name_tree(document, kid, destinations, visited, depth + 1);
+        }
+    }
+}
+
+// The largest image of the first page when it has about the shape of the page.
+fn cover(document: &Document) -> Option<Cover> {
+    let page_id = document.page_iter().next()?;
+    let images = document.get_page_images(page_id).unwrap_or_default();
+    let image = images
+        .into_iter()
+        .filter(|image| image.width > 0 && image.height > 0)
+        .max_by_key(|image| image.width.saturating_mul(image.height))?;
+    if let Some([x0, y0, x1, y1]) = media_box(document, page_id) {
+        let page_ratio = (x1 - x0).abs() / (y1 - y0).abs();
+        let image_ratio = image.width as f32 / image.height as f32;
+        if (image_ratio / page_ratio - 1.0).abs() > COVER_ASPECT_TOLERANCE {
+            return None;
+        }
+    }
+    image_cover(document, &image)
+}
+
+fn image_cover(document: &Document, image: &PdfImage) -> Option<Cover> {
+    let image = decode_image(document, image)?;
+    // Scanned pages are often several thousand pixels high, the cover is only a thumbnail.
+    // `thumbnail` also scales smaller images up, so they are kept as they are.
+    let size = DEFAULT_THUMBNAIL_SIZE;
+    let thumbnail = if image.width() > size || image.height() > size {
+        image.thumbnail(size, size)
+    } else {
+        image
+    };
+    // JPEG has no alpha channel.
+    let thumbnail = DynamicImage::ImageRgb8(thumbnail.to_rgb8());
+    let mut data = Vec::new();
+    thumbnail
+        .write_to(&mut Cursor::new(&mut data), ImageFormat::Jpeg)
+        .ok()?;
+    Some(Cover {
+        data,
+        mime_type: "image/jpeg".into(),
+    })
+}
+
+fn decode_image(document: &Document, image: &PdfImage) -> Option<DynamicImage> {
+    let filters = image.filters.as_deref().unwrap_or_default();
+    if let [filter] = filters {
+        if filter == "DCTDecode" {
+            return image::load_from_memory_with_format(image.content, ImageFormat::Jpeg).ok();
+        }
+    }
+    // Other images are raw pixels, only 8-bit gray, RGB and CMYK ones are converted.
+    let is_flate = filters.iter().all(|filter| filter == "FlateDecode");
+    if !is_flate || image.bits_per_component != Some(8) {
+        return None;
+    }
+    let components = match image.color_space.as_deref()? {
+        "DeviceGray" => 1,
+        "DeviceRGB" => 3,
+        "DeviceCMYK" => 4,
+        "ICCBased" => icc_components(document, image.origin_dict)?,
+        _ => return None,
+    };
+    let stream = document.get_object(image.id).ok()?.as_stream().ok()?;
+    let pixels = stream.get_plain_content().ok()?;
+    let width = u32::try_from(image.width).ok()?;
+    let height = u32::try_from(image.height).ok()?;
+    let len = (width as usize)
+        .checked_mul(height as usize)?
+        .checked_mul(components)?;
+    let pixels = pixels.get(..len)?;
+    let image = match components {
+        1 => DynamicImage::ImageLuma8(GrayImage::from_raw(width, height, pixels.to_vec())?),
+        3 => DynamicImage::ImageRgb8(RgbImage::from_raw(width, height, pixels.to_vec())?),
+        4 => {
+            let rgb = pixels
+                .chunks_exact(4)
+                .flat_map(|cmyk| {
+                    let k = 255 - cmyk[3] as u32;
+                    [0, 1, 2].map(|i| ((255 - cmyk[i] as u32) * k / 255) as u8)
+                })
+                .collect();
+            DynamicImage::ImageRgb8(RgbImage::from_raw(width, height, rgb)?)
+        }
+        _ => return None,
+    };
+    Some(image)
+}
+
+// The number of components of an `[/ICCBased stream]` color space.
+fn icc_components(document: &Document, dictionary: &Dictionary) -> Option<usize> {
+    let color_space = dereference(document, dictionary.get(b"ColorSpace").ok()?);
+    let profile = dereference(document, color_space.as_array().ok()?.get(1)?);
+    let components = profile
+        .as_stream()
+        .ok()?
+        .dict
+        .get(b"N")
+        .ok()?
+        .as_i64()
+        .ok()?;
+    Some(components as usize)
+}
+
+// The media box of a page, which may be inherited from its parents.
+fn media_box(document: &Document, page_id: ObjectId) -> Option<[f32; 4]> {
+    let mut node = document.get_dictionary(page_id).ok()?;
+    for _ in 0..MAX_DEPTH {
+        if let Ok(media_box) = node.get(b"MediaBox") {
+            let values = dereference(document, media_box).as_array().ok()?;
+            let values: Vec<f32> = values.iter().filter_map(|v| v.as_float().ok()).collect();
+            return values.try_into().ok();
+        }
+        node = dereference(document, node.get(b"Parent").ok()?)
+            .as_dict()
+            .ok()?;
+    }
+    None
+}
+
+fn dereference<'a>(document: &'a Document, object: &'a Object) -> &'a Object {
+    document
+        .dereference(object)
+        .map(|(_, object)| object)
+        .unwrap_or(object)
+}
+
+fn text(document: &Document, object: &Object) -> Option<String> {
+    let text = decode_text_string(dereference(document, object)).ok()?;
+    let text = text.trim_matches(|c: char| c.is_whitespace() || c == '\0');
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+fn first(values: Vec<String>) -> Option<String> {
+    values.into_iter().next()
+}
+
+fn split(text: &str, separators: &[char]) -> Vec<String> {
+    text.split(separators)
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+// Converts a PDF date such as `D:20200102030405+01'00'` to `2020-01-02`.
+fn pdf_date(date: &str) -> Option<String> {
+    let date = date.strip_prefix("D:").unwrap_or(date);
+    let digits: String = date.chars().take_while(char::is_ascii_digit).collect();
+    match digits.len() {
+        len if len >= 8 => Some(format!(
+            "{}-{}-{}",
+            &digits[..4],
+            &digits[4..6],
+            &digits[6..8]
+        )),
+        len if len >= 6 => Some(format!("{}-{}", &digits[..4], &digits[4..6])),
+        len if len >= 4 => Some(digits[..4].to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use lopdf::{dictionary, Stream, StringFormat};
+    use tempfile::NamedTempFile;
+
+    const XMP: &str = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+<rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about="" xmlns:dc="http://purl.org/dc/elements/1.1/"
+      xmlns:prism="http://prismstandard.org/namespaces/basic/3.0/"
+      prism:isbn="9780000000000">
+    <dc:title><rdf:Alt><rdf:li xml:lang="x-default">XMP Title</rdf:li></rdf:Alt></dc:title>
+    <dc:creator><rdf:Seq><rdf:li>Ann Author</rdf:li><rdf:li>Bob Author</rdf:li></rdf:Seq></dc:creator>
+    <dc:subject><rdf:Bag><rdf:li>History</rdf:li></rdf:Bag></dc:subject>
+  </rdf:Description>
+</rdf:RDF>
+</x:xmpmeta>"#;
+
+    fn string(text: &str) -> Object {
+        Object::String(text.as_bytes().to_vec(), StringFormat::Literal)
+    }
+
+    // A document of `page_count` pages of 100x150 points, `build` adds to its catalog.
+    fn new_document(
+        page_count: usize,
+        build: impl FnOnce(&mut Document, &[ObjectId], &mut Dictionary),
+    ) -> Document {
+        let mut document = Document::with_version("1.7");
+        let pages_id = document.new_object_id();
+        let page_ids: Vec<ObjectId> = (0..page_count)
+            .map(|_| {
+                document.add_object(dictionary! {
+                    "Type" => "Page",
+                    "Parent" => pages_id,
+                    "Resources" => dictionary! {},
+                })
+            })
+            .collect();
+        let kids: Vec<Object> = page_ids.iter().map(|&id| id.into()).collect();
+        document.objects.insert(
+            pages_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages",
+                "Kids" => kids,
+                "Count" => page_count as i64,
+                "MediaBox" => vec![0.into(), 0.into(), 100.into(), 150.into()],
+            }),
+        );
+        let mut catalog = dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+        };
+        build(&mut document, &page_ids, &mut catalog);
+        let catalog_id = document.add_object(catalog);
+        document.trailer.set("Root", catalog_id);
+        document
+    }
+
+    fn reload(mut document: Document) -> Document {
+        let file = NamedTempFile::new().unwrap();
+        document.save(file.path()).unwrap();
+        load(file.path()).unwrap()
+    }
+
+    fn add_image(document: &mut Document, page_id: ObjectId, width: i64, height: i64) {
+        let pixels = vec![0x80; (width * height * 3).max(0) as usize];
+        add_image_stream(document, page_id, width, height, dictionary! {}, pixels);
+    }
+
+    fn add_image_stream(
+        document: &mut Document,
+        page_id: ObjectId,
+        width: i64,
+        height: i64,
+        filter: Dictionary,
+        content: Vec<u8>,
+    ) {
+        let mut dict = dictionary! {
+            "Type" => "XObject",
+            "Subtype" => "Image",
+            "Width" => width,
+            "Height" => height,
+            "ColorSpace" => "DeviceRGB",
+            "BitsPerComponent" => 8,
+        };
+        dict.extend(&filter);
+        let image = document.add_object(Stream::new(dict, content));
+        let page = document.get_dictionary_mut(page_id).unwrap();
+        page.set(
+            "Resources",
+            dictionary! { "XObject" => dictionary! { "Im0" => image } },
+        );
+    }
+
+    #[test]
+    fn reads_xmp_metadata_and_falls_back_to_the_info_dictionary() {
+        let mut document = new_document(1, |document, _, catalog| {
+            let xmp = document.add_object(Stream::new(
+                dictionary! { "Type" => "Metadata", "Subtype" => "XML" },
+                XMP.as_bytes().to_vec(),
+            ));
+            catalog.set("Metadata", xmp);
+            catalog.set("Lang", string("de"));
+        });
+        let info = document.add_object(dictionary! {
+            "Title" => string("Info Title"),
+            "Author" => string("Info Author"),
+            "Subject" => string("Info Subject"),
+            "Keywords" => string("History, Maps; Travel"),
+            "CreationDate" => string("D:20200102030405+01'00'"),
+        });
+        document.trailer.set("Info", info);
+        let (metadata, _) = metadata(&reload(document), false);
+
+        assert_eq!(metadata.titles[0].value, "XMP Title");
+        let creators: Vec<_> = metadata.creators.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(creators, ["Ann Author", "Bob Author"]);
+        assert_eq!(metadata.identifiers[0].value, "9780000000000");
+        assert_eq!(metadata.identifiers[0].scheme.as_deref(), Some("ISBN"));
+        assert_eq!(metadata.languages, ["de"]);
+        assert_eq!(metadata.description.as_deref(), Some("Info Subject"));
+        assert_eq!(metadata.published.as_deref(), Some("2020-01-02"));
+        assert_eq!(metadata.subjects, ["History", "Maps", "Travel"]);
+    }
+
+    #[test]
+    fn reads_the_outline_with_named_destinations() {
+        let document = new_document(3, |document, pages, catalog| {
+            let dests = dictionary! {
+                "chapter-2" => dictionary! {
+                    "D" => vec![pages[1].into(), "Fit".into()],
+                },
+            };
+            let names = document.add_object(dictionary! {
+                "Names" => vec![string("chapter-3"), vec![pages[2].into(), "Fit".into()].into()],
+            });
+            let outlines_id = document.new_object_id();
+            let first_id = document.new_object_id();
+            let second_id = document.new_object_id();
+            let child_id = document.new_object_id();
+            document.objects.insert(
+                child_id,
+                dictionary! {
+                    "Title" => string("Section"),
+                    "Parent" => second_id,
+                    "A" => dictionary! { "S" => "GoTo", "D" => string("chapter-3") },
+                }
+                .into(),
+            );
+            document.objects.insert(
+                second_id,
+                dictionary! {
+                    "Title" => string("Chapter 2"),
+                    "Parent" => outlines_id,
+                    "Prev" => first_id,
+                    "Dest" => Object::Name(b"chapter-2".to_vec()),
+                    "First" => child_id,
+                    "Last" => child_id,
+                }
+                .into(),
+            );
+            document.objects.insert(
+                first_id,
+                dictionary! {
+                    "Title" => string("Chapter 1"),
+                    "Parent" => outlines_id,
+                    "Next" => second_id,
+                    "Dest" => vec![pages[0].into(), "Fit".into()],
+                }
+                .into(),
+            );
+            document.objects.insert(
+                outlines_id,
+                dictionary! { "First" => first_id, "Last" => second_id }.into(),
+            );
+            catalog.set("Outlines", outlines_id);
+            catalog.set("Dests", dests);
+            catalog.set("Names", dictionary! { "Dests" => names });
+        });
+        let outline = outline(&reload(document));
+
+        let items: Vec<_> = outline
+            .iter()
+            .map(|item| (item.title.as_str(), item.page_index))
+            .collect();
+        assert_eq!(items, [("Chapter 1", Some(0)), ("Chapter 2", Some(1))]);
+        assert_eq!(outline[1].children[0].title, "Section");
+        assert_eq!(outline[1].children[0].page_index, Some(2));
+    }
+
+    #[test]
+    fn stops_at_name_trees_that_reference_themselves() {
+        let document = new_document(2, |document, pages, catalog| {
+            let root_id = document.new_object_id();
+            let leaf = document.add_object(dictionary! {
+                "Names" => vec![string("end"), vec![pages[1].into(), "Fit".into()].into()],
+                "Kids" => vec![root_id.into()],
+            });
+            document
+                .objects
+                .insert(root_id, dictionary! { "Kids" => vec![leaf.into()] }.into());
+            let item = document.add_object(dictionary! {
+                "Title" => string("End"),
+                "Dest" => string("end"),
+            });
+            let outlines = document.add_object(dictionary! { "First" => item, "Last" => item });
+            catalog.set("Outlines", outlines);
+            catalog.set("Names", dictionary! { "Dests" => root_id });
+        });
+        let outline = outline(&reload(document));
+
+        assert_eq!(outline.len(), 1);
+        assert_eq!(outline[0].page_index, Some(1));
+    }
+
+    #[test]
+    fn reads_the_image_filling_the_first_page_as_the_cover() {
+        let document = new_document(1, |document, pages, _| {
+            add_image(document, pages[0], 20, 30);
+        });
+        let (_, cover) = metadata(&reload(document), true);
+        let cover = cover.unwrap();
+        assert_eq!(cover.mime_type, "image/jpeg");
+        let image = image::load_from_memory(&cover.data).unwrap();
+        assert_eq!((image.width(), image.height()), (20, 30));
+
+        // A landscape image is not the cover of a portrait page.
+        let document = new_document(1, |document, pages, _| {
+            add_image(document, pages[0], 30, 20);
+        });
+        assert!(metadata(&reload(document), true).1.is_none());
+    }
+
+    #[test]
+    fn downscales_the_cover_to_a_thumbnail() {
+        let document = new_document(1, |document, pages, _| {
+            add_image(document, pages[0], 1000, 1500);
+        });
+        let cover = metadata(&reload(document), true).1.unwrap();
+        let image = image::load_from_memory(&cover.data).unwrap();
+        assert_eq!((image.width(), image.height()), (341, 512));
+
+        let mut jpeg = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(600, 900))
+            .write_to(&mut Cursor::new(&mut jpeg), ImageFormat::Jpeg)
+            .unwrap();
+        let document = new_document(1, |document, pages, _| {
+            let filter = dictionary! { "Filter" => "DCTDecode" };
+            add_image_stream(document, pages[0], 600, 900, filter, jpeg);
+        });
+        let cover = metadata(&reload(document), true).1.unwrap();
+        assert_eq!(cover.mime_type, "image/jpeg");
+        let image = image::load_from_memory(&cover.data).unwrap();
+        assert_eq!((image.width(), image.height()), (341, 512));
+    }
+
+    #[test]
+    fn skips_cover_images_with_invalid_dimensions() {
+        for (width, height) in [(i64::MAX, i64::MAX), (1 << 40, 3 << 39), (-2, -3)] {
+            let document = new_document(1, |document, pages, _| {
+                add_image(document, pages[0], 0, 0);
+                let page = document.get_dictionary(pages[0]).unwrap();
+                let image = page
+                    .get(b"Resources")
+                    .and_then(Object::as_dict)
+                    .and_then(|resources| resources.get(b"XObject"))
+                    .and_then(Object::as_dict)
+                    .and_then(|xobject| xobject.get(b"Im0"))
+                    .and_then(Object::as_reference)
+                    .unwrap();
+                let stream = document
+                    .get_object_mut(image)
+                    .unwrap()
+                    .as_stream_mut()
+                    .unwrap();
+                stream.dict.set("Width", width);
+                stream.dict.set("Height", height);
+            });
+            assert!(metadata(&reload(document), true).1.is_none());
+        }
+    }
+}
@@ -7,61 +7,108 @@
 //! Download files from a remote HTTP server to disk.
 
 use futures_util::TryStreamExt;
-use serde::{ser::Serializer, Serialize};
-use tauri::{command, ipc::Channel};
+use rand::{distributions::Alphanumeric, Rng};
+use reqwest::{header, StatusCode};
+use serde::{ser::Serializer, Deserialize, Serialize};
+use tauri::{command, ipc::Channel, State};
 use tokio::{
-    fs::File,
+    fs::{File, OpenOptions},
     io::{AsyncWriteExt, BufWriter},
 };
 use tokio_util::codec::{BytesCodec, FramedRead};
+use tokio_util::sync::CancellationToken;
 
 use read_progress_stream::ReadProgressStream;
 
+use crate::bandwidth::{BandwidthLimiter, TokenBucket};
+use crate::checksum::{Checksum, ChecksumHasher};
+use crate::http_client::HttpClientState;
+
 use std::collections::HashMap;
-use std::time::Instant;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+pub type Result<T> = std::result::Result<T, Error>;
 
-type Result<T> = std::result::Result<T, Error>;
+// Weight of the latest speed sample in the exponentially weighted moving average.
+const SPEED_SMOOTHING: f64 = 0.3;
 
 // The TransferStats struct tracks both transfer speed and cumulative transfer progress.
 pub struct TransferStats {
-    accumulated_chunk_len: usize, // Total length of chunks transferred in the current period
-    accumulated_time: u128,       // Total time taken for the transfers in the current period
-    pub transfer_speed: u64,      // Calculated transfer speed in bytes per second
-    pub total_transferred: u64,   // Cumulative total of all transferred data
-    start_time: Instant,          // Time when the current period started
-    granularity: u32, // Time period (in milliseconds) over which the transfer speed is calculated
+    pub transfer_speed: u64,    // Smoothed transfer speed in bytes per second
+    pub total_transferred: u64, // Cumulative total of all transferred data
+    start_time: Instant,        // Time when the transfer started
+    period_start: Instant,      // Time when the current sampling period started
+    period_len: u64,            // Total length of chunks transferred in the current period
+    sampled: bool,              // Whether a full period has been sampled yet
+    granularity: Duration,      // Time period over which each speed sample is measured
 }
 
 impl TransferStats {
-    // Initializes a new TransferStats instance with the specified granularity.
+    // Initializes a new TransferStats instance with the specified granularity in milliseconds.
     pub fn start(granularity: u32) -> Self {
+        let now = Instant::now();
         Self {
-            accumulated_chunk_len: 0,
-            accumulated_time: 0,
             transfer_speed: 0,
             total_transferred: 0,
-            start_time: Instant::now(),
-            granularity,
+            start_time: now,
+            period_start: now,
+            period_len: 0,
+            sampled: false,
+            granularity: Duration::from_millis(granularity as u64),
         }
     }
+
     // Records the transfer of a data chunk and updates both transfer speed and total progress.
     pub fn record_chunk_transfer(&mut self, chunk_len: usize) {
-        let now = Instant::now();
-        let it_took = now.duration_since(self.start_time).as_millis();
-        self.accumulated_chunk_len += chunk_len;
+        self.record_chunk_transfer_at(chunk_len, Instant::now());
+    }
+
+    fn record_chunk_transfer_at(&mut self, chunk_len: usize, now: Instant) {
         self.total_transferred += chunk_len as u64;
-        self.accumulated_time += it_took;
+        self.period_len += chunk_len as u64;
 
-        // Calculate transfer speed if accumulated time exceeds granularity.
-        if self.accumulated_time >= self.granularity as u128 {
-            self.transfer_speed =
-                (self.accumulated_chunk_len as u128 / self.accumulated_time * 1024) as u64;
-            self.accumulated_chunk_len = 0;
-            self.accumulated_time = 0;
+        let period = now.saturating_duration_since(self.period_start);
+        if period >= self.granularity && !period.is_zero() {
+            // Fold the speed of the completed period into the moving average.
+            let sample = self.period_len as f64 / period.as_secs_f64();
+            let speed = if self.sampled {
+                SPEED_SMOOTHING * sample + (1.0 - SPEED_SMOOTHING) * self.transfer_speed as f64
+            } else {
+                sample
+            };
+            self.transfer_speed = speed.round() as u64;
+            self.sampled = true;
+            self.period_len = 0;
+            self.period_start = now;
+        } else if !self.sampled {
+            // Until the first period completes, report the average speed so far.
+            let elapsed = now.saturating_duration_since(self.start_time);
+            if !elapsed.is_zero() {
+                self.transfer_speed =
+                    (self.total_transferred as f64 / elapsed.as_secs_f64()).round() as u64;
+            }
         }
+    }
+
+    // Time since the transfer started.
+    pub fn elapsed(&self) -> Duration {
+        self.start_time.elapsed()
+    }
 
-        // Reset the start time for the next period.
-        self.start_time = now;
+    // Estimated time to transfer the remaining bytes at the current speed.
+    pub fn eta(&self, remaining: u64) -> Option<Duration> {
+        if remaining == 0 {
+            Some(Duration::ZERO)
+        } else if self.transfer_speed == 0 {
+            None
+        } else {
+            Some(Duration::from_secs_f64(
+                remaining as f64 / self.transfer_speed as f64,
+            ))
+        }
     }
 }
 
@@ -80,8 +127,99 @@ pub enum Error {
     Request(#[from] reqwest::Error),
     #[error("{0}")]
     ContentLength(String),
+    #[error("invalid argument: {0}")]
+    InvalidArgument(String),
     #[error("request failed with status code {0}: {1}")]
-    HttpErrorCode(u16, String),
+    HttpErrorCode(u16, String, header::HeaderMap),
+    #[error("transfer cancelled")]
+    Cancelled,
+    #[error("transfer {0} is already running")]
+    AlreadyRunning(u32),
+    #[error("checksum mismatch: expected {expected}, got {actual}")]
+    ChecksumMismatch { expected: String, actual: String },
+    #[error("part {0} has no ETag")]
+    MissingETag(usize),
+    #[error("the server cannot resume the interrupted download")]
+    ResumeNotSupported,
+    #[error("invalid response: {0}")]
+    InvalidResponse(String),
+    #[error("invalid OPDS feed: {0}")]
+    InvalidFeed(String),
+}
+
+impl Error {
+    pub(crate) async fn from_response(response: reqwest::Response) -> Self {
+        let status = response.status().as_u16();
+        let headers = response.headers().clone();
+        Error::HttpErrorCode(status, response.text().await.unwrap_or_default(), headers)
+    }
+}
+
+fn verify_checksum(checksum: &Checksum, hasher: ChecksumHasher) -> Result<()> {
+    let actual = hasher.finalize();
+    if checksum.matches(&actual) {
+        Ok(())
+    } else {
+        Err(Error::ChecksumMismatch {
+            expected: checksum.value.clone(),
+            actual,
+        })
+    }
+}
+
+// Response headers passed on to the frontend, e.g. to refresh credentials or to back off.
+const ERROR_HEADERS: [header::HeaderName; 5] = [
+    header::CONTENT_TYPE,
+    header::LOCATION,
+    header::RETRY_AFTER,
+    header::WWW_AUTHENTICATE,
+    header::ETAG,
+];
+
+// Error bodies are cut to this many bytes.
+const ERROR_BODY_LIMIT: usize = 1024;
+
+// The error as seen by the frontend, tagged by `kind`.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ErrorDetails<'a> {
+    kind: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<u16>,
+    // Why a request failed without a response: timeout, connect, redirect, body, decode or request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'static str>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    headers: HashMap<&'static str, &'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body: Option<&'a str>,
+    // Whether the default retry policy would try again.
+    retryable: bool,
+}
+
+fn request_error_reason(error: &reqwest::Error) -> &'static str {
+    if error.is_timeout() {
+        "timeout"
+    } else if error.is_connect() {
+        "connect"
+    } else if error.is_redirect() {
+        "redirect"
+    } else if error.is_body() {
+        "body"
+    } else if error.is_decode() {
+        "decode"
+    } else {
+        "request"
+    }
+}
+
+fn truncate(text: &str, limit: usize) -> &str {
+    let mut end = text.len().min(limit);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
 }
 
 impl Serialize for Error {
@@ -89,7 +227,198 @@ impl Serialize for Error {
     where
         S: Serializer,
     {
-        serializer.serialize_str(self.to_string().as_ref())
+        let mut details = ErrorDetails {
+            kind: match self {
+                Error::Io(_) => "io",
+                Error::Request(_) => "request",
+                Error::ContentLength(_) => "contentLength",
+                Error::InvalidArgument(_) => "invalidArgument",
+                Error::HttpErrorCode(..) => "httpErrorCode",
+                Error::Cancelled => "cancelled",
+                Error::AlreadyRunning(_) => "alreadyRunning",
+                Error::ChecksumMismatch { .. } => "checksumMismatch",
+                Error::MissingETag(_) => "missingETag",
+                Error::ResumeNotSupported => "resumeNotSupported",
+                Error::InvalidResponse(_) => "invalidResponse",
+                Error::InvalidFeed(_) => "invalidFeed",
+            },
+            message: self.to_string(),
+            status: None,
+            reason: None,
+            headers: HashMap::new(),
+            body: None,
+            retryable: RetryPolicy::default().should_retry(self),
+        };
+        match self {
+            Error::Request(e) => {
+                details.status = e.status().map(|status| status.as_u16());
+                details.reason = Some(request_error_reason(e));
+            }
+            Error::HttpErrorCode(status, body, headers) => {
+                details.message = format!("request failed with status code {status}");
+                details.status = Some(*status);
+                details.headers = ERROR_HEADERS
+                    .iter()
+                    .filter_map(|name| {
+                        let value = headers.get(name)?.to_str().ok()?;
+                        Some((name.as_str(), value))
+                    })
+                    .collect();
+                details.body =
+                    Some(truncate(body, ERROR_BODY_LIMIT)).filter(|body| !body.is_empty());
+            }
+            _ => {}
+        }
+        details.serialize(serializer)
+    }
+}
+
+// Keeps track of the running transfers by the id given by the frontend, so they can be cancelled.
+#[derive(Default)]
+pub struct TransferState {
+    transfers: Mutex<HashMap<u32, CancellationToken>>,
+}
+
+impl TransferState {
+    // Fails when a transfer with the same id is still running, including one that was
+    // cancelled but has not stopped yet.
+    pub(crate) fn register(&self, id: u32) -> Result<Transfer<'_>> {
+        let token = self.reserve(id)?;
+        Ok(self.adopt(id, token))
+    }
+
+    // Registers a transfer ahead of the task that runs it, which takes it over with `adopt`.
+    pub(crate) fn reserve(&self, id: u32) -> Result<CancellationToken> {
+        let mut transfers = self.transfers.lock().unwrap();
+        if transfers.contains_key(&id) {
+            return Err(Error::AlreadyRunning(id));
+        }
+        let token = CancellationToken::new();
+        transfers.insert(id, token.clone());
+        Ok(token)
+    }
+
+    pub(crate) fn adopt(&self, id: u32, token: CancellationToken) -> Transfer<'_> {
+        Transfer {
+            state: self,
+            id,
+            token,
+        }
+    }
+
+    pub(crate) fn cancel(&self, id: u32) -> bool {
+        match self.transfers.lock().unwrap().get(&id) {
+            Some(token) => {
+                token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+// A registered transfer, removed from the TransferState when dropped.
+pub(crate) struct Transfer<'a> {
+    state: &'a TransferState,
+    id: u32,
+    token: CancellationToken,
+}
+
+impl Transfer<'_> {
+    // Drives the future until it completes or the transfer is cancelled.
+    pub(crate) async fn run<T>(&self, future: impl Future<Output = Result<T>>) -> Result<T> {
+        self.token
+            .run_until_cancelled(future)
+            .await
+            .unwrap_or(Err(Error::Cancelled))
+    }
+}
+
+impl Drop for Transfer<'_> {
+    fn drop(&mut self) {
+        self.state.transfers.lock().unwrap().remove(&self.id);
+    }
+}
+
+// How failed transfers are retried, with exponential backoff between attempts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+    // Fraction of the delay randomly added or removed, to spread out retries.
+    pub jitter: f64,
+    pub retryable_status_codes: Vec<u16>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay_ms: 1000,
+            max_delay_ms: 30_000,
+            jitter: 0.2,
+            retryable_status_codes: vec![408, 425, 429, 500, 502, 503, 504],
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn should_retry(&self, error: &Error) -> bool {
+        match error {
+            Error::Request(e) => e.is_timeout() || e.is_connect() || e.is_request() || e.is_body(),
+            Error::HttpErrorCode(status, _, _) => self.retryable_status_codes.contains(status),
+            _ => false,
+        }
+    }
+
+    // The server's Retry-After takes precedence over the computed backoff.
+    fn delay(&self, attempt: u32, error: &Error) -> Duration {
+        let max_delay = Duration::from_millis(self.max_delay_ms);
+        if let Some(retry_after) = retry_after(error) {
+            return retry_after.min(max_delay);
+        }
+        let backoff = self
+            .base_delay_ms
+            .saturating_mul(1 << (attempt - 1).min(16))
+            .min(self.max_delay_ms) as f64;
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let factor = 1.0 + rand::thread_rng().gen_range(-jitter..=jitter);
+        Duration::from_millis((backoff * factor) as u64).min(max_delay)
+    }
+
+    // Runs the attempts until one succeeds, fails permanently, or the attempts are exhausted.
+    pub(crate) async fn run<T, F, Fut>(&self, mut attempt: F) -> Result<T>
+    where
+        F: FnMut(u32) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempt_no = 1;
+        loop {
+            match attempt(attempt_no).await {
+                Err(e) if attempt_no < self.max_attempts && self.should_retry(&e) => {
+                    tokio::time::sleep(self.delay(attempt_no, &e)).await;
+                    attempt_no += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+// Parses Retry-After given either in seconds or as an HTTP date.
+fn retry_after(error: &Error) -> Option<Duration> {
+    let Error::HttpErrorCode(_, _, headers) = error else {
+        return None;
+    };
+    let value = headers.get(header::RETRY_AFTER)?.to_str().ok()?.trim();
+    match value.parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => httpdate::parse_http_date(value)
+            .ok()?
+            .duration_since(SystemTime::now())
+            .ok(),
     }
 }
 
@@ -99,105 +428,1108 @@ pub struct ProgressPayload {
     progress: u64,
     total: u64,
     transfer_speed: u64,
+    elapsed_ms: u64,
+    // Unknown while the total size or the speed is unknown.
+    eta_ms: Option<u64>,
+    attempt: u32,
+    // Set on the event sent once all bytes were transferred.
+    #[serde(skip)]
+    complete: bool,
+}
+
+impl ProgressPayload {
+    pub(crate) fn new(progress: u64, total: u64, stats: &TransferStats, attempt: u32) -> Self {
+        let eta = (total > 0)
+            .then(|| stats.eta(total.saturating_sub(progress)))
+            .flatten();
+        Self {
+            progress,
+            total,
+            transfer_speed: stats.transfer_speed,
+            elapsed_ms: stats.elapsed().as_millis() as u64,
+            eta_ms: eta.map(|eta| eta.as_millis() as u64),
+            attempt,
+            complete: false,
+        }
+    }
+
+    // The final event of a transfer, also of one whose size was unknown or zero.
+    pub(crate) fn completed(progress: u64, stats: &TransferStats, attempt: u32) -> Self {
+        Self {
+            complete: true,
+            ..Self::new(progress, progress, stats, attempt)
+        }
+    }
+}
+
+// Limits how often progress is reported, the event that completes a transfer is always sent.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ProgressThrottle {
+    // Minimum time between two events.
+    pub interval_ms: u64,
+    // Minimum change of the progress between two events, in percent of the total size.
+    pub min_percent: f64,
+}
+
+impl Default for ProgressThrottle {
+    fn default() -> Self {
+        Self {
+            interval_ms: 100,
+            min_percent: 0.0,
+        }
+    }
+}
+
+impl ProgressThrottle {
+    // Drops the events that arrive too soon or change the progress too little.
+    pub(crate) fn wrap(self, on_progress: OnProgress) -> OnProgress {
+        let last_sent: Mutex<Option<(Instant, ProgressPayload)>> = Mutex::new(None);
+        Arc::new(move |payload| {
+            let mut last_sent = last_sent.lock().unwrap();
+            if self.allows(last_sent.as_ref(), &payload) {
+                *last_sent = Some((Instant::now(), payload.clone()));
+                drop(last_sent);
+                on_progress(payload);
+            }
+        })
+    }
+
+    fn allows(
+        &self,
+        last_sent: Option<&(Instant, ProgressPayload)>,
+        payload: &ProgressPayload,
+    ) -> bool {
+        let Some((sent_at, last)) = last_sent else {
+            return true;
+        };
+        if payload.complete || (payload.total > 0 && payload.progress >= payload.total) {
+            // Send the completion once, unless a new attempt completes again.
+            return last.progress != payload.progress
+                || last.total != payload.total
+                || last.attempt != payload.attempt;
+        }
+        if sent_at.elapsed() < Duration::from_millis(self.interval_ms) {
+            return false;
+        }
+        // The percentage cannot be computed while the total size is unknown.
+        payload.total == 0
+            || payload.progress.abs_diff(last.progress) as f64 * 100.0 / payload.total as f64
+                >= self.min_percent
+    }
+}
+
+// Validators of a downloaded representation, used to check that a resumed download
+// continues the same file.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ResumeInfo {
+    url: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl ResumeInfo {
+    pub(crate) fn from_response(url: &str, response: &reqwest::Response) -> Self {
+        Self {
+            url: url.to_string(),
+            etag: header_value(response, header::ETAG),
+            last_modified: header_value(response, header::LAST_MODIFIED),
+        }
+    }
+
+    // Weak ETags cannot be used in If-Range, fall back to Last-Modified for them.
+    pub(crate) fn if_range(&self) -> Option<&str> {
+        self.etag
+            .as_deref()
+            .filter(|etag| !etag.starts_with("W/"))
+            .or(self.last_modified.as_deref())
+    }
+
+    // Checks that a 206 response still refers to the same representation.
+    pub(crate) fn matches(&self, response: &reqwest::Response) -> bool {
+        let etag = header_value(response, header::ETAG);
+        let last_modified = header_value(response, header::LAST_MODIFIED);
+        let same_etag = match (&self.etag, &etag) {
+            (Some(stored), Some(current)) => stored == current,
+            _ => true,
+        };
+        let same_last_modified = match (&self.last_modified, &last_modified) {
+            (Some(stored), Some(current)) => stored == current,
+            _ => true,
+        };
+        same_etag && same_last_modified
+    }
+}
+
+// A partial download left behind by an interrupted connection, recorded next to the
+// destination so that a later download of the same URL asks for the remaining bytes only.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PartialDownload {
+    // The file name of the temporary file, in the directory of the destination.
+    part: String,
+    #[serde(flatten)]
+    validators: ResumeInfo,
+}
+
+impl PartialDownload {
+    fn path(file_path: &str) -> String {
+        format!("{file_path}.resume")
+    }
+
+    fn part_path(&self, file_path: &str) -> PathBuf {
+        Path::new(file_path).with_file_name(&self.part)
+    }
+
+    async fn load(file_path: &str) -> Option<Self> {
+        let content = tokio::fs::read(Self::path(file_path)).await.ok()?;
+        serde_json::from_slice(&content).ok()
+    }
+
+    // Takes over the partial file of a previous download of `url` by moving it to
+    // `temp_path`. Only one of several concurrent downloads can win the rename.
+    async fn claim(file_path: &str, url: &str, temp_path: &str) -> Option<ResumeInfo> {
+        let partial = Self::load(file_path)
+            .await
+            .filter(|partial| partial.validators.url == url)?;
+        tokio::fs::rename(partial.part_path(file_path), temp_path)
+            .await
+            .ok()?;
+        let _ = tokio::fs::remove_file(Self::path(file_path)).await;
+        Some(partial.validators)
+    }
+
+    // Records the partial file, replacing the one of an older interrupted download.
+    async fn save(&self, file_path: &str) -> Result<()> {
+        if let Some(previous) = Self::load(file_path).await {
+            if previous.part != self.part {
+                let _ = tokio::fs::remove_file(previous.part_path(file_path)).await;
+            }
+        }
+        let content = serde_json::to_vec(self).map_err(std::io::Error::from)?;
+        tokio::fs::write(Self::path(file_path), content).await?;
+        Ok(())
+    }
+}
+
+fn header_value(response: &reqwest::Response, name: header::HeaderName) -> Option<String> {
+    response
+        .headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+// Parses the first byte position of a `Content-Range: bytes <start>-<end>/<size>` header.
+pub(crate) fn content_range_start(response: &reqwest::Response) -> Option<u64> {
+    let value = header_value(response, header::CONTENT_RANGE)?;
+    let range = value.strip_prefix("bytes ")?;
+    let (start, _) = range.split_once('-')?;
+    start.trim().parse().ok()
+}
+
+pub(crate) fn build_download_request(
+    client: &reqwest::Client,
+    url: &str,
+    headers: &HashMap<String, String>,
+    body: Option<&String>,
+) -> reqwest::RequestBuilder {
+    let mut request = if let Some(body) = body {
+        client.post(url).body(body.clone())
+    } else {
+        client.get(url)
+    };
+    // Loop trought the headers keys and values
+    // and add them to the request object.
+    for (key, value) in headers {
+        request = request.header(key, value);
+    }
+    request
+}
+
+// Each download writes to its own temporary file next to the destination, so concurrent
+// downloads to the same path never write into the same file.
+fn temp_file_path(file_path: &str) -> String {
+    let suffix: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(8)
+        .map(char::from)
+        .collect();
+    format!("{file_path}.{suffix}.part")
+}
+
+// Receives the progress of a transfer, sent to a command's channel or emitted by the queue.
+pub(crate) type OnProgress = Arc<dyn Fn(ProgressPayload) + Send + Sync>;
+
+pub(crate) fn channel_progress(
+    channel: Channel<ProgressPayload>,
+    throttle: Option<ProgressThrottle>,
+) -> OnProgress {
+    throttle.unwrap_or_default().wrap(Arc::new(move |payload| {
+        let _ = channel.send(payload);
+    }))
+}
+
+// The parameters of a download, shared by all of its attempts.
+pub(crate) struct DownloadParams<'a> {
+    pub(crate) client: reqwest::Client,
+    pub(crate) bandwidth: Arc<TokenBucket>,
+    pub(crate) url: &'a str,
+    pub(crate) file_path: &'a str,
+    pub(crate) headers: HashMap<String, String>,
+    pub(crate) body: Option<String>,
+    pub(crate) checksum: Option<Checksum>,
 }
 
 #[command]
+#[allow(clippy::too_many_arguments)]
 pub async fn download_file(
+    id: u32,
     url: &str,
     file_path: &str,
     headers: HashMap<String, String>,
     body: Option<String>,
+    checksum: Option<Checksum>,
+    retry: Option<RetryPolicy>,
+    progress_throttle: Option<ProgressThrottle>,
     on_progress: Channel<ProgressPayload>,
+    transfers: State<'_, TransferState>,
+    http_client: State<'_, HttpClientState>,
+    bandwidth: State<'_, BandwidthLimiter>,
 ) -> Result<()> {
-    let client = reqwest::Client::new();
-    let mut request = if let Some(body) = body {
-        client.post(url).body(body)
-    } else {
-        client.get(url)
+    let transfer = transfers.register(id)?;
+    let params = DownloadParams {
+        client: http_client.client(),
+        bandwidth: bandwidth.download.clone(),
+        url,
+        file_path,
+        headers,
+        body,
+        checksum,
     };
-    // Loop trought the headers keys and values
-    // and add them to the request object.
-    for (key, value) in headers {
-        request = request.header(&key, value);
+    run_download(
+        &transfer,
+        &params,
+        &retry.unwrap_or_default(),
+        &channel_progress(on_progress, progress_throttle),
+    )
+    .await
+}
+
+pub(crate) async fn run_download(
+    transfer: &Transfer<'_>,
+    params: &DownloadParams<'_>,
+    retry: &RetryPolicy,
+    on_progress: &OnProgress,
+) -> Result<()> {
+    let file_path = params.file_path;
+    let temp_path = temp_file_path(file_path);
+    // The validators of the bytes in the temporary file, each attempt resumes from them.
+    let resume_info = Mutex::new(PartialDownload::claim(file_path, params.url, &temp_path).await);
+    let result = match transfer
+        .run(retry.run(|attempt| {
+            download_to_temp_file(params, &temp_path, &resume_info, attempt, on_progress)
+        }))
+        .await
+    {
+        Ok(completed) => match tokio::fs::rename(&temp_path, file_path).await {
+            Ok(()) => {
+                on_progress(completed);
+                Ok(())
+            }
+            Err(e) => Err(e.into()),
+        },
+        Err(e) => Err(e),
+    };
+
+    // An interrupted connection leaves valid bytes behind. They are kept on purpose so that
+    // the next download of the same URL resumes, the temporary file is removed on any other
+    // error.
+    let keep_partial = match (&result, resume_info.into_inner().unwrap()) {
+        (Err(Error::Request(_)), Some(validators)) => {
+            let part = Path::new(&temp_path).file_name().unwrap_or_default();
+            PartialDownload {
+                part: part.to_string_lossy().into_owned(),
+                validators,
+            }
+            .save(file_path)
+            .await
+            .is_ok()
+        }
+        _ => false,
+    };
+    if result.is_err() && !keep_partial {
+        let _ = tokio::fs::remove_file(&temp_path).await;
     }
+    result
+}
+
+async fn download_to_temp_file(
+    params: &DownloadParams<'_>,
+    temp_path: &str,
+    resume_info: &Mutex<Option<ResumeInfo>>,
+    attempt: u32,
+    on_progress: &OnProgress,
+) -> Result<ProgressPayload> {
+    let &DownloadParams {
+        ref client,
+        ref bandwidth,
+        url,
+        ref headers,
+        ref body,
+        ref checksum,
+        ..
+    } = params;
+
+    let validators = resume_info.lock().unwrap().clone();
+    let mut offset = match &validators {
+        Some(_) => tokio::fs::metadata(temp_path)
+            .await
+            .map(|m| m.len())
+            .unwrap_or(0),
+        None => 0,
+    };
+
+    let response = loop {
+        let mut request = build_download_request(client, url, headers, body.as_ref());
+        if offset > 0 {
+            request = request.header(header::RANGE, format!("bytes={offset}-"));
+            if let Some(validator) = validators.as_ref().and_then(ResumeInfo::if_range) {
+                request = request.header(header::IF_RANGE, validator);
+            }
+        }
+
+        let response = request.send().await?;
+        if offset == 0 {
+            break response;
+        }
+        match response.status() {
+            StatusCode::PARTIAL_CONTENT
+                if content_range_start(&response) == Some(offset)
+                    && validators
+                        .as_ref()
+                        .is_some_and(|info| info.matches(&response)) =>
+            {
+                break response;
+            }
+            // The server ignored the range or the representation changed, start over.
+            StatusCode::OK => {
+                offset = 0;
+                break response;
+            }
+            _ => offset = 0,
+        }
+    };
 
-    let response = request.send().await?;
     if !response.status().is_success() {
-        return Err(Error::HttpErrorCode(
-            response.status().as_u16(),
-            response.text().await.unwrap_or_default(),
-        ));
+        return Err(Error::from_response(response).await);
+    }
+    let total = response
+        .content_length()
+        .map(|len| len + offset)
+        .unwrap_or(0);
+
+    let file = if offset > 0 {
+        OpenOptions::new().append(true).open(temp_path).await?
+    } else {
+        *resume_info.lock().unwrap() = Some(ResumeInfo::from_response(url, &response));
+        File::create(temp_path).await?
+    };
+    let mut hasher = checksum.as_ref().map(Checksum::hasher);
+    if let Some(hasher) = hasher.as_mut().filter(|_| offset > 0) {
+        // The resumed bytes must be part of the digest as well.
+        let mut partial = FramedRead::new(File::open(temp_path).await?, BytesCodec::new());
+        while let Some(chunk) = partial.try_next().await? {
+            hasher.update(&chunk);
+        }
     }
-    let total = response.content_length().unwrap_or(0);
 
-    let mut file = BufWriter::new(File::create(file_path).await?);
+    let mut file = BufWriter::new(file);
     let mut stream = response.bytes_stream();
 
     let mut stats = TransferStats::default();
-    while let Some(chunk) = stream.try_next().await? {
-        file.write_all(&chunk).await?;
-        stats.record_chunk_transfer(chunk.len());
-        let _ = on_progress.send(ProgressPayload {
-            progress: stats.total_transferred,
-            total,
-            transfer_speed: stats.transfer_speed,
-        });
+    let streamed: Result<()> = async {
+        while let Some(chunk) = stream.try_next().await? {
+            bandwidth.acquire(chunk.len()).await;
+            file.write_all(&chunk).await?;
+            if let Some(hasher) = hasher.as_mut() {
+                hasher.update(&chunk);
+            }
+            stats.record_chunk_transfer(chunk.len());
+            // 100% is reported once the file is verified and in place.
+            let progress = offset + stats.total_transferred;
+            if total == 0 || progress < total {
+                on_progress(ProgressPayload::new(progress, total, &stats, attempt));
+            }
+        }
+        Ok(())
     }
+    .await;
+    // The bytes received before an interrupted connection are kept to resume from.
     file.flush().await?;
+    streamed?;
+    // Make sure the data is on disk before the temporary file replaces the destination.
+    file.into_inner().sync_all().await?;
+
+    if let (Some(checksum), Some(hasher)) = (checksum, hasher) {
+        verify_checksum(checksum, hasher)?;
+    }
+
+    // Without a Content-Length only the end of the stream tells that the download is complete.
+    Ok(ProgressPayload::completed(
+        offset + stats.total_transferred,
+        &stats,
+        attempt,
+    ))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum UploadMethod {
+    Put,
+    Post,
+    Patch,
+}
+
+impl From<UploadMethod> for reqwest::Method {
+    fn from(method: UploadMethod) -> Self {
+        match method {
+            UploadMethod::Put => reqwest::Method::PUT,
+            UploadMethod::Post => reqwest::Method::POST,
+            UploadMethod::Patch => reqwest::Method::PATCH,
+        }
+    }
+}
+
+// Sends the file as a field of a multipart/form-data body instead of the raw body.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FormUpload {
+    // Name of the form field holding the file.
+    pub field_name: String,
+    // Defaults to the name of the uploaded file.
+    pub file_name: Option<String>,
+    pub content_type: Option<String>,
+    // Text fields sent along with the file.
+    #[serde(default)]
+    pub fields: HashMap<String, String>,
+}
 
-    Ok(())
+// The parameters of an upload, shared by all of its attempts.
+pub(crate) struct UploadParams<'a> {
+    pub(crate) client: reqwest::Client,
+    pub(crate) bandwidth: Arc<TokenBucket>,
+    pub(crate) url: &'a str,
+    pub(crate) file_path: &'a str,
+    pub(crate) method: UploadMethod,
+    pub(crate) headers: HashMap<String, String>,
+    pub(crate) form: Option<FormUpload>,
+    pub(crate) checksum: Option<Checksum>,
 }
 
 #[command]
+#[allow(clippy::too_many_arguments)]
 pub async fn upload_file(
+    id: u32,
     url: &str,
     file_path: &str,
-    method: &str,
+    method: UploadMethod,
     headers: HashMap<String, String>,
+    form: Option<FormUpload>,
+    checksum: Option<Checksum>,
+    retry: Option<RetryPolicy>,
+    progress_throttle: Option<ProgressThrottle>,
     on_progress: Channel<ProgressPayload>,
+    transfers: State<'_, TransferState>,
+    http_client: State<'_, HttpClientState>,
+    bandwidth: State<'_, BandwidthLimiter>,
+) -> Result<String> {
+    let transfer = transfers.register(id)?;
+    let params = UploadParams {
+        client: http_client.client(),
+        bandwidth: bandwidth.upload.clone(),
+        url,
+        file_path,
+        method,
+        headers,
+        form,
+        checksum,
+    };
+    run_upload(
+        &transfer,
+        &params,
+        &retry.unwrap_or_default(),
+        &channel_progress(on_progress, progress_throttle),
+    )
+    .await
+}
+
+pub(crate) async fn run_upload(
+    transfer: &Transfer<'_>,
+    params: &UploadParams<'_>,
+    retry: &RetryPolicy,
+    on_progress: &OnProgress,
 ) -> Result<String> {
+    transfer
+        .run(retry.run(|attempt| upload(params, attempt, on_progress)))
+        .await
+}
+
+// Aborts a running download or upload, partial downloads are removed.
+#[command]
+pub fn cancel_transfer(id: u32, transfers: State<'_, TransferState>) -> bool {
+    transfers.cancel(id)
+}
+
+async fn upload(
+    params: &UploadParams<'_>,
+    attempt: u32,
+    on_progress: &OnProgress,
+) -> Result<String> {
+    let &UploadParams {
+        ref client,
+        ref bandwidth,
+        url,
+        file_path,
+        method,
+        ref headers,
+        ref form,
+        ref checksum,
+    } = params;
     let file = File::open(file_path).await?;
-    let file_len = file.metadata().await.unwrap().len();
+    let file_len = file.metadata().await?.len();
 
-    let client = reqwest::Client::new();
-    let mut request = match method.to_uppercase().as_str() {
-        "POST" => client.post(url),
-        "PUT" => client.put(url),
-        _ => return Err(Error::ContentLength("Invalid HTTP method".into())),
-    };
+    // The digest is computed from the bytes actually read while streaming the body.
+    let hasher = checksum
+        .as_ref()
+        .map(|checksum| Arc::new(Mutex::new(checksum.hasher())));
+    let stats = Arc::new(Mutex::new(TransferStats::default()));
+    let body = file_to_body(
+        on_progress.clone(),
+        file,
+        file_len,
+        attempt,
+        hasher.clone(),
+        bandwidth.clone(),
+        stats.clone(),
+    );
 
-    request = request
-        .header(reqwest::header::CONTENT_LENGTH, file_len)
-        .body(file_to_body(on_progress.clone(), file, file_len));
+    let mut request = client.request(method.into(), url);
+    request = match form {
+        Some(form) => request.multipart(file_to_form(form, file_path, body, file_len)?),
+        None => request
+            .header(reqwest::header::CONTENT_LENGTH, file_len)
+            .body(body),
+    };
 
     for (key, value) in headers {
-        request = request.header(&key, value);
+        request = request.header(key, value);
     }
 
     let response = request.send().await?;
     if response.status().is_success() {
+        if let (Some(checksum), Some(hasher)) = (checksum, hasher) {
+            let hasher = hasher.lock().unwrap().clone();
+            verify_checksum(checksum, hasher)?;
+        }
+        // The body stream reports nothing for an empty file.
+        on_progress(ProgressPayload::completed(
+            file_len,
+            &stats.lock().unwrap(),
+            attempt,
+        ));
         response.text().await.map_err(Into::into)
     } else {
-        Err(Error::HttpErrorCode(
-            response.status().as_u16(),
-            response.text().await.unwrap_or_default(),
-        ))
+        Err(Error::from_response(response).await)
     }
 }
 
-fn file_to_body(channel: Channel<ProgressPayload>, file: File, file_len: u64) -> reqwest::Body {
-    let stream = FramedRead::new(file, BytesCodec::new()).map_ok(|r| r.freeze());
+fn file_to_form(
+    form: &FormUpload,
+    file_path: &str,
+    body: reqwest::Body,
+    file_len: u64,
+) -> Result<reqwest::multipart::Form> {
+    let file_name = form.file_name.clone().unwrap_or_else(|| {
+        std::path::Path::new(file_path)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    });
+    let mut part =
+        reqwest::multipart::Part::stream_with_length(body, file_len).file_name(file_name);
+    if let Some(content_type) = &form.content_type {
+        part = part
+            .mime_str(content_type)
+            .map_err(|_| Error::InvalidArgument(format!("invalid content type {content_type}")))?;
+    }
+
+    let mut multipart = reqwest::multipart::Form::new();
+    for (name, value) in &form.fields {
+        multipart = multipart.text(name.clone(), value.clone());
+    }
+    Ok(multipart.part(form.field_name.clone(), part))
+}
+
+fn file_to_body(
+    on_progress: OnProgress,
+    file: File,
+    file_len: u64,
+    attempt: u32,
+    hasher: Option<Arc<Mutex<ChecksumHasher>>>,
+    bandwidth: Arc<TokenBucket>,
+    stats: Arc<Mutex<TransferStats>>,
+) -> reqwest::Body {
+    let stream = FramedRead::new(file, BytesCodec::new())
+        .map_ok(move |r| {
+            if let Some(hasher) = &hasher {
+                hasher.lock().unwrap().update(&r);
+            }
+            r.freeze()
+        })
+        .and_then(move |chunk| {
+            let bandwidth = bandwidth.clone();
+            async move {
+                bandwidth.acquire(chunk.len()).await;
+                Ok(chunk)
+            }
+        });
 
-    let mut stats = TransferStats::default();
     reqwest::Body::wrap_stream(ReadProgressStream::new(
-        stream,
+        Box::pin(stream),
         Box::new(move |progress_chunk, _progress_total| {
+            let mut stats = stats.lock().unwrap();
             stats.record_chunk_transfer(progress_chunk as usize);
-            let _ = channel.send(ProgressPayload {
-                progress: stats.total_transferred,
-                total: file_len,
-                transfer_speed: stats.transfer_speed,
-            });
+            on_progress(ProgressPayload::new(
+                stats.total_transferred,
+                file_len,
+                &stats,
+                attempt,
+            ));
         }),
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::checksum::ChecksumAlgorithm;
+
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    // Records `chunk_len` bytes `ms` milliseconds after the transfer started.
+    fn record(stats: &mut TransferStats, ms: u64, chunk_len: usize) {
+        let now = stats.start_time + Duration::from_millis(ms);
+        stats.record_chunk_transfer_at(chunk_len, now);
+    }
+
+    #[test]
+    fn reports_the_average_speed_until_the_first_period_completes() {
+        let mut stats = TransferStats::start(500);
+        record(&mut stats, 100, 1000);
+        assert_eq!(stats.transfer_speed, 10_000);
+        record(&mut stats, 400, 1000);
+        assert_eq!(stats.transfer_speed, 5_000);
+        assert_eq!(stats.total_transferred, 2000);
+    }
+
+    #[test]
+    fn keeps_the_speed_of_a_steady_slow_transfer() {
+        let mut stats = TransferStats::start(500);
+        for ms in (100..=3000).step_by(100) {
+            record(&mut stats, ms, 100);
+        }
+        assert_eq!(stats.transfer_speed, 1_000);
+        assert_eq!(stats.total_transferred, 3000);
+        assert_eq!(stats.eta(2500), Some(Duration::from_millis(2500)));
+    }
+
+    #[test]
+    fn smooths_bursts() {
+        let mut stats = TransferStats::start(500);
+        record(&mut stats, 500, 5000);
+        assert_eq!(stats.transfer_speed, 10_000);
+
+        // A chunk within the current period leaves the speed alone.
+        record(&mut stats, 700, 50_000);
+        assert_eq!(stats.transfer_speed, 10_000);
+
+        // 0.3 * 100 000 + 0.7 * 10 000
+        record(&mut stats, 1000, 0);
+        assert_eq!(stats.transfer_speed, 37_000);
+
+        // An idle period decays the speed instead of dropping it to zero.
+        record(&mut stats, 1500, 0);
+        assert_eq!(stats.transfer_speed, 25_900);
+        assert_eq!(stats.eta(25_900), Some(Duration::from_secs(1)));
+        assert_eq!(stats.total_transferred, 55_000);
+    }
+
+    #[test]
+    fn handles_zero_length_transfers() {
+        let mut stats = TransferStats::start(500);
+        record(&mut stats, 0, 0);
+        assert_eq!(stats.transfer_speed, 0);
+        record(&mut stats, 600, 0);
+        assert_eq!(stats.transfer_speed, 0);
+        assert_eq!(stats.total_transferred, 0);
+        assert_eq!(stats.eta(0), Some(Duration::ZERO));
+        assert_eq!(stats.eta(1000), None);
+    }
+
+    #[test]
+    fn handles_chunks_recorded_at_the_start() {
+        let mut stats = TransferStats::start(500);
+        record(&mut stats, 0, 4096);
+        assert_eq!(stats.transfer_speed, 0);
+        assert_eq!(stats.eta(4096), None);
+        record(&mut stats, 500, 1024);
+        assert_eq!(stats.transfer_speed, 10_240);
+        assert_eq!(stats.eta(10_240), Some(Duration::from_secs(1)));
+    }
+
+    fn payload(progress: u64, total: u64, attempt: u32) -> ProgressPayload {
+        ProgressPayload::new(progress, total, &TransferStats::default(), attempt)
+    }
+
+    fn completed(progress: u64, attempt: u32) -> ProgressPayload {
+        ProgressPayload::completed(progress, &TransferStats::default(), attempt)
+    }
+
+    // Passes the payloads through a throttle that lets nothing through by time alone.
+    fn throttled(payloads: Vec<ProgressPayload>) -> Vec<(u64, u64, u32)> {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let on_progress: OnProgress = {
+            let sent = sent.clone();
+            Arc::new(move |payload: ProgressPayload| {
+                sent.lock()
+                    .unwrap()
+                    .push((payload.progress, payload.total, payload.attempt))
+            })
+        };
+        let throttle = ProgressThrottle {
+            interval_ms: 3_600_000,
+            min_percent: 0.0,
+        };
+        let on_progress = throttle.wrap(on_progress);
+        for payload in payloads {
+            on_progress(payload);
+        }
+        let sent = sent.lock().unwrap().clone();
+        sent
+    }
+
+    #[test]
+    fn throttle_sends_the_completion_of_a_known_length_once() {
+        let sent = throttled(vec![
+            payload(10, 100, 1),
+            payload(50, 100, 1),
+            payload(100, 100, 1),
+            completed(100, 1),
+        ]);
+        assert_eq!(sent, vec![(10, 100, 1), (100, 100, 1)]);
+    }
+
+    #[test]
+    fn throttle_sends_the_completion_of_an_unknown_length() {
+        let sent = throttled(vec![payload(10, 0, 1), payload(50, 0, 1), completed(50, 1)]);
+        assert_eq!(sent, vec![(10, 0, 1), (50, 50, 1)]);
+
+        // The last chunk was sent, the completion still tells the size.
+        let sent = throttled(vec![payload(10, 0, 1), completed(10, 1)]);
+        assert_eq!(sent, vec![(10, 0, 1), (10, 10, 1)]);
+    }
+
+    #[test]
+    fn throttle_sends_the_completion_of_an_empty_transfer() {
+        assert_eq!(throttled(vec![completed(0, 1)]), vec![(0, 0, 1)]);
+        assert_eq!(
+            throttled(vec![payload(0, 0, 1), completed(0, 1)]),
+            vec![(0, 0, 1)]
+        );
+    }
+
+    #[test]
+    fn throttle_sends_the_completion_of_each_attempt() {
+        let sent = throttled(vec![
+            payload(100, 100, 1),
+            payload(10, 100, 2),
+            payload(100, 100, 2),
+        ]);
+        assert_eq!(sent, vec![(100, 100, 1), (100, 100, 2)]);
+    }
+
+    const BODY: &[u8] = b"0123456789";
+
+    // Answers each connection with `handler`, which gets the number and the head of the
+    // request, then closes it. Returns the server's URL and the heads of the requests.
+    fn serve(
+        handler: impl Fn(usize, &str, &mut TcpStream) + Send + 'static,
+    ) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/book.epub", listener.local_addr().unwrap());
+        let heads = Arc::new(Mutex::new(Vec::new()));
+        let received = heads.clone();
+        std::thread::spawn(move || {
+            for (number, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                let mut head = String::new();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                while reader.read_line(&mut head).unwrap() > 2 {}
+                let head = head.to_lowercase();
+                received.lock().unwrap().push(head.clone());
+                handler(number, &head, &mut stream);
+            }
+        });
+        (url, heads)
+    }
+
+    // A response that closes the connection, `body` may be shorter than `len` to drop it early.
+    fn respond(stream: &mut TcpStream, status: &str, headers: &[&str], len: usize, body: &[u8]) {
+        let mut response =
+            format!("HTTP/1.1 {status}\r\nContent-Length: {len}\r\nConnection: close\r\n");
+        for header in headers {
+            response.push_str(&format!("{header}\r\n"));
+        }
+        response.push_str("\r\n");
+        let _ = stream.write_all(response.as_bytes());
+        let _ = stream.write_all(body);
+    }
+
+    fn retry(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            base_delay_ms: 1,
+            jitter: 0.0,
+            ..Default::default()
+        }
+    }
+
+    // Downloads `url` to `book.epub` in `dir`, cancelling it after `cancel_after` if given.
+    async fn download(
+        url: &str,
+        dir: &Path,
+        retry: RetryPolicy,
+        checksum: Option<Checksum>,
+        cancel_after: Option<Duration>,
+    ) -> (Result<()>, Vec<ProgressPayload>) {
+        let file_path = dir.join("book.epub");
+        let state = TransferState::default();
+        let transfer = state.register(1).unwrap();
+        let params = DownloadParams {
+            client: reqwest::Client::new(),
+            bandwidth: Arc::default(),
+            url,
+            file_path: file_path.to_str().unwrap(),
+            headers: HashMap::new(),
+            body: None,
+            checksum,
+        };
+        let payloads = Arc::new(Mutex::new(Vec::new()));
+        let on_progress: OnProgress = {
+            let payloads = payloads.clone();
+            Arc::new(move |payload| payloads.lock().unwrap().push(payload))
+        };
+        let cancel = async {
+            if let Some(delay) = cancel_after {
+                tokio::time::sleep(delay).await;
+                state.cancel(1);
+            }
+        };
+        let (result, _) = tokio::join!(
+            run_download(&transfer, &params, &retry, &on_progress),
+            cancel
+        );
+        let payloads = payloads.lock().unwrap().clone();
+        (result, payloads)
+    }
+
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    // The first request drops the connection after 4 bytes, the next are answered by `handler`.
+    fn serve_interrupted(
+        handler: impl Fn(&str, &mut TcpStream) + Send + 'static,
+    ) -> (String, Arc<Mutex<Vec<String>>>) {
+        serve(move |number, head, stream| match number {
+            0 => respond(stream, "200 OK", &["ETag: \"v1\""], BODY.len(), &BODY[..4]),
+            _ => handler(head, stream),
+        })
+    }
+
+    #[tokio::test]
+    async fn resumes_an_interrupted_download_with_a_range_request() {
+        let dir = tempfile::tempdir().unwrap();
+        let (url, heads) = serve_interrupted(|head, stream| {
+            if head.contains("range: bytes=4-") && head.contains("if-range: \"v1\"") {
+                let headers = ["ETag: \"v1\"", "Content-Range: bytes 4-9/10"];
+                respond(stream, "206 Partial Content", &headers, 6, &BODY[4..]);
+            } else {
+                respond(stream, "200 OK", &["ETag: \"v1\""], BODY.len(), BODY);
+            }
+        });
+
+        let (result, _) = download(&url, dir.path(), retry(1), None, None).await;
+        assert!(matches!(result, Err(Error::Request(_))));
+        // The partial file and its validators are kept for the next download.
+        let names = file_names(dir.path());
+        assert_eq!(names.len(), 2);
+        assert!(names[0].starts_with("book.epub.") && names[0].ends_with(".part"));
+        assert_eq!(names[1], "book.epub.resume");
+
+        let (result, payloads) = download(&url, dir.path(), retry(1), None, None).await;
+        result.unwrap();
+        assert!(heads.lock().unwrap()[1].contains("range: bytes=4-"));
+        assert_eq!(std::fs::read(dir.path().join("book.epub")).unwrap(), BODY);
+        assert_eq!(file_names(dir.path()), ["book.epub"]);
+        let last = payloads.last().unwrap();
+        assert_eq!((last.progress, last.total), (10, 10));
+    }
+
+    #[tokio::test]
+    async fn restarts_the_download_when_the_server_ignores_the_range() {
+        let dir = tempfile::tempdir().unwrap();
+        let (url, heads) = serve_interrupted(|_, stream| {
+            respond(stream, "200 OK", &["ETag: \"v2\""], BODY.len(), BODY);
+        });
+
+        let (result, _) = download(&url, dir.path(), retry(1), None, None).await;
+        assert!(result.is_err());
+        download(&url, dir.path(), retry(1), None, None)
+            .await
+            .0
+            .unwrap();
+        assert!(heads.lock().unwrap()[1].contains("range: bytes=4-"));
+        assert_eq!(std::fs::read(dir.path().join("book.epub")).unwrap(), BODY);
+        assert_eq!(file_names(dir.path()), ["book.epub"]);
+    }
+
+    #[tokio::test]
+    async fn keeps_the_destination_and_removes_the_temporary_file_on_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("book.epub"), "old").unwrap();
+        let (url, _) = serve(|_, _, stream| respond(stream, "404 Not Found", &[], 0, b""));
+
+        let (result, _) = download(&url, dir.path(), retry(1), None, None).await;
+        assert!(matches!(result, Err(Error::HttpErrorCode(404, ..))));
+        assert_eq!(file_names(dir.path()), ["book.epub"]);
+        assert_eq!(std::fs::read(dir.path().join("book.epub")).unwrap(), b"old");
+    }
+
+    #[tokio::test]
+    async fn removes_the_partial_file_and_its_validators_when_cancelled() {
+        let dir = tempfile::tempdir().unwrap();
+        let (url, _) = serve_interrupted(|_, stream| {
+            let headers = ["ETag: \"v1\"", "Content-Range: bytes 4-9/10"];
+            respond(stream, "206 Partial Content", &headers, 6, &BODY[4..6]);
+            std::thread::sleep(Duration::from_secs(2));
+        });
+        let (result, _) = download(&url, dir.path(), retry(1), None, None).await;
+        assert!(result.is_err());
+        assert_eq!(file_names(dir.path()).len(), 2);
+
+        let cancel_after = Some(Duration::from_millis(200));
+        let (result, _) = download(&url, dir.path(), retry(1), None, cancel_after).await;
+        assert!(matches!(result, Err(Error::Cancelled)));
+        assert!(file_names(dir.path()).is_empty());
+    }
+
+    fn sha256(data: &[u8]) -> Checksum {
+        let mut checksum = Checksum {
+            algorithm: ChecksumAlgorithm::Sha256,
+            value: String::new(),
+        };
+        let mut hasher = checksum.hasher();
+        hasher.update(data);
+        checksum.value = hasher.finalize();
+        checksum
+    }
+
+    #[tokio::test]
+    async fn reports_completion_once_the_checksum_is_verified() {
+        let dir = tempfile::tempdir().unwrap();
+        let (url, _) = serve(|_, _, stream| respond(stream, "200 OK", &[], BODY.len(), BODY));
+
+        let checksum = Some(sha256(b"another book"));
+        let (result, payloads) = download(&url, dir.path(), retry(1), checksum, None).await;
+        assert!(matches!(result, Err(Error::ChecksumMismatch { .. })));
+        assert!(payloads.iter().all(|payload| payload.progress < 10));
+        assert!(file_names(dir.path()).is_empty());
+
+        let checksum = Some(sha256(BODY));
+        let (result, payloads) = download(&url, dir.path(), retry(1), checksum, None).await;
+        result.unwrap();
+        let last = payloads.last().unwrap();
+        assert!(last.complete);
+        assert_eq!((last.progress, last.total), (10, 10));
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors_and_dropped_connections() {
+        let dir = tempfile::tempdir().unwrap();
+        let (url, heads) = serve(|number, _, stream| match number {
+            0 => respond(stream, "503 Service Unavailable", &[], 0, b""),
+            // Closes the connection without a response.
+            1 => {}
+            _ => respond(stream, "200 OK", &[], BODY.len(), BODY),
+        });
+
+        let (result, payloads) = download(&url, dir.path(), retry(3), None, None).await;
+        result.unwrap();
+        assert_eq!(heads.lock().unwrap().len(), 3);
+        assert_eq!(payloads.last().unwrap().attempt, 3);
+        assert_eq!(std::fs::read(dir.path().join("book.epub")).unwrap(), BODY);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_the_last_attempt() {
+        let dir = tempfile::tempdir().unwrap();
+        let (url, heads) = serve(|_, _, stream| {
+            respond(stream, "502 Bad Gateway", &[], 0, b"");
+        });
+
+        let (result, _) = download(&url, dir.path(), retry(2), None, None).await;
+        assert!(matches!(result, Err(Error::HttpErrorCode(502, ..))));
+        assert_eq!(heads.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_client_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let (url, heads) = serve(|_, _, stream| respond(stream, "403 Forbidden", &[], 0, b""));
+
+        let (result, _) = download(&url, dir.path(), retry(3), None, None).await;
+        assert!(matches!(result, Err(Error::HttpErrorCode(403, ..))));
+        assert_eq!(heads.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn backs_off_exponentially_up_to_the_maximum() {
+        let policy = RetryPolicy {
+            base_delay_ms: 100,
+            max_delay_ms: 1000,
+            jitter: 0.0,
+            ..Default::default()
+        };
+        let error = Error::Cancelled;
+        let delays: Vec<_> = (1..=5)
+            .map(|attempt| policy.delay(attempt, &error))
+            .collect();
+        let expected = [100, 200, 400, 800, 1000].map(Duration::from_millis);
+        assert_eq!(delays, expected);
+
+        let mut headers = header::HeaderMap::new();
+        headers.insert(header::RETRY_AFTER, "1".parse().unwrap());
+        let error = Error::HttpErrorCode(429, String::new(), headers);
+        assert_eq!(policy.delay(1, &error), Duration::from_secs(1));
+    }
+}
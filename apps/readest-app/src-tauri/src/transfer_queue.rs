@@ -0,0 +1,568 @@
+//! Run downloads and uploads through a queue.
+//!
+//! Jobs wait until one of a limited number of slots is free, the job with the highest
+//! priority first. The queue is saved to disk so pending jobs are resumed when the app
+//! is started again, except jobs that carry credentials in their headers or URL, which
+//! only last until the app quits. Changes to the queue and the progress of its jobs are
+//! emitted as `transfer-queue` events.
+
+use serde::{Deserialize, Serialize};
+use tauri::{command, AppHandle, Emitter, Manager, State};
+use tokio_util::sync::CancellationToken;
+
+use crate::bandwidth::BandwidthLimiter;
+use crate::checksum::Checksum;
+use crate::http_client::HttpClientState;
+use crate::transfer_file::{
+    run_download, run_upload, DownloadParams, FormUpload, OnProgress, ProgressPayload,
+    ProgressThrottle, Result, RetryPolicy, TransferState, UploadMethod, UploadParams,
+};
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+const QUEUE_FILE: &str = "transfer-queue.json";
+const QUEUE_EVENT: &str = "transfer-queue";
+const DEFAULT_CONCURRENCY: usize = 3;
+
+// Jobs with one of these headers are not saved to disk.
+const SECRET_HEADERS: [&str; 3] = ["authorization", "proxy-authorization", "cookie"];
+// Nor are jobs whose URL has one of these query parameters, such as presigned URLs.
+const SECRET_QUERY_PARAMS: [&str; 8] = [
+    "x-amz-signature",
+    "x-amz-credential",
+    "x-amz-security-token",
+    "x-goog-signature",
+    "signature",
+    "sig",
+    "token",
+    "access_token",
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+pub enum TransferKind {
+    #[serde(rename_all = "camelCase")]
+    Download {
+        url: String,
+        file_path: String,
+        #[serde(default)]
+        headers: HashMap<String, String>,
+        body: Option<String>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Upload {
+        url: String,
+        file_path: String,
+        method: UploadMethod,
+        #[serde(default)]
+        headers: HashMap<String, String>,
+        form: Option<FormUpload>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TransferJob {
+    // Also the transfer id, so a running job can be cancelled with cancel_transfer.
+    pub id: u32,
+    // Jobs with a higher priority start first, jobs of equal priority in the order queued.
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(flatten)]
+    pub kind: TransferKind,
+    pub checksum: Option<Checksum>,
+    pub retry: Option<RetryPolicy>,
+    pub progress_throttle: Option<ProgressThrottle>,
+}
+
+impl TransferJob {
+    fn carries_secrets(&self) -> bool {
+        let (url, headers) = match &self.kind {
+            TransferKind::Download { url, headers, .. } => (url, headers),
+            TransferKind::Upload { url, headers, .. } => (url, headers),
+        };
+        let secret_header = headers
+            .keys()
+            .any(|name| SECRET_HEADERS.contains(&name.to_lowercase().as_str()));
+        let secret_query = reqwest::Url::parse(url).is_ok_and(|url| {
+            url.query_pairs()
+                .any(|(name, _)| SECRET_QUERY_PARAMS.contains(&name.to_lowercase().as_str()))
+        });
+        secret_header || secret_query
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum JobStatus {
+    Pending,
+    Running,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueuedJob {
+    #[serde(flatten)]
+    job: TransferJob,
+    status: JobStatus,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase", tag = "type")]
+enum QueueEvent {
+    // The jobs in the queue, sent whenever a job is added, started, finished or changed.
+    Changed {
+        jobs: Vec<QueuedJob>,
+    },
+    Progress {
+        id: u32,
+        #[serde(flatten)]
+        progress: ProgressPayload,
+    },
+    // `response` is the response body of an upload.
+    Finished {
+        id: u32,
+        response: Option<String>,
+        error: Option<serde_json::Value>,
+    },
+}
+
+struct Queue {
+    // In the order the jobs were queued.
+    jobs: Vec<QueuedJob>,
+    concurrency: usize,
+}
+
+impl Queue {
+    // The index of the pending job to start next, if a slot is free.
+    fn next_job(&self) -> Option<usize> {
+        let running = self
+            .jobs
+            .iter()
+            .filter(|queued| queued.status == JobStatus::Running)
+            .count();
+        if running >= self.concurrency {
+            return None;
+        }
+        // `min_by_key` returns the first of equal elements, which keeps the queue order.
+        self.jobs
+            .iter()
+            .enumerate()
+            .filter(|(_, queued)| queued.status == JobStatus::Pending)
+            .min_by_key(|(_, queued)| Reverse(queued.job.priority))
+            .map(|(index, _)| index)
+    }
+}
+
+pub struct TransferQueue {
+    queue: Mutex<Queue>,
+    path: Option<PathBuf>,
+}
+
+impl TransferQueue {
+    pub fn load(app: &AppHandle) -> Self {
+        let path = app
+            .path()
+            .app_data_dir()
+            .ok()
+            .map(|dir| dir.join(QUEUE_FILE));
+        Self::open(path)
+    }
+
+    fn open(path: Option<PathBuf>) -> Self {
+        let jobs: Vec<QueuedJob> = path
+            .as_ref()
+            .and_then(|path| std::fs::read(path).ok())
+            .and_then(|content| serde_json::from_slice(&content).ok())
+            .unwrap_or_default();
+        let saved = jobs.len();
+        // Jobs that were running when the app quit start over.
+        let jobs: Vec<QueuedJob> = jobs
+            .into_iter()
+            .filter(|queued| !queued.job.carries_secrets())
+            .map(|job| QueuedJob {
+                status: JobStatus::Pending,
+                ..job
+            })
+            .collect();
+        let queue = Self {
+            queue: Mutex::new(Queue {
+                jobs: Vec::new(),
+                concurrency: DEFAULT_CONCURRENCY,
+            }),
+            path,
+        };
+        // Rewrites a queue saved with the credentials of its jobs.
+        if jobs.len() < saved {
+            if let Err(e) = queue.save(&jobs) {
+                eprintln!("Failed to save the transfer queue: {}", e);
+            }
+        }
+        queue.queue.lock().unwrap().jobs = jobs;
+        queue
+    }
+
+    fn save(&self, jobs: &[QueuedJob]) -> std::io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let jobs: Vec<&QueuedJob> = jobs
+            .iter()
+            .filter(|queued| !queued.job.carries_secrets())
+            .collect();
+        std::fs::write(path, serde_json::to_vec(&jobs)?)
+    }
+
+    // Saves the queue and tells the frontend about the change.
+    fn changed(&self, app: &AppHandle, queue: &Queue) {
+        if let Err(e) = self.save(&queue.jobs) {
+            eprintln!("Failed to save the transfer queue: {}", e);
+        }
+        let _ = app.emit(
+            QUEUE_EVENT,
+            QueueEvent::Changed {
+                jobs: queue.jobs.clone(),
+            },
+        );
+    }
+
+    fn finish(&self, app: &AppHandle, id: u32) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.jobs.retain(|queued| queued.job.id != id);
+        self.changed(app, &queue);
+    }
+}
+
+// Starts pending jobs while fewer than the allowed number of jobs are running.
+pub fn schedule(app: &AppHandle) {
+    let state = app.state::<TransferQueue>();
+    let transfers = app.state::<TransferState>();
+    let mut queue = state.queue.lock().unwrap();
+    let mut changed = false;
+    while let Some(index) = queue.next_job() {
+        changed = true;
+        // Registered before it is marked as running, so remove_transfer can always cancel it.
+        match transfers.reserve(queue.jobs[index].job.id) {
+            Ok(token) => {
+                let queued = &mut queue.jobs[index];
+                queued.status = JobStatus::Running;
+                tauri::async_runtime::spawn(run_job(app.clone(), queued.job.clone(), token));
+            }
+            Err(e) => {
+                let queued = queue.jobs.remove(index);
+                emit_finished(app, queued.job.id, Err(e));
+            }
+        }
+    }
+    if changed {
+        state.changed(app, &queue);
+    }
+}
+
+async fn run_job(app: AppHandle, job: TransferJob, token: CancellationToken) {
+    let result = transfer(&app, &job, token).await;
+    app.state::<TransferQueue>().finish(&app, job.id);
+    emit_finished(&app, job.id, result);
+    schedule(&app);
+}
+
+fn emit_finished(app: &AppHandle, id: u32, result: Result<Option<String>>) {
+    let (response, error) = match result {
+        Ok(response) => (response, None),
+        Err(e) => (None, serde_json::to_value(&e).ok()),
+    };
+    let _ = app.emit(
+        QUEUE_EVENT,
+        QueueEvent::Finished {
+            id,
+            response,
+            error,
+        },
+    );
+}
+
+async fn transfer(
+    app: &AppHandle,
+    job: &TransferJob,
+    token: CancellationToken,
+) -> Result<Option<String>> {
+    let transfers = app.state::<TransferState>();
+    let bandwidth = app.state::<BandwidthLimiter>();
+    let client = app.state::<HttpClientState>().client();
+    let transfer = transfers.adopt(job.id, token);
+    let retry = job.retry.clone().unwrap_or_default();
+    let on_progress: OnProgress = {
+        let app = app.clone();
+        let id = job.id;
+        Arc::new(move |progress| {
+            let _ = app.emit(QUEUE_EVENT, QueueEvent::Progress { id, progress });
+        })
+    };
+    let on_progress = job
+        .progress_throttle
+        .clone()
+        .unwrap_or_default()
+        .wrap(on_progress);
+
+    match &job.kind {
+        TransferKind::Download {
+            url,
+            file_path,
+            headers,
+            body,
+        } => {
+            let params = DownloadParams {
+                client,
+                bandwidth: bandwidth.download.clone(),
+                url,
+                file_path,
+                headers: headers.clone(),
+                body: body.clone(),
+                checksum: job.checksum.clone(),
+            };
+            run_download(&transfer, &params, &retry, &on_progress)
+                .await
+                .map(|()| None)
+        }
+        TransferKind::Upload {
+            url,
+            file_path,
+            method,
+            headers,
+            form,
+        } => {
+            let params = UploadParams {
+                client,
+                bandwidth: bandwidth.upload.clone(),
+                url,
+                file_path,
+                method: *method,
+                headers: headers.clone(),
+                form: form.clone(),
+                checksum: job.checksum.clone(),
+            };
+            run_upload(&transfer, &params, &retry, &on_progress)
+                .await
+                .map(Some)
+        }
+    }
+}
+
+// Adds a job to the queue, returns false when a job with the same id is already queued.
+#[command]
+pub fn enqueue_transfer(job: TransferJob, app: AppHandle, queue: State<'_, TransferQueue>) -> bool {
+    {
+        let mut state = queue.queue.lock().unwrap();
+        if state.jobs.iter().any(|queued| queued.job.id == job.id) {
+            return false;
+        }
+        state.jobs.push(QueuedJob {
+            job,
+            status: JobStatus::Pending,
+        });
+        queue.changed(&app, &state);
+    }
+    schedule(&app);
+    true
+}
+
+// Changes the priority of a job that has not started yet.
+#[command]
+pub fn set_transfer_priority(
+    id: u32,
+    priority: i32,
+    app: AppHandle,
+    queue: State<'_, TransferQueue>,
+) -> bool {
+    let mut state = queue.queue.lock().unwrap();
+    let Some(queued) = state
+        .jobs
+        .iter_mut()
+        .find(|queued| queued.job.id == id && queued.status == JobStatus::Pending)
+    else {
+        return false;
+    };
+    queued.job.priority = priority;
+    queue.changed(&app, &state);
+    true
+}
+
+// Sets how many jobs may run at the same time, at least one.
+#[command]
+pub fn set_transfer_queue_concurrency(
+    concurrency: usize,
+    app: AppHandle,
+    queue: State<'_, TransferQueue>,
+) {
+    queue.queue.lock().unwrap().concurrency = concurrency.max(1);
+    schedule(&app);
+}
+
+#[command]
+pub fn get_transfer_queue(queue: State<'_, TransferQueue>) -> Vec<QueuedJob> {
+    queue.queue.lock().unwrap().jobs.clone()
+}
+
+// Removes a pending job from the queue or cancels it when it is running.
+#[command]
+pub fn remove_transfer(
+    id: u32,
+    app: AppHandle,
+    queue: State<'_, TransferQueue>,
+    transfers: State<'_, TransferState>,
+) -> bool {
+    let mut state = queue.queue.lock().unwrap();
+    let Some(index) = state.jobs.iter().position(|queued| queued.job.id == id) else {
+        return false;
+    };
+    match state.jobs[index].status {
+        // The job is removed from the queue once it has stopped.
+        JobStatus::Running => transfers.cancel(id),
+        JobStatus::Pending => {
+            state.jobs.remove(index);
+            queue.changed(&app, &state);
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(url: &str, headers: serde_json::Value) -> TransferJob {
+        serde_json::from_value(serde_json::json!({
+            "id": 1,
+            "kind": "download",
+            "url": url,
+            "filePath": "/tmp/book.epub",
+            "headers": headers,
+        }))
+        .unwrap()
+    }
+
+    fn queued(id: u32, priority: i32, status: JobStatus) -> QueuedJob {
+        QueuedJob {
+            job: TransferJob {
+                id,
+                priority,
+                ..job(
+                    &format!("https://example.com/{id}.epub"),
+                    serde_json::json!({}),
+                )
+            },
+            status,
+        }
+    }
+
+    // Marks the jobs as running in the order they are scheduled, until no slot is free.
+    fn start_jobs(queue: &mut Queue) -> Vec<u32> {
+        let mut started = Vec::new();
+        while let Some(index) = queue.next_job() {
+            queue.jobs[index].status = JobStatus::Running;
+            started.push(queue.jobs[index].job.id);
+        }
+        started
+    }
+
+    #[test]
+    fn starts_the_jobs_with_the_highest_priority_first() {
+        let mut queue = Queue {
+            jobs: vec![
+                queued(1, 0, JobStatus::Pending),
+                queued(2, 5, JobStatus::Pending),
+                queued(3, -1, JobStatus::Pending),
+                queued(4, 5, JobStatus::Pending),
+                queued(5, 0, JobStatus::Pending),
+            ],
+            concurrency: 10,
+        };
+        assert_eq!(start_jobs(&mut queue), [2, 4, 1, 5, 3]);
+    }
+
+    #[test]
+    fn runs_at_most_the_allowed_number_of_jobs() {
+        let mut queue = Queue {
+            jobs: vec![
+                queued(1, 0, JobStatus::Running),
+                queued(2, 0, JobStatus::Pending),
+                queued(3, 1, JobStatus::Pending),
+                queued(4, 0, JobStatus::Pending),
+            ],
+            concurrency: 3,
+        };
+        assert_eq!(start_jobs(&mut queue), [3, 2]);
+        assert_eq!(queue.next_job(), None);
+
+        // A finished job frees its slot for the next pending one.
+        queue.jobs.retain(|queued| queued.job.id != 1);
+        assert_eq!(start_jobs(&mut queue), [4]);
+
+        queue.concurrency = 1;
+        queue.jobs.push(queued(5, 9, JobStatus::Pending));
+        assert!(start_jobs(&mut queue).is_empty());
+    }
+
+    #[test]
+    fn reloads_the_pending_jobs_after_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(QUEUE_FILE);
+        let mut presigned = queued(3, 0, JobStatus::Pending);
+        presigned.job.kind = job(
+            "https://bucket.s3.amazonaws.com/3.epub?X-Amz-Signature=abc",
+            serde_json::json!({}),
+        )
+        .kind;
+        let saved = [
+            queued(1, 0, JobStatus::Running),
+            queued(2, 4, JobStatus::Pending),
+            presigned,
+        ];
+        std::fs::write(&path, serde_json::to_vec(&saved).unwrap()).unwrap();
+
+        let queue = TransferQueue::open(Some(path.clone()));
+        let jobs = queue.queue.lock().unwrap().jobs.clone();
+        let ids: Vec<_> = jobs.iter().map(|queued| queued.job.id).collect();
+        assert_eq!(ids, [1, 2]);
+        // The job that was running when the app quit starts over.
+        assert!(jobs
+            .iter()
+            .all(|queued| queued.status == JobStatus::Pending));
+        assert_eq!(jobs[1].job.priority, 4);
+
+        // The job with a presigned URL is dropped from the file as well.
+        let reloaded: Vec<QueuedJob> =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(reloaded.len(), 2);
+
+        let empty = TransferQueue::open(Some(dir.path().join("missing.json")));
+        assert!(empty.queue.lock().unwrap().jobs.is_empty());
+    }
+
+    #[test]
+    fn detects_jobs_that_carry_secrets() {
+        let plain = job(
+            "https://example.com/book.epub?v=2",
+            serde_json::json!({"Accept": "*/*"}),
+        );
+        assert!(!plain.carries_secrets());
+
+        let authorized = job(
+            "https://example.com/book.epub",
+            serde_json::json!({"Authorization": "Bearer token"}),
+        );
+        assert!(authorized.carries_secrets());
+
+        let presigned = job(
+            "https://bucket.s3.amazonaws.com/book.epub?X-Amz-Date=20130524T000000Z&X-Amz-Signature=abc",
+            serde_json::json!({}),
+        );
+        assert!(presigned.carries_secrets());
+    }
+}
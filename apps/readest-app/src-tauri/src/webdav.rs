@@ -0,0 +1,738 @@
+//! Sync files with a WebDAV server such as Nextcloud.
+//!
+//! Paths are relative to the configured folder. Changes can be made conditional on the
+//! ETag of the remote file, so that a change made by another device is not overwritten.
+//! The password is stored in the keychain, not in the configuration file.
+
+use quick_xml::events::Event;
+use reqwest::{header, Method, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use tauri::{command, ipc::Channel, AppHandle, Manager, State};
+
+use crate::bandwidth::BandwidthLimiter;
+use crate::credentials;
+use crate::http_client::{basic_auth, HttpClientState};
+use crate::transfer_file::{
+    channel_progress, run_download, run_upload, DownloadParams, Error, ProgressPayload,
+    ProgressThrottle, Result, RetryPolicy, TransferState, UploadMethod, UploadParams,
+};
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+const CONFIG_FILE: &str = "webdav.json";
+const CREDENTIALS_ACCOUNT: &str = "webdav";
+
+// The properties requested for each resource in a listing.
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:">
+  <d:prop>
+    <d:resourcetype/>
+    <d:getcontentlength/>
+    <d:getetag/>
+    <d:getlastmodified/>
+    <d:getcontenttype/>
+  </d:prop>
+</d:propfind>"#;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WebDavConfig {
+    // The folder to sync, e.g. https://cloud.example.com/remote.php/dav/files/<user>/Readest
+    pub url: String,
+    pub username: Option<String>,
+    // Kept in the keychain and never written to webdav.json.
+    #[serde(default, skip_serializing)]
+    pub password: Option<String>,
+}
+
+impl WebDavConfig {
+    fn resource_url(&self, path: &str, collection: bool) -> Result<Url> {
+        let invalid_url = || Error::InvalidArgument(format!("invalid WebDAV URL {}", self.url));
+        let mut url = Url::parse(&self.url).map_err(|_| invalid_url())?;
+        {
+            let mut segments = url.path_segments_mut().map_err(|_| invalid_url())?;
+            segments
+                .pop_if_empty()
+                .extend(path.split('/').filter(|segment| !segment.is_empty()));
+            if collection {
+                segments.push("");
+            }
+        }
+        Ok(url)
+    }
+
+    fn authorization(&self) -> Option<String> {
+        let username = self.username.as_ref()?;
+        Some(basic_auth(username, self.password.as_deref()))
+    }
+
+    fn request(
+        &self,
+        client: &reqwest::Client,
+        method: Method,
+        url: Url,
+    ) -> reqwest::RequestBuilder {
+        let request = client.request(method, url);
+        match self.authorization() {
+            Some(authorization) => request.header(header::AUTHORIZATION, authorization),
+            None => request,
+        }
+    }
+
+    // The headers of a download or upload that runs through transfer_file.
+    fn transfer_headers(&self, condition: &Precondition) -> HashMap<String, String> {
+        let mut headers = HashMap::new();
+        if let Some(authorization) = self.authorization() {
+            headers.insert(header::AUTHORIZATION.to_string(), authorization);
+        }
+        for (name, value) in condition.headers() {
+            headers.insert(name.to_string(), value.to_string());
+        }
+        headers
+    }
+}
+
+// ETag conditions of a request, `ifNoneMatch: "*"` only creates files that do not exist yet.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Precondition {
+    pub if_match: Option<String>,
+    pub if_none_match: Option<String>,
+}
+
+impl Precondition {
+    fn headers(&self) -> impl Iterator<Item = (header::HeaderName, &str)> {
+        [
+            (header::IF_MATCH, self.if_match.as_deref()),
+            (header::IF_NONE_MATCH, self.if_none_match.as_deref()),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| Some((name, value?)))
+    }
+
+    fn apply(&self, mut request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        for (name, value) in self.headers() {
+            request = request.header(name, value);
+        }
+        request
+    }
+}
+
+pub struct WebDavState {
+    config: RwLock<Option<WebDavConfig>>,
+    path: Option<PathBuf>,
+}
+
+impl WebDavState {
+    pub fn load(app: &AppHandle) -> Self {
+        let path = app
+            .path()
+            .app_config_dir()
+            .ok()
+            .map(|dir| dir.join(CONFIG_FILE));
+        let config: Option<WebDavConfig> = path
+            .as_ref()
+            .and_then(|path| std::fs::read(path).ok())
+            .and_then(|content| serde_json::from_slice(&content).ok());
+        let state = Self {
+            config: RwLock::new(None),
+            path,
+        };
+        match config {
+            // Written by a version that kept the password in the file, move it to the keychain.
+            Some(config) if config.password.is_some() => {
+                if let Err(e) = state.save(Some(config)) {
+                    eprintln!("Failed to move the WebDAV password to the keychain: {}", e);
+                }
+            }
+            Some(mut config) => {
+                config.password = credentials::get(CREDENTIALS_ACCOUNT);
+                // Without the password an authenticated server cannot be reached.
+                if config.username.is_none() || config.password.is_some() {
+                    *state.config.write().unwrap() = Some(config);
+                }
+            }
+            None => {}
+        }
+        state
+    }
+
+    fn config(&self) -> Result<WebDavConfig> {
+        self.config
+            .read()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| Error::InvalidArgument("WebDAV is not configured".into()))
+    }
+
+    // Without a keychain the password is only kept in memory.
+    fn save(&self, config: Option<WebDavConfig>) -> Result<()> {
+        match config.as_ref().and_then(|config| config.password.as_ref()) {
+            Some(password) => credentials::set(CREDENTIALS_ACCOUNT, password)?,
+            None => credentials::delete(CREDENTIALS_ACCOUNT),
+        }
+        if let Some(path) = &self.path {
+            match &config {
+                Some(config) => {
+                    if let Some(dir) = path.parent() {
+                        std::fs::create_dir_all(dir)?;
+                    }
+                    let content = serde_json::to_vec(config).map_err(std::io::Error::from)?;
+                    std::fs::write(path, content)?;
+                }
+                None => {
+                    let _ = std::fs::remove_file(path);
+                }
+            }
+        }
+        *self.config.write().unwrap() = config;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WebDavEntry {
+    // Relative to the configured folder, without a trailing slash.
+    pub path: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub content_type: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WebDavText {
+    pub content: String,
+    pub etag: Option<String>,
+}
+
+fn method(name: &str) -> Method {
+    Method::from_bytes(name.as_bytes()).unwrap()
+}
+
+fn etag(response: &reqwest::Response) -> Option<String> {
+    response
+        .headers()
+        .get(header::ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .map(str::to_string)
+}
+
+async fn send(request: reqwest::RequestBuilder) -> Result<reqwest::Response> {
+    let response = request.send().await?;
+    if response.status().is_success() {
+        Ok(response)
+    } else {
+        Err(Error::from_response(response).await)
+    }
+}
+
+fn invalid_response(e: impl std::fmt::Display) -> Error {
+    Error::InvalidResponse(format!("PROPFIND: {e}"))
+}
+
+// Decodes a path of the multistatus response relative to the configured folder.
+fn relative_path(base: &Url, href: &str) -> Result<String> {
+    // Servers differ in which characters they encode, compare the decoded paths.
+    let decode = |path: &str| {
+        percent_encoding::percent_decode_str(path)
+            .decode_utf8()
+            .map(|path| path.into_owned())
+            .map_err(invalid_response)
+    };
+    let base_path = decode(base.path())?;
+    let path = decode(base.join(href).map_err(invalid_response)?.path())?;
+    let path = path
+        .strip_prefix(base_path.trim_end_matches('/'))
+        .unwrap_or(&path);
+    Ok(path.trim_matches('/').to_string())
+}
+
+// Reads the entries of a multistatus response, independent of the namespace prefixes in use.
+fn parse_multistatus(base: &Url, xml: &str) -> Result<Vec<WebDavEntry>> {
+    let mut reader = quick_xml::Reader::from_str(xml);
+    reader.config_mut().trim_text(true);
+
+    let mut entries = Vec::new();
+    let mut entry: Option<WebDavEntry> = None;
+    let mut element = Vec::new();
+    loop {
+        match reader.read_event().map_err(invalid_response)? {
+            Event::Start(start) => {
+                element = start.local_name().as_ref().to_vec();
+                match element.as_slice() {
+                    b"response" => entry = Some(WebDavEntry::default()),
+                    b"collection" => entry.iter_mut().for_each(|entry| entry.is_dir = true),
+                    _ => {}
+                }
+            }
+            Event::Empty(empty) if empty.local_name().as_ref() == b"collection" => {
+                entry.iter_mut().for_each(|entry| entry.is_dir = true);
+            }
+            Event::Text(text) => {
+                let Some(entry) = entry.as_mut() else {
+                    continue;
+                };
+                let text = text.unescape().map_err(invalid_response)?.into_owned();
+                match element.as_slice() {
+                    b"href" => entry.path = relative_path(base, &text)?,
+                    b"getcontentlength" => entry.size = text.parse().unwrap_or(0),
+                    b"getetag" => entry.etag = Some(text),
+                    b"getlastmodified" => entry.last_modified = Some(text),
+                    b"getcontenttype" => entry.content_type = Some(text),
+                    _ => {}
+                }
+            }
+            Event::End(end) => {
+                if end.local_name().as_ref() == b"response" {
+                    entries.extend(entry.take());
+                }
+                element.clear();
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(entries)
+}
+
+async fn propfind(
+    config: &WebDavConfig,
+    client: &reqwest::Client,
+    path: &str,
+    depth: &str,
+) -> Result<Vec<WebDavEntry>> {
+    let base = config.resource_url("", true)?;
+    let request = config
+        .request(
+            client,
+            method("PROPFIND"),
+            config.resource_url(path, false)?,
+        )
+        .header("Depth", depth)
+        .header(header::CONTENT_TYPE, "application/xml; charset=utf-8")
+        .body(PROPFIND_BODY);
+    let body = send(request).await?.text().await?;
+    parse_multistatus(&base, &body)
+}
+
+#[command]
+pub fn webdav_set_config(
+    config: Option<WebDavConfig>,
+    webdav: State<'_, WebDavState>,
+) -> Result<()> {
+    webdav.save(config)
+}
+
+#[command]
+pub fn webdav_is_configured(webdav: State<'_, WebDavState>) -> bool {
+    webdav.config().is_ok()
+}
+
+// Lists the direct children of a folder.
+#[command]
+pub async fn webdav_list(
+    path: &str,
+    webdav: State<'_, WebDavState>,
+    http_client: State<'_, HttpClientState>,
+) -> Result<Vec<WebDavEntry>> {
+    let path = path.trim_matches('/');
+    let mut entries = propfind(&webdav.config()?, &http_client.client(), path, "1").await?;
+    // The folder itself is part of the response.
+    entries.retain(|entry| entry.path != path);
+    Ok(entries)
+}
+
+// Returns the properties of a file or folder, `None` when it does not exist.
+#[command]
+pub async fn webdav_stat(
+    path: &str,
+    webdav: State<'_, WebDavState>,
+    http_client: State<'_, HttpClientState>,
+) -> Result<Option<WebDavEntry>> {
+    match propfind(&webdav.config()?, &http_client.client(), path, "0").await {
+        Ok(entries) => Ok(entries.into_iter().next()),
+        Err(Error::HttpErrorCode(404, ..)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// Creates a folder, succeeds when the folder already exists.
+#[command]
+pub async fn webdav_create_dir(
+    path: &str,
+    webdav: State<'_, WebDavState>,
+    http_client: State<'_, HttpClientState>,
+) -> Result<()> {
+    let config = webdav.config()?;
+    let request = config.request(
+        &http_client.client(),
+        method("MKCOL"),
+        config.resource_url(path, true)?,
+    );
+    match send(request).await {
+        Ok(_) | Err(Error::HttpErrorCode(405, ..)) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+#[command]
+pub async fn webdav_delete(
+    path: &str,
+    condition: Option<Precondition>,
+    webdav: State<'_, WebDavState>,
+    http_client: State<'_, HttpClientState>,
+) -> Result<()> {
+    let config = webdav.config()?;
+    let request = config.request(
+        &http_client.client(),
+        Method::DELETE,
+        config.resource_url(path, false)?,
+    );
+    send(condition.unwrap_or_default().apply(request)).await?;
+    Ok(())
+}
+
+#[command]
+pub async fn webdav_move(
+    from: &str,
+    to: &str,
+    overwrite: bool,
+    condition: Option<Precondition>,
+    webdav: State<'_, WebDavState>,
+    http_client: State<'_, HttpClientState>,
+) -> Result<()> {
+    let config = webdav.config()?;
+    let request = config
+        .request(
+            &http_client.client(),
+            method("MOVE"),
+            config.resource_url(from, false)?,
+        )
+        .header("Destination", config.resource_url(to, false)?.as_str())
+        .header("Overwrite", if overwrite { "T" } else { "F" });
+    send(condition.unwrap_or_default().apply(request)).await?;
+    Ok(())
+}
+
+// Downloads a file with progress, returns false when `ifNoneMatch` matched and nothing changed.
+#[command]
+#[allow(clippy::too_many_arguments)]
+pub async fn webdav_download(
+    id: u32,
+    path: &str,
+    file_path: &str,
+    condition: Option<Precondition>,
+    retry: Option<RetryPolicy>,
+    progress_throttle: Option<ProgressThrottle>,
+    on_progress: Channel<ProgressPayload>,
+    webdav: State<'_, WebDavState>,
+    transfers: State<'_, TransferState>,
+    http_client: State<'_, HttpClientState>,
+    bandwidth: State<'_, BandwidthLimiter>,
+) -> Result<bool> {
+    let config = webdav.config()?;
+    let url = config.resource_url(path, false)?;
+    let transfer = transfers.register(id)?;
+    let params = DownloadParams {
+        client: http_client.client(),
+        bandwidth: bandwidth.download.clone(),
+        url: url.as_str(),
+        file_path,
+        headers: config.transfer_headers(&condition.unwrap_or_default()),
+        body: None,
+        checksum: None,
+    };
+    let result = run_download(
+        &transfer,
+        &params,
+        &retry.unwrap_or_default(),
+        &channel_progress(on_progress, progress_throttle),
+    )
+    .await;
+    match result {
+        Ok(()) => Ok(true),
+        Err(Error::HttpErrorCode(304, ..)) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+// Uploads a file with progress, a failed condition is reported as status 412.
+#[command]
+#[allow(clippy::too_many_arguments)]
+pub async fn webdav_upload(
+    id: u32,
+    path: &str,
+    file_path: &str,
+    condition: Option<Precondition>,
+    retry: Option<RetryPolicy>,
+    progress_throttle: Option<ProgressThrottle>,
+    on_progress: Channel<ProgressPayload>,
+    webdav: State<'_, WebDavState>,
+    transfers: State<'_, TransferState>,
+    http_client: State<'_, HttpClientState>,
+    bandwidth: State<'_, BandwidthLimiter>,
+) -> Result<()> {
+    let config = webdav.config()?;
+    let url = config.resource_url(path, false)?;
+    let transfer = transfers.register(id)?;
+    let params = UploadParams {
+        client: http_client.client(),
+        bandwidth: bandwidth.upload.clone(),
+        url: url.as_str(),
+        file_path,
+        method: UploadMethod::Put,
+        headers: config.transfer_headers(&condition.unwrap_or_default()),
+        form: None,
+        checksum: None,
+    };
+    run_upload(
+        &transfer,
+        &params,
+        &retry.unwrap_or_default(),
+        &channel_progress(on_progress, progress_throttle),
+    )
+    .await?;
+    Ok(())
+}
+
+// Reads a small text file such as the reading progress, `None` when `ifNoneMatch` matched.
+#[command]
+pub async fn webdav_read_text(
+    path: &str,
+    condition: Option<Precondition>,
+    webdav: State<'_, WebDavState>,
+    http_client: State<'_, HttpClientState>,
+) -> Result<Option<WebDavText>> {
+    let config = webdav.config()?;
+    let request = config.request(
+        &http_client.client(),
+        Method::GET,
+        config.resource_url(path, false)?,
+    );
+    let response = condition.unwrap_or_default().apply(request).send().await?;
+    if response.status() == StatusCode::NOT_MODIFIED {
+        return Ok(None);
+    }
+    if !response.status().is_success() {
+        return Err(Error::from_response(response).await);
+    }
+    let etag = etag(&response);
+    Ok(Some(WebDavText {
+        content: response.text().await?,
+        etag,
+    }))
+}
+
+// Writes a small text file and returns its new ETag when the server reports it.
+#[command]
+pub async fn webdav_write_text(
+    path: &str,
+    content: String,
+    condition: Option<Precondition>,
+    webdav: State<'_, WebDavState>,
+    http_client: State<'_, HttpClientState>,
+) -> Result<Option<String>> {
+    let config = webdav.config()?;
+    let request = config
+        .request(
+            &http_client.client(),
+            Method::PUT,
+            config.resource_url(path, false)?,
+        )
+        .body(content);
+    let response = send(condition.unwrap_or_default().apply(request)).await?;
+    Ok(etag(&response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MULTISTATUS: &str = r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:" xmlns:oc="http://owncloud.org/ns">
+  <d:response>
+    <d:href>/dav/files/alice/Readest/</d:href>
+    <d:propstat>
+      <d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop>
+      <d:status>HTTP/1.1 200 OK</d:status>
+    </d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>/dav/files/alice/Readest/Books/</d:href>
+    <d:propstat>
+      <d:prop>
+        <d:resourcetype><d:collection></d:collection></d:resourcetype>
+        <d:getetag>"5f1"</d:getetag>
+      </d:prop>
+      <d:status>HTTP/1.1 200 OK</d:status>
+    </d:propstat>
+  </d:response>
+  <D:response xmlns:D="DAV:">
+    <D:href>http://HOST/dav/files/alice/Readest/Caf%C3%A9%20%26%20Books.epub</D:href>
+    <D:propstat>
+      <D:prop>
+        <D:resourcetype/>
+        <D:getcontentlength>1024</D:getcontentlength>
+        <D:getetag>"a1b2"</D:getetag>
+        <D:getlastmodified>Sat, 01 Mar 2025 10:00:00 GMT</D:getlastmodified>
+        <D:getcontenttype>application/epub+zip</D:getcontenttype>
+      </D:prop>
+      <D:status>HTTP/1.1 200 OK</D:status>
+    </D:propstat>
+  </D:response>
+</d:multistatus>"#;
+
+    // Serves the requests with `handler` on a local port, returns the server's URL.
+    fn serve(handler: impl Fn(tiny_http::Request) + Send + 'static) -> String {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", server.server_addr().to_ip().unwrap());
+        std::thread::spawn(move || {
+            for request in server.incoming_requests() {
+                handler(request);
+            }
+        });
+        url
+    }
+
+    fn header<'a>(request: &'a tiny_http::Request, name: &'static str) -> Option<&'a str> {
+        request
+            .headers()
+            .iter()
+            .find(|header| header.field.equiv(name))
+            .map(|header| header.value.as_str())
+    }
+
+    fn config(server: &str) -> WebDavConfig {
+        WebDavConfig {
+            url: format!("{server}/dav/files/alice/Readest"),
+            username: Some("alice".into()),
+            password: Some("secret".into()),
+        }
+    }
+
+    #[tokio::test]
+    async fn lists_a_folder() {
+        let server = serve(|request| {
+            let authorized = header(&request, "Authorization") == Some("Basic YWxpY2U6c2VjcmV0");
+            let valid = request.method().as_str() == "PROPFIND"
+                && request.url() == "/dav/files/alice/Readest"
+                && header(&request, "Depth") == Some("1");
+            let response = match (authorized, valid) {
+                (false, _) => tiny_http::Response::from_string("").with_status_code(401),
+                (true, false) => tiny_http::Response::from_string("").with_status_code(400),
+                (true, true) => tiny_http::Response::from_string(MULTISTATUS).with_status_code(207),
+            };
+            let _ = request.respond(response);
+        });
+
+        let entries = propfind(&config(&server), &reqwest::Client::new(), "", "1")
+            .await
+            .unwrap();
+        let paths: Vec<_> = entries.iter().map(|entry| entry.path.as_str()).collect();
+        assert_eq!(paths, ["", "Books", "Café & Books.epub"]);
+
+        assert!(entries[1].is_dir);
+        assert_eq!(entries[1].etag.as_deref(), Some("\"5f1\""));
+
+        let book = &entries[2];
+        assert!(!book.is_dir);
+        assert_eq!(book.size, 1024);
+        assert_eq!(book.etag.as_deref(), Some("\"a1b2\""));
+        assert_eq!(
+            book.last_modified.as_deref(),
+            Some("Sat, 01 Mar 2025 10:00:00 GMT")
+        );
+        assert_eq!(book.content_type.as_deref(), Some("application/epub+zip"));
+    }
+
+    #[tokio::test]
+    async fn reports_missing_files() {
+        let server = serve(|request| {
+            let _ = request.respond(tiny_http::Response::from_string("").with_status_code(404));
+        });
+        let result = propfind(
+            &config(&server),
+            &reqwest::Client::new(),
+            "missing.epub",
+            "0",
+        )
+        .await;
+        assert!(matches!(result, Err(Error::HttpErrorCode(404, ..))));
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_replies() {
+        let server = serve(|request| {
+            let body = "<d:multistatus xmlns:d=\"DAV:\"><d:response></d:multistatus>";
+            let _ = request.respond(tiny_http::Response::from_string(body).with_status_code(207));
+        });
+        let result = propfind(&config(&server), &reqwest::Client::new(), "", "1").await;
+        assert!(matches!(result, Err(Error::InvalidResponse(_))));
+    }
+
+    #[test]
+    fn does_not_serialize_the_password() {
+        let json = serde_json::to_value(config("http://localhost")).unwrap();
+        assert!(json.get("password").is_none());
+        assert_eq!(json["username"], "alice");
+    }
+
+    // Runs against a real server, e.g. `rclone serve webdav --user alice --pass secret /tmp/dav`:
+    //
+    //   READEST_TEST_WEBDAV_URL=http://localhost:8080/readest READEST_TEST_WEBDAV_USERNAME=alice \
+    //   READEST_TEST_WEBDAV_PASSWORD=secret cargo test webdav -- --ignored
+    #[tokio::test]
+    #[ignore = "needs a WebDAV server"]
+    async fn syncs_with_a_webdav_server() {
+        let var = |name: &str| std::env::var(format!("READEST_TEST_WEBDAV_{name}")).ok();
+        let config = WebDavConfig {
+            url: var("URL").expect("READEST_TEST_WEBDAV_URL is not set"),
+            username: var("USERNAME"),
+            password: var("PASSWORD"),
+        };
+        let client = reqwest::Client::new();
+        let folder = format!("test-{}", rand::random::<u32>());
+        let file = format!("{folder}/progress.json");
+
+        let request = config.request(
+            &client,
+            method("MKCOL"),
+            config.resource_url("", true).unwrap(),
+        );
+        let _ = request.send().await;
+        let request = config.request(
+            &client,
+            method("MKCOL"),
+            config.resource_url(&folder, true).unwrap(),
+        );
+        send(request).await.unwrap();
+        let request = config
+            .request(
+                &client,
+                Method::PUT,
+                config.resource_url(&file, false).unwrap(),
+            )
+            .body("{}");
+        send(request).await.unwrap();
+
+        let entries = propfind(&config, &client, &folder, "1").await.unwrap();
+        let entry = entries.iter().find(|entry| entry.path == file).unwrap();
+        assert_eq!(entry.size, 2);
+        assert!(entry.etag.is_some());
+
+        let request = config.request(
+            &client,
+            Method::DELETE,
+            config.resource_url(&folder, true).unwrap(),
+        );
+        send(request).await.unwrap();
+        let result = propfind(&config, &client, &folder, "0").await;
+        assert!(matches!(result, Err(Error::HttpErrorCode(404, ..))));
+    }
+}
@@ -18,11 +18,17 @@ import { useSettingsStore } from '@/store/settingsStore';
 import { useTranslation } from '@/hooks/useTranslation';
 import { isTauriAppPlatform } from '@/services/environment';
 import { onOpenUrl } from '@tauri-apps/plugin-deep-link';
-import { start, cancel, onUrl, onInvalidUrl } from '@fabianlars/tauri-plugin-oauth';
 import { openUrl } from '@tauri-apps/plugin-opener';
-import { handleAuthCallback } from '@/helpers/auth';
+import { exchangePkceCode, handleAuthCallback } from '@/helpers/auth';
 import { getAppleIdAuth, Scope } from './utils/appleIdAuth';
-import { authWithSafari } from './utils/safariAuth';
+import { authWithSafari, cancelAuthWithSafari } from './utils/safariAuth';
+import {
+  cancelOAuthServer,
+  onOAuthCallback,
+  startOAuthServer,
+  OAuthCallback,
+  OAuthSession,
+} from './utils/oauthServer';
 
 type OAuthProvider = 'google' | 'apple' | 'azure' | 'github';
 
@@ -63,8 +69,12 @@ export default function AuthPage() {
   const { envConfig, appService } = useEnv();
   const { isDarkMode } = useTheme();
   const { settings, setSettings, saveSettings } = useSettingsStore();
-  const [port, setPort] = useState<number | null>(null);
+  const [oauthRedirectUri, setOAuthRedirectUri] = useState<string | null>(null);
+  const oauthSession = useRef<OAuthSession | null>(null);
   const isOAuthServerRunning = useRef(false);
+  const isAuthSessionPending = useRef(false);
+  const hasRestartedOAuthListener = useRef(false);
+  const unlistenOAuthCallback = useRef<(() => void) | null>(null);
   const [isMounted, setIsMounted] = useState(false);
 
   const getTauriRedirectTo = (isOAuth: boolean) => {
@@ -76,7 +86,7 @@ export default function AuthPage() {
       }
       return DEEPLINK_CALLBACK;
     }
-    return `http://localhost:${port}`; // only for development env on Desktop
+    return oauthRedirectUri ?? undefined; // only for development env on Desktop
   };
 
   const tauriSignInApple = async () => {
@@ -104,11 +114,22 @@ export default function AuthPage() {
       throw new Error('No backend connected');
     }
     supabase.auth.signOut();
+    const redirectTo = getTauriRedirectTo(true);
+    // Redirects to the loopback listener use the PKCE challenge of its session.
+    const session = oauthSession.current;
+    const queryParams =
+      session && redirectTo === session.redirectUri
+        ? {
+            code_challenge: session.codeChallenge,
+            code_challenge_method: session.codeChallengeMethod,
+          }
+        : undefined;
     const { data, error } = await supabase.auth.signInWithOAuth({
       provider,
       options: {
         skipBrowserRedirect: true,
-        redirectTo: getTauriRedirectTo(true),
+        redirectTo,
+        queryParams,
       },
     });
 
@@ -117,11 +138,17 @@ export default function AuthPage() {
       return;
     }
     // Open the OAuth URL in a ASWebAuthenticationSession on iOS to comply with Apple's guidelines
+    // on desktop the same API opens the default browser and waits for the deep link redirect
     // for other platforms, open the OAuth URL in the default browser
-    if (appService?.isIOSApp) {
-      const res = await authWithSafari({ authUrl: data.url });
-      if (res) {
+    if (appService?.isIOSApp || (!appService?.isMobile && redirectTo === DEEPLINK_CALLBACK)) {
+      isAuthSessionPending.current = true;
+      try {
+        const res = await authWithSafari({ authUrl: data.url, callbackUrl: redirectTo });
         handleOAuthUrl(res.redirectUrl);
+      } catch (error) {
+        console.error('Authentication error:', error);
+      } finally {
+        isAuthSessionPending.current = false;
       }
     } else {
       await openUrl(data.url);
@@ -143,6 +170,44 @@ export default function AuthPage() {
     }
   };
 
+  const handleOAuthCallback = async (callback: OAuthCallback) => {
+    switch (callback.type) {
+      case 'tokens':
+        handleAuthCallback({
+          accessToken: callback.accessToken,
+          refreshToken: callback.refreshToken,
+          login,
+          navigate: router.push,
+        });
+        break;
+      case 'code':
+        try {
+          const { accessToken, refreshToken } = await exchangePkceCode(
+            callback.code,
+            callback.codeVerifier,
+          );
+          handleAuthCallback({ accessToken, refreshToken, login, navigate: router.push });
+        } catch (error) {
+          console.error('Authentication error:', error);
+        }
+        break;
+      default:
+        console.log('OAuth sign-in did not complete:', callback);
+        // The listener stops after one redirect, so wait once more for another attempt.
+        if (!hasRestartedOAuthListener.current) {
+          hasRestartedOAuthListener.current = true;
+          startOAuthListener();
+        }
+    }
+  };
+
+  const startOAuthListener = async () => {
+    const session = await startOAuthServer();
+    oauthSession.current = session;
+    setOAuthRedirectUri(session.redirectUri);
+    console.log(`OAuth server started on port ${session.port}`);
+  };
+
   const startTauriOAuth = async () => {
     try {
       if (process.env.NODE_ENV === 'production' || appService?.isMobile) {
@@ -150,25 +215,22 @@ export default function AuthPage() {
         const currentWindow = getCurrentWindow();
         currentWindow.listen('single-instance', ({ event, payload }) => {
           console.log('Received deep link:', event, payload);
+          if (isAuthSessionPending.current) return;
           const { args } = payload as SingleInstancePayload;
           if (args?.[1]) {
             handleOAuthUrl(args[1]);
           }
         });
         await onOpenUrl((urls) => {
+          // The redirect of a pending sign-in is delivered to authWithSafari.
+          if (isAuthSessionPending.current) return;
           urls.forEach((url) => {
             handleOAuthUrl(url);
           });
         });
       } else {
-        const port = await start();
-        setPort(port);
-        console.log(`OAuth server started on port ${port}`);
-
-        await onUrl(handleOAuthUrl);
-        await onInvalidUrl((url) => {
-          console.log('Received invalid OAuth URL:', url);
-        });
+        unlistenOAuthCallback.current = await onOAuthCallback(handleOAuthCallback);
+        await startOAuthListener();
       }
     } catch (error) {
       console.error('Error starting OAuth server:', error);
@@ -177,10 +239,13 @@ export default function AuthPage() {
 
   const stopTauriOAuth = async () => {
     try {
-      if (port) {
-        await cancel(port);
-        console.log('OAuth server stopped');
+      if (isAuthSessionPending.current) {
+        await cancelAuthWithSafari();
       }
+      unlistenOAuthCallback.current?.();
+      unlistenOAuthCallback.current = null;
+      await cancelOAuthServer();
+      console.log('OAuth server stopped');
     } catch (error) {
       console.error('Error stopping OAuth server:', error);
     }
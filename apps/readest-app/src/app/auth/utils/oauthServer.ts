@@ -0,0 +1,44 @@
+import { invoke } from '@tauri-apps/api/core';
+import { listen, UnlistenFn } from '@tauri-apps/api/event';
+
+export interface OAuthSession {
+  port: number;
+  // Already carries the `state` of the session in its query.
+  redirectUri: string;
+  state: string;
+  // Sent with the authorization request, the verifier arrives with the code.
+  codeChallenge: string;
+  codeChallengeMethod: 'S256';
+}
+
+export type OAuthCallback =
+  | { type: 'code'; code: string; codeVerifier: string }
+  | {
+      type: 'tokens';
+      accessToken: string;
+      refreshToken: string | null;
+      expiresIn: number | null;
+      tokenType: string | null;
+    }
+  | { type: 'error'; error: string; description: string | null }
+  | { type: 'timeout' };
+
+export const OAUTH_CALLBACK_EVENT = 'oauth-callback';
+
+// Listens on a loopback port for a single redirect to `path`, which defaults to /auth/callback.
+export async function startOAuthServer(
+  path?: string,
+  timeoutSecs?: number,
+): Promise<OAuthSession> {
+  return await invoke<OAuthSession>('start_server', { path, timeoutSecs });
+}
+
+export async function cancelOAuthServer(): Promise<void> {
+  await invoke('cancel_oauth_server');
+}
+
+export async function onOAuthCallback(
+  handler: (callback: OAuthCallback) => void,
+): Promise<UnlistenFn> {
+  return await listen<OAuthCallback>(OAUTH_CALLBACK_EVENT, (event) => handler(event.payload));
+}
@@ -2,6 +2,11 @@ import { invoke } from '@tauri-apps/api/core';
 
 export interface SafariAuthRequest {
   authUrl: string;
+  // The redirect to wait for, `readest://` by default. On desktop a `http://localhost:<port>/`
+  // URL is received on that loopback port instead of through a deep link.
+  callbackUrl?: string;
+  // Rejects when no redirect arrives in time, 300 seconds by default.
+  timeoutSecs?: number;
 }
 
 export interface SafariAuthResponse {
@@ -15,3 +20,7 @@ export async function authWithSafari(request: SafariAuthRequest): Promise<Safari
 
   return result;
 }
+
+export async function cancelAuthWithSafari(): Promise<void> {
+  await invoke('plugin:safari-auth|cancel');
+}
@@ -1,5 +1,5 @@
 import { User } from '@supabase/supabase-js';
-import { supabase } from '@/utils/supabase';
+import { supabase, supabaseAnonKey, supabaseUrl } from '@/utils/supabase';
 
 interface UseAuthCallbackOptions {
   accessToken?: string | null;
@@ -48,3 +48,18 @@ export function handleAuthCallback({
 
   finalizeSession();
 }
+
+// Exchanges the authorization code of a PKCE sign-in for the tokens of its session, with
+// the verifier that matches the challenge sent in the authorization request.
+export async function exchangePkceCode(authCode: string, codeVerifier: string) {
+  const response = await fetch(`${supabaseUrl}/auth/v1/token?grant_type=pkce`, {
+    method: 'POST',
+    headers: { apikey: supabaseAnonKey, 'Content-Type': 'application/json' },
+    body: JSON.stringify({ auth_code: authCode, code_verifier: codeVerifier }),
+  });
+  if (!response.ok) {
+    throw new Error(`Failed to exchange the authorization code: ${response.status}`);
+  }
+  const { access_token, refresh_token } = await response.json();
+  return { accessToken: access_token as string, refreshToken: refresh_token as string };
+}
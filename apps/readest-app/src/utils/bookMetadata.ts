@@ -0,0 +1,72 @@
+import { invoke } from '@tauri-apps/api/core';
+import { BookFormat } from '@/types/book';
+
+export interface NativeTitle {
+  value: string;
+  // e.g. `main`, `subtitle` or `collection`.
+  kind: string | null;
+  fileAs: string | null;
+  language: string | null;
+}
+
+export interface NativeCreator {
+  name: string;
+  fileAs: string | null;
+  // MARC relator codes such as `aut`, `edt` or `trl`.
+  roles: string[];
+}
+
+export interface NativeIdentifier {
+  value: string;
+  scheme: string | null;
+}
+
+export interface NativeSeries {
+  name: string;
+  index: number | null;
+}
+
+export interface NativeBookMetadata {
+  format: BookFormat;
+  titles: NativeTitle[];
+  creators: NativeCreator[];
+  contributors: NativeCreator[];
+  languages: string[];
+  identifiers: NativeIdentifier[];
+  series: NativeSeries | null;
+  publisher: string | null;
+  published: string | null;
+  description: string | null;
+  subjects: string[];
+  // Set when the cover was written to `coverPath`.
+  coverMimeType: string | null;
+}
+
+// Reads the metadata of a book natively, without loading the whole document in the webview.
+export const readBookMetadata = async (
+  filePath: string,
+  coverPath?: string,
+): Promise<NativeBookMetadata> => {
+  return await invoke('read_book_metadata', { filePath, coverPath });
+};
+
+export interface PdfOutlineItem {
+  title: string;
+  // 0-based, null when the item does not point to a page of the document.
+  pageIndex: number | null;
+  children: PdfOutlineItem[];
+}
+
+export interface NativePdfDocument {
+  metadata: NativeBookMetadata;
+  pageCount: number;
+  outline: PdfOutlineItem[];
+}
+
+// The cover is the image filling the first page, pages are not rendered.
+export const readPdfDocument = async (
+  filePath: string,
+  coverPath?: string,
+): Promise<NativePdfDocument> => {
+  return await invoke('read_pdf_document', { filePath, coverPath });
+};
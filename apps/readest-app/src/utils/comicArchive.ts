@@ -0,0 +1,83 @@
+import { invoke } from '@tauri-apps/api/core';
+
+export interface ComicPage {
+  // The path of the image in the archive.
+  name: string;
+  size: number;
+}
+
+export interface ComicInfo {
+  title: string | null;
+  series: string | null;
+  number: string | null;
+  volume: string | null;
+  summary: string | null;
+  published: string | null;
+  writers: string[];
+  pencillers: string[];
+  inkers: string[];
+  colorists: string[];
+  letterers: string[];
+  coverArtists: string[];
+  editors: string[];
+  translators: string[];
+  publisher: string | null;
+  genres: string[];
+  tags: string[];
+  language: string | null;
+  web: string | null;
+  gtin: string | null;
+  rightToLeft: boolean;
+  coverPage: number | null;
+}
+
+export interface ComicArchive {
+  // The images of the archive in natural order.
+  pages: ComicPage[];
+  info: ComicInfo | null;
+}
+
+export interface ComicThumbnail {
+  width: number;
+  height: number;
+}
+
+// Opens a CBZ, CBR or CB7 file, the archive type is detected from its content.
+export const openComicArchive = async (filePath: string): Promise<ComicArchive> => {
+  return await invoke('open_comic_archive', { filePath });
+};
+
+export const readComicPage = async (filePath: string, index: number): Promise<ArrayBuffer> => {
+  return await invoke('read_comic_page', { filePath, index });
+};
+
+export const extractComicPage = async (
+  filePath: string,
+  index: number,
+  destPath: string,
+): Promise<ComicPage> => {
+  return await invoke('extract_comic_page', { filePath, index, destPath });
+};
+
+// Writes a JPEG of the cover page, no larger than `maxSize` (512 by default) on either side.
+export const createComicThumbnail = async (
+  filePath: string,
+  destPath: string,
+  maxSize?: number,
+): Promise<ComicThumbnail> => {
+  return await invoke('create_comic_thumbnail', { filePath, destPath, maxSize });
+};
+
+// A loader for `makeComicBook` of foliate-js that reads the pages natively.
+export const makeComicLoader = async (filePath: string) => {
+  const { pages } = await openComicArchive(filePath);
+  const indexes = new Map(pages.map((page, index) => [page.name, index]));
+  const entries = pages.map((page) => ({ filename: page.name }));
+  const loadBlob = async (name: string, type?: string) => {
+    const index = indexes.get(name);
+    if (index === undefined) return null;
+    return new Blob([await readComicPage(filePath, index)], { type });
+  };
+  const getSize = (name: string) => pages[indexes.get(name) ?? -1]?.size ?? 0;
+  return { entries, loadBlob, getSize };
+};
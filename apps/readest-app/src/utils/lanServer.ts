@@ -0,0 +1,31 @@
+import { invoke } from '@tauri-apps/api/core';
+
+export interface LanServerInfo {
+  // Open one of these on the other device, empty when no LAN address was found.
+  urls: string[];
+  port: number;
+  // Entered once on the upload page to pair the browser.
+  pairingCode: string;
+}
+
+// Emitted for every book received, the file is saved to the books directory
+// (`LOCAL_BOOKS_SUBDIR` in the app data directory) and already allowed in the fs scope.
+export interface LanUploadPayload {
+  path: string;
+  name: string;
+  size: number;
+}
+
+export const LAN_UPLOAD_EVENT = 'lan-upload';
+
+export const startLanServer = async (port?: number): Promise<LanServerInfo> => {
+  return await invoke('start_lan_server', { port });
+};
+
+export const stopLanServer = async (): Promise<void> => {
+  await invoke('stop_lan_server');
+};
+
+export const getLanServerInfo = async (): Promise<LanServerInfo | null> => {
+  return await invoke('get_lan_server_info');
+};
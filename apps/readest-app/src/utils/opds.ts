@@ -0,0 +1,79 @@
+import { invoke, Channel } from '@tauri-apps/api/core';
+import { genTransferId, ProgressHandler, ProgressPayload, TransferOptions } from './transfer';
+
+// The credentials are only sent to the origin of `catalogUrl`.
+export interface OpdsAuth {
+  catalogUrl: string;
+  username: string;
+  password?: string;
+}
+
+export interface OpdsAcquisition {
+  href: string;
+  // 'acquisition' for a generic link, otherwise 'open-access', 'borrow', 'buy', 'sample'...
+  rel: string;
+  mimeType: string | null;
+}
+
+export interface OpdsEntry {
+  id: string | null;
+  title: string;
+  authors: string[];
+  summary: string | null;
+  language: string | null;
+  publisher: string | null;
+  published: string | null;
+  updated: string | null;
+  categories: string[];
+  cover: string | null;
+  thumbnail: string | null;
+  // The feed this entry leads to in a navigation feed.
+  navigation: string | null;
+  acquisitions: OpdsAcquisition[];
+}
+
+export interface OpdsFeed {
+  id: string | null;
+  title: string;
+  updated: string | null;
+  url: string;
+  start: string | null;
+  up: string | null;
+  next: string | null;
+  previous: string | null;
+  // An OpenSearch description document or a URL template with `{searchTerms}`.
+  search: string | null;
+  entries: OpdsEntry[];
+}
+
+// Loads the feed and follows its next links until `maxPages` pages are loaded.
+export const opdsFetchFeed = async (
+  url: string,
+  auth?: OpdsAuth,
+  maxPages = 1,
+): Promise<OpdsFeed> => {
+  return await invoke('opds_fetch_feed', { url, auth, maxPages });
+};
+
+export const opdsDownload = async (
+  acquisition: OpdsAcquisition,
+  filePath: string,
+  auth?: OpdsAuth,
+  progressHandler?: ProgressHandler,
+  options: TransferOptions = {},
+): Promise<void> => {
+  const onProgress = new Channel<ProgressPayload>();
+  if (progressHandler) {
+    onProgress.onmessage = progressHandler;
+  }
+
+  await invoke('opds_download', {
+    id: options.transferId ?? genTransferId(),
+    url: acquisition.href,
+    filePath,
+    auth,
+    retry: options.retry,
+    progressThrottle: options.progressThrottle,
+    onProgress,
+  });
+};
@@ -1,15 +1,83 @@
 import { invoke, Channel } from '@tauri-apps/api/core';
 
-export type UploadMethod = 'POST' | 'PUT';
+export type UploadMethod = 'POST' | 'PUT' | 'PATCH';
 
 export interface ProgressPayload {
   progress: number;
   total: number;
   transferSpeed: number;
+  elapsedMs?: number;
+  etaMs?: number | null;
+  attempt?: number;
 }
 
 export type ProgressHandler = (progress: ProgressPayload) => void;
 
+export interface Checksum {
+  algorithm: 'md5' | 'sha256' | 'partialMd5';
+  value: string;
+}
+
+export interface RetryPolicy {
+  maxAttempts?: number;
+  baseDelayMs?: number;
+  maxDelayMs?: number;
+  jitter?: number;
+  retryableStatusCodes?: number[];
+}
+
+// Progress events are sent at most every `intervalMs` (100 by default) and only when the
+// progress changed by `minPercent` of the total, the final event is always sent.
+export interface ProgressThrottle {
+  intervalMs?: number;
+  minPercent?: number;
+}
+
+export interface TransferOptions {
+  transferId?: number;
+  checksum?: Checksum;
+  retry?: RetryPolicy;
+  progressThrottle?: ProgressThrottle;
+}
+
+// Uploads the file as the `fieldName` field of a multipart/form-data body.
+export interface FormUpload {
+  fieldName: string;
+  fileName?: string;
+  contentType?: string;
+  fields?: Record<string, string>;
+}
+
+export interface UploadOptions extends TransferOptions {
+  form?: FormUpload;
+}
+
+// The error thrown by the native transfer commands.
+export interface TransferError {
+  kind:
+    | 'io'
+    | 'request'
+    | 'contentLength'
+    | 'invalidArgument'
+    | 'httpErrorCode'
+    | 'cancelled'
+    | 'alreadyRunning'
+    | 'checksumMismatch'
+    | 'missingETag'
+    | 'resumeNotSupported'
+    | 'invalidResponse'
+    | 'invalidFeed';
+  message: string;
+  status?: number;
+  reason?: 'timeout' | 'connect' | 'redirect' | 'body' | 'decode' | 'request';
+  headers?: Record<string, string>;
+  body?: string;
+  retryable: boolean;
+}
+
+export const isTransferError = (error: unknown): error is TransferError =>
+  typeof error === 'object' && error !== null && 'kind' in error && 'retryable' in error;
+
 export const webUpload = (file: File, uploadUrl: string, onProgress?: ProgressHandler) => {
   return new Promise<void>((resolve, reject) => {
     const startTime = Date.now();
@@ -72,16 +140,48 @@ export const webDownload = async (downloadUrl: string, onProgress?: ProgressHand
   return new Blob(chunks);
 };
 
+export interface HttpClientConfig {
+  connectTimeoutMs?: number;
+  readTimeoutMs?: number;
+  userAgent?: string;
+  proxy?:
+    | { mode: 'system' }
+    | { mode: 'none' }
+    | { mode: 'custom'; url: string; username?: string; password?: string };
+  rootCertificates?: string[];
+}
+
+export const tauriSetHttpClientConfig = async (config: HttpClientConfig): Promise<void> => {
+  await invoke('set_http_client_config', { config });
+};
+
+// Limits are in bytes per second, leave them out or set them to 0 for unlimited.
+export const tauriSetBandwidthLimits = async (
+  downloadLimit?: number,
+  uploadLimit?: number,
+): Promise<void> => {
+  await invoke('set_bandwidth_limits', { downloadLimit, uploadLimit });
+};
+
+export const genTransferId = () => {
+  const ids = new Uint32Array(1);
+  window.crypto.getRandomValues(ids);
+  return ids[0]!;
+};
+
+export const tauriCancelTransfer = async (transferId: number): Promise<boolean> => {
+  return await invoke('cancel_transfer', { id: transferId });
+};
+
 export const tauriUpload = async (
   url: string,
   filePath: string,
   method: UploadMethod,
   progressHandler?: ProgressHandler,
   headers?: Map<string, string>,
+  options: UploadOptions = {},
 ): Promise<string> => {
-  const ids = new Uint32Array(1);
-  window.crypto.getRandomValues(ids);
-  const id = ids[0];
+  const id = options.transferId ?? genTransferId();
 
   const onProgress = new Channel<ProgressPayload>();
   if (progressHandler) {
@@ -94,6 +194,10 @@ export const tauriUpload = async (
     filePath,
     method,
     headers: headers ?? {},
+    form: options.form,
+    checksum: options.checksum,
+    retry: options.retry,
+    progressThrottle: options.progressThrottle,
     onProgress,
   });
 };
@@ -104,10 +208,9 @@ export const tauriDownload = async (
   progressHandler?: ProgressHandler,
   headers?: Map<string, string>,
   body?: string,
+  options: TransferOptions = {},
 ): Promise<void> => {
-  const ids = new Uint32Array(1);
-  window.crypto.getRandomValues(ids);
-  const id = ids[0];
+  const id = options.transferId ?? genTransferId();
 
   const onProgress = new Channel<ProgressPayload>();
   if (progressHandler) {
@@ -121,5 +224,182 @@ export const tauriDownload = async (
     headers: headers ?? {},
     onProgress,
     body,
+    checksum: options.checksum,
+    retry: options.retry,
+    progressThrottle: options.progressThrottle,
+  });
+};
+
+export type DataHandler = (chunk: Uint8Array) => void;
+
+// The body chunks, then the size of the whole body once it was sent.
+type DataMessage = ArrayBuffer | { size: number };
+
+// Streams the response body to `dataHandler` without writing it to disk. Bodies up to
+// `inlineLimit` bytes (1 MiB by default) arrive in a single chunk.
+export const tauriStreamDownload = async (
+  url: string,
+  dataHandler: DataHandler,
+  progressHandler?: ProgressHandler,
+  headers?: Map<string, string>,
+  body?: string,
+  options: TransferOptions & { inlineLimit?: number } = {},
+): Promise<void> => {
+  const id = options.transferId ?? genTransferId();
+
+  const onProgress = new Channel<ProgressPayload>();
+  if (progressHandler) {
+    onProgress.onmessage = progressHandler;
+  }
+  let received = 0;
+  const onData = new Channel<DataMessage>();
+  // Channel messages may arrive after the command returned, the size message comes last.
+  const sent = new Promise<number>((resolve) => {
+    onData.onmessage = (message) => {
+      if (message instanceof ArrayBuffer) {
+        received += message.byteLength;
+        dataHandler(new Uint8Array(message));
+      } else {
+        resolve(message.size);
+      }
+    };
+  });
+
+  const inline = await invoke<ArrayBuffer>('download_to_memory', {
+    id,
+    url,
+    headers: headers ?? {},
+    body,
+    inlineLimit: options.inlineLimit,
+    retry: options.retry,
+    progressThrottle: options.progressThrottle,
+    onProgress,
+    onData,
+  });
+  const size = await sent;
+  if (inline.byteLength > 0) {
+    received += inline.byteLength;
+    dataHandler(new Uint8Array(inline));
+  }
+  if (received !== size) {
+    throw new Error(`Received ${received} of ${size} bytes`);
+  }
+};
+
+export const tauriDownloadToMemory = async (
+  url: string,
+  progressHandler?: ProgressHandler,
+  headers?: Map<string, string>,
+  body?: string,
+  options: TransferOptions = {},
+): Promise<Uint8Array> => {
+  const chunks: Uint8Array[] = [];
+  await tauriStreamDownload(
+    url,
+    (chunk) => chunks.push(chunk),
+    progressHandler,
+    headers,
+    body,
+    options,
+  );
+  const data = new Uint8Array(chunks.reduce((len, chunk) => len + chunk.length, 0));
+  let offset = 0;
+  for (const chunk of chunks) {
+    data.set(chunk, offset);
+    offset += chunk.length;
+  }
+  return data;
+};
+
+export interface MultipartUpload {
+  partSize: number;
+  partUrls: string[];
+  completeUrl: string;
+  abortUrl?: string;
+  concurrency?: number;
+}
+
+export const tauriMultipartUpload = async (
+  filePath: string,
+  upload: MultipartUpload,
+  progressHandler?: ProgressHandler,
+  headers?: Map<string, string>,
+  options: TransferOptions = {},
+): Promise<string> => {
+  const id = options.transferId ?? genTransferId();
+
+  const onProgress = new Channel<ProgressPayload>();
+  if (progressHandler) {
+    onProgress.onmessage = progressHandler;
+  }
+
+  return await invoke('upload_file_multipart', {
+    id,
+    filePath,
+    upload,
+    headers: headers ?? {},
+    retry: options.retry,
+    progressThrottle: options.progressThrottle,
+    onProgress,
   });
 };
+
+// Queued jobs are saved and resumed when the app starts again, unless their headers or URL
+// carry credentials.
+export type TransferJob = {
+  id: number;
+  // Higher priorities start first, the default is 0.
+  priority?: number;
+  checksum?: Checksum;
+  retry?: RetryPolicy;
+  progressThrottle?: ProgressThrottle;
+} & (
+  | {
+      kind: 'download';
+      url: string;
+      filePath: string;
+      headers?: Record<string, string>;
+      body?: string;
+    }
+  | {
+      kind: 'upload';
+      url: string;
+      filePath: string;
+      method: UploadMethod;
+      headers?: Record<string, string>;
+      form?: FormUpload;
+    }
+);
+
+export type QueuedJob = TransferJob & { status: 'pending' | 'running' };
+
+export type TransferQueueEvent =
+  | { type: 'changed'; jobs: QueuedJob[] }
+  | ({ type: 'progress'; id: number } & ProgressPayload)
+  | { type: 'finished'; id: number; response: string | null; error: TransferError | null };
+
+export const TRANSFER_QUEUE_EVENT = 'transfer-queue';
+
+export const tauriEnqueueTransfer = async (job: TransferJob): Promise<boolean> => {
+  return await invoke('enqueue_transfer', { job });
+};
+
+export const tauriSetTransferPriority = async (
+  id: number,
+  priority: number,
+): Promise<boolean> => {
+  return await invoke('set_transfer_priority', { id, priority });
+};
+
+export const tauriSetTransferQueueConcurrency = async (concurrency: number): Promise<void> => {
+  await invoke('set_transfer_queue_concurrency', { concurrency });
+};
+
+export const tauriGetTransferQueue = async (): Promise<QueuedJob[]> => {
+  return await invoke('get_transfer_queue');
+};
+
+// Removes a pending job or cancels a running one.
+export const tauriRemoveTransfer = async (id: number): Promise<boolean> => {
+  return await invoke('remove_transfer', { id });
+};
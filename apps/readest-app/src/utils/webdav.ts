@@ -0,0 +1,124 @@
+import { invoke, Channel } from '@tauri-apps/api/core';
+import { genTransferId, ProgressHandler, ProgressPayload, TransferOptions } from './transfer';
+
+export interface WebDavConfig {
+  // The folder to sync, e.g. https://cloud.example.com/remote.php/dav/files/<user>/Readest
+  url: string;
+  username?: string;
+  password?: string;
+}
+
+// `ifNoneMatch: '*'` only creates files that do not exist yet.
+export interface WebDavCondition {
+  ifMatch?: string;
+  ifNoneMatch?: string;
+}
+
+export interface WebDavEntry {
+  path: string;
+  isDir: boolean;
+  size: number;
+  etag: string | null;
+  lastModified: string | null;
+  contentType: string | null;
+}
+
+export interface WebDavText {
+  content: string;
+  etag: string | null;
+}
+
+const progressChannel = (progressHandler?: ProgressHandler) => {
+  const onProgress = new Channel<ProgressPayload>();
+  if (progressHandler) {
+    onProgress.onmessage = progressHandler;
+  }
+  return onProgress;
+};
+
+export const webdavSetConfig = async (config: WebDavConfig | null): Promise<void> => {
+  await invoke('webdav_set_config', { config });
+};
+
+export const webdavIsConfigured = async (): Promise<boolean> => {
+  return await invoke('webdav_is_configured');
+};
+
+export const webdavList = async (path: string): Promise<WebDavEntry[]> => {
+  return await invoke('webdav_list', { path });
+};
+
+export const webdavStat = async (path: string): Promise<WebDavEntry | null> => {
+  return await invoke('webdav_stat', { path });
+};
+
+export const webdavCreateDir = async (path: string): Promise<void> => {
+  await invoke('webdav_create_dir', { path });
+};
+
+export const webdavDelete = async (path: string, condition?: WebDavCondition): Promise<void> => {
+  await invoke('webdav_delete', { path, condition });
+};
+
+export const webdavMove = async (
+  from: string,
+  to: string,
+  overwrite = false,
+  condition?: WebDavCondition,
+): Promise<void> => {
+  await invoke('webdav_move', { from, to, overwrite, condition });
+};
+
+// Resolves to false when `condition.ifNoneMatch` matched and the file was not downloaded.
+export const webdavDownload = async (
+  path: string,
+  filePath: string,
+  progressHandler?: ProgressHandler,
+  condition?: WebDavCondition,
+  options: TransferOptions = {},
+): Promise<boolean> => {
+  return await invoke('webdav_download', {
+    id: options.transferId ?? genTransferId(),
+    path,
+    filePath,
+    condition,
+    retry: options.retry,
+    progressThrottle: options.progressThrottle,
+    onProgress: progressChannel(progressHandler),
+  });
+};
+
+export const webdavUpload = async (
+  path: string,
+  filePath: string,
+  progressHandler?: ProgressHandler,
+  condition?: WebDavCondition,
+  options: TransferOptions = {},
+): Promise<void> => {
+  await invoke('webdav_upload', {
+    id: options.transferId ?? genTransferId(),
+    path,
+    filePath,
+    condition,
+    retry: options.retry,
+    progressThrottle: options.progressThrottle,
+    onProgress: progressChannel(progressHandler),
+  });
+};
+
+// Resolves to null when `condition.ifNoneMatch` matched.
+export const webdavReadText = async (
+  path: string,
+  condition?: WebDavCondition,
+): Promise<WebDavText | null> => {
+  return await invoke('webdav_read_text', { path, condition });
+};
+
+// Resolves to the new ETag of the file when the server reports it.
+export const webdavWriteText = async (
+  path: string,
+  content: string,
+  condition?: WebDavCondition,
+): Promise<string | null> => {
+  return await invoke('webdav_write_text', { path, content, condition });
+};